@@ -0,0 +1,44 @@
+use anyhow::{bail, Result};
+use delta_2a_lidar::columnar::{self, ColumnarFormat, ExportOptions};
+use log::info;
+use pretty_env_logger::env_logger::{Builder, Env};
+use std::path::Path;
+
+const USAGE: &str = "Usage: columnar_export [--batch-size <rows>] [--source-device <name>] <measurements file> <.parquet or .arrow file>";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    let mut options = ExportOptions::default();
+    let mut paths = Vec::new();
+    let mut arguments = std::env::args().skip(1);
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--batch-size" => match arguments.next().and_then(|batch_size| batch_size.parse().ok()) {
+                Some(batch_size) => options.batch_size = batch_size,
+                None => bail!("Invalid batch size\n{}", USAGE),
+            },
+            "--source-device" => options.source_device = arguments.next(),
+            _ if argument.starts_with("--") => bail!("Unknown option {}\n{}", argument, USAGE),
+            _ => paths.push(argument),
+        }
+    }
+
+    let (input, output) = match paths.as_slice() {
+        [input, output] => (input, output),
+        _ => bail!(USAGE),
+    };
+
+    // The format follows the extension of the output file
+    options.format = match Path::new(output).extension().and_then(|extension| extension.to_str()) {
+        Some("parquet") => ColumnarFormat::Parquet,
+        Some("arrow") | Some("ipc") | Some("feather") => ColumnarFormat::ArrowIpc,
+        _ => bail!("Unknown output format\n{}", USAGE),
+    };
+
+    let rows = columnar::export_file(input, output, options).await?;
+    info!("Exported {} rows", rows);
+
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use anyhow::{bail, Result};
+use delta_2a_lidar::csv_export::{self, CsvOptions};
+use log::info;
+use pretty_env_logger::env_logger::{Builder, Env};
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+const USAGE: &str = "Usage: csv_export [--cartesian] [--skip-no-return] <measurements file> [csv file]";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    let mut options = CsvOptions::default();
+    let mut paths = Vec::new();
+    for argument in std::env::args().skip(1) {
+        match argument.as_str() {
+            "--cartesian" => options.cartesian = true,
+            "--skip-no-return" => options.skip_no_return = true,
+            _ if argument.starts_with("--") => bail!("Unknown option {}\n{}", argument, USAGE),
+            _ => paths.push(argument),
+        }
+    }
+
+    // Without an output file the rows are written to stdout
+    let rows = match paths.as_slice() {
+        [input] => csv_export::export_file(input, BufWriter::new(io::stdout()), options).await?,
+        [input, output] => csv_export::export_file(input, BufWriter::new(File::create(output)?), options).await?,
+        _ => bail!(USAGE),
+    };
+
+    info!("Exported {} rows", rows);
+
+    Ok(())
+}
@@ -1,12 +1,53 @@
-use anyhow::{Context, Result};
-use delta_2a_lidar::{measurements_file, Lidar};
+use anyhow::{bail, Context, Result};
+use delta_2a_lidar::measurements_file::{self, RecordingStream, Retention, Rotation, SyncPolicy, WriteOptions};
+use delta_2a_lidar::{packet_stream::PacketStream, Lidar};
 use log::info;
 use pretty_env_logger::env_logger::{Builder, Env};
+use std::time::Duration;
+
+const USAGE: &str = "Usage: record [--max-file-size <megabytes>] [--max-file-duration <minutes>] [--keep-files <count> | --keep-hours <hours>]";
+
+/// Parse the value of an option, e.g. `--keep-files 10`
+fn value<T: std::str::FromStr>(arguments: &mut impl Iterator<Item = String>, option: &str) -> Result<T> {
+    match arguments.next().and_then(|value| value.parse().ok()) {
+        Some(value) => Ok(value),
+        None => bail!("Invalid value for {}\n{}", option, USAGE),
+    }
+}
+
+/// Without any option the whole recording is written to a single file
+fn parse_rotation() -> Result<Option<Rotation>> {
+    let mut rotation = Rotation::default();
+    let mut arguments = std::env::args().skip(1);
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--max-file-size" => rotation.max_file_size = Some(value::<u64>(&mut arguments, &argument)? * 1024 * 1024),
+            "--max-file-duration" => rotation.max_file_duration = Some(Duration::from_secs(value::<u64>(&mut arguments, &argument)? * 60)),
+            "--keep-files" | "--keep-hours" if rotation.retention != Retention::All => bail!("Use either --keep-files or --keep-hours\n{}", USAGE),
+            "--keep-files" => rotation.retention = Retention::Files(value(&mut arguments, &argument)?),
+            "--keep-hours" => rotation.retention = Retention::Age(Duration::from_secs(value::<u64>(&mut arguments, &argument)? * 3600)),
+            _ => bail!("Unknown option {}\n{}", argument, USAGE),
+        }
+    }
+
+    if rotation == Rotation::default() {
+        return Ok(None);
+    }
+
+    // Older files are only removed when a new file is started
+    if rotation.max_file_size.is_none() && rotation.max_file_duration.is_none() {
+        bail!("Keeping the last files requires --max-file-size or --max-file-duration\n{}", USAGE);
+    }
+
+    Ok(Some(rotation))
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     Builder::from_env(Env::default().default_filter_or("info")).init();
 
+    let rotation = parse_rotation()?;
+
     info!("Enumerating lidars");
     let mut lidar_names = Lidar::enumerate()?;
 
@@ -14,17 +55,32 @@ async fn main() -> Result<()> {
     let lidar_name = lidar_names.next().context("Lidar was not found")?;
 
     info!("Connecting to: {}", lidar_name);
-    let mut lidar = Lidar::open(lidar_name)?;
+    let lidar = Lidar::open(lidar_name)?;
+
+    info!("Recording to measurement file");
+    let options = WriteOptions {
+        device_info: Some(lidar.device_info().clone()),
+        // Lose at most a second of measurements when the recording is killed
+        sync_policy: SyncPolicy::Interval(Duration::from_secs(1)),
+        rotation,
+        ..WriteOptions::default()
+    };
+    let file = measurements_file::write_with_options("./measurements.ldr", options).await?;
+    let mut lidar = RecordingStream::new(lidar, file);
 
-    info!("Creating measurement file");
-    let mut measurements = measurements_file::write("./measurements.ldr").await?;
+    let receive = async {
+        while let Some(package) = lidar.next().await {
+            info!("Received package: {:?}", package);
+        }
+    };
 
-    while let Some(package) = lidar.next().await {
-        info!("Received package: {:?}", package);
-        measurements.write(&package).await?;
+    tokio::select! {
+        _ = receive => info!("Finished receiving messages, quitting"),
+        _ = tokio::signal::ctrl_c() => info!("Interrupted, quitting"),
     }
 
-    info!("Finished receiving messages, quitting");
+    info!("Finishing measurement file");
+    lidar.finish().await?;
 
     Ok(())
 }
@@ -0,0 +1,319 @@
+//! Columnar export of measurements to Apache Parquet or Arrow IPC, e.g. to load recordings into a data warehouse.
+//! It is hidden behind the `columnar` feature flag.
+//!
+//! Every sample becomes a row of `schema()`. Rows are buffered and written in record batches of `batch_size` rows,
+//! so the memory usage doesn't grow with the size of the recording.
+use crate::measurements_file::Metadata;
+use crate::packet::{DistancePacket, Packet};
+use anyhow::Result;
+use arrow_array::builder::{ArrayBuilder, Float32Builder, StringBuilder, TimestampNanosecondBuilder, UInt64Builder, UInt8Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Default amount of rows in a record batch
+pub const DEFAULT_BATCH_SIZE: usize = 64 * 1024;
+
+/// File format of a columnar export
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColumnarFormat {
+    /// Apache Parquet, snappy compressed (default)
+    #[default]
+    Parquet,
+    /// Arrow IPC file, also known as Feather v2
+    ArrowIpc,
+}
+
+/// The schema of an export, new columns are only ever appended
+///
+/// - `scan_id`: index of the revolution, increases every time the start angle of a packet wraps around
+/// - `time`: wall clock time at which the packet was received
+/// - `angle`: angle in degrees
+/// - `distance`: distance in millimeters, 0 means no return
+/// - `intensity`: signal strength of the sample
+/// - `rotation_speed`: rotation speed of the lidar in revolutions per second
+/// - `source_device`: the lidar which was recorded, see `source_device`
+pub fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("scan_id", DataType::UInt64, false),
+        Field::new("time", DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())), false),
+        Field::new("angle", DataType::Float32, false),
+        Field::new("distance", DataType::Float32, false),
+        Field::new("intensity", DataType::UInt8, false),
+        Field::new("rotation_speed", DataType::Float32, false),
+        Field::new("source_device", DataType::Utf8, true),
+    ]))
+}
+
+/// Identifies the recorded lidar: the USB serial number, otherwise the serial port and the host name
+pub fn source_device(metadata: &Metadata) -> Option<String> {
+    let device_info = metadata.device_info.as_ref()?;
+
+    match (&device_info.usb_serial_number, &metadata.host_name) {
+        (Some(usb_serial_number), _) => Some(usb_serial_number.clone()),
+        (None, Some(host_name)) => Some(format!("{}:{}", host_name, device_info.port_name)),
+        (None, None) => Some(device_info.port_name.clone()),
+    }
+}
+
+enum Output<W: Write + Send> {
+    Parquet(ArrowWriter<W>),
+    ArrowIpc(FileWriter<W>),
+}
+
+/// Writes the samples of distance packets in record batches, other packets are ignored. Call `finish` when done.
+pub struct ColumnarWriter<W: Write + Send> {
+    output: Output<W>,
+    batch_size: usize,
+    source_device: Option<String>,
+    scan_id: u64,
+    last_start_angle: Option<f32>,
+    rows: u64,
+    scan_ids: UInt64Builder,
+    times: TimestampNanosecondBuilder,
+    angles: Float32Builder,
+    distances: Float32Builder,
+    intensities: UInt8Builder,
+    rotation_speeds: Float32Builder,
+    source_devices: StringBuilder,
+}
+
+impl<W: Write + Send> ColumnarWriter<W> {
+    /// Create a writer, `source_device` is stored in every row
+    pub fn new(writer: W, format: ColumnarFormat, source_device: Option<String>) -> Result<Self> {
+        let output = match format {
+            ColumnarFormat::Parquet => {
+                let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+                Output::Parquet(ArrowWriter::try_new(writer, schema(), Some(properties))?)
+            }
+            ColumnarFormat::ArrowIpc => Output::ArrowIpc(FileWriter::try_new(writer, &schema())?),
+        };
+
+        Ok(ColumnarWriter {
+            output,
+            batch_size: DEFAULT_BATCH_SIZE,
+            source_device,
+            scan_id: 0,
+            last_start_angle: None,
+            rows: 0,
+            scan_ids: UInt64Builder::new(),
+            times: TimestampNanosecondBuilder::new().with_timezone("UTC"),
+            angles: Float32Builder::new(),
+            distances: Float32Builder::new(),
+            intensities: UInt8Builder::new(),
+            rotation_speeds: Float32Builder::new(),
+            source_devices: StringBuilder::new(),
+        })
+    }
+
+    /// The amount of rows in a record batch, `DEFAULT_BATCH_SIZE` by default
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Write the samples of a packet which was received at `received`
+    pub fn write_packet(&mut self, received: SystemTime, packet: &Packet) -> Result<()> {
+        match packet {
+            Packet::Distance(packet) => self.write_distance_packet(received, packet),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the samples of a distance packet which was received at `received`
+    pub fn write_distance_packet(&mut self, received: SystemTime, packet: &DistancePacket) -> Result<()> {
+        if self.last_start_angle.map(|last| packet.start_angle() < last).unwrap_or(false) {
+            self.scan_id += 1;
+        }
+        self.last_start_angle = Some(packet.start_angle());
+
+        let time = received.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO).as_nanos() as i64;
+        for sample in packet.samples() {
+            self.scan_ids.append_value(self.scan_id);
+            self.times.append_value(time);
+            self.angles.append_value(sample.angle);
+            self.distances.append_value(sample.distance);
+            self.intensities.append_value(sample.signal_strength);
+            self.rotation_speeds.append_value(packet.radar_speed());
+            self.source_devices.append_option(self.source_device.as_deref());
+            self.rows += 1;
+
+            if self.scan_ids.len() >= self.batch_size {
+                self.write_batch()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Amount of rows written so far
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Write the buffered rows and the footer, returns the inner writer
+    pub fn finish(mut self) -> Result<W> {
+        self.write_batch()?;
+
+        let writer = match self.output {
+            Output::Parquet(writer) => writer.into_inner()?,
+            Output::ArrowIpc(mut writer) => {
+                writer.finish()?;
+                writer.into_inner()?
+            }
+        };
+
+        Ok(writer)
+    }
+
+    fn write_batch(&mut self) -> Result<()> {
+        if self.scan_ids.is_empty() {
+            return Ok(());
+        }
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.scan_ids.finish()),
+            Arc::new(self.times.finish()),
+            Arc::new(self.angles.finish()),
+            Arc::new(self.distances.finish()),
+            Arc::new(self.intensities.finish()),
+            Arc::new(self.rotation_speeds.finish()),
+            Arc::new(self.source_devices.finish()),
+        ];
+        let batch = RecordBatch::try_new(schema(), columns)?;
+
+        match &mut self.output {
+            Output::Parquet(writer) => {
+                // Every batch becomes a row group, otherwise the writer buffers up to a million rows
+                writer.write(&batch)?;
+                writer.flush()?;
+            }
+            Output::ArrowIpc(writer) => writer.write(&batch)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Options for `export_file`
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub format: ColumnarFormat,
+    pub batch_size: usize,
+    /// Overrides the source device of the recording's metadata
+    pub source_device: Option<String>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            format: ColumnarFormat::default(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            source_device: None,
+        }
+    }
+}
+
+/// Export a measurements file (or any file which `measurements_file::read` can open), returns the amount of rows
+pub async fn export_file(file_name: impl AsRef<Path>, output: impl AsRef<Path>, options: ExportOptions) -> Result<u64> {
+    let mut file = crate::measurements_file::read(file_name).await?;
+    let start_time = file.metadata().map(|metadata| metadata.start_time).unwrap_or(SystemTime::UNIX_EPOCH);
+    let source_device = options.source_device.or_else(|| file.metadata().and_then(source_device));
+
+    let output = std::io::BufWriter::new(std::fs::File::create(output)?);
+    let mut columnar_writer = ColumnarWriter::new(output, options.format, source_device)?.with_batch_size(options.batch_size);
+
+    while let Some(timestamped_packet) = file.next_timestamped().await? {
+        columnar_writer.write_packet(start_time + timestamped_packet.timestamp, &timestamped_packet.packet)?;
+    }
+
+    let rows = columnar_writer.rows();
+    columnar_writer.finish()?.flush()?;
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurements_file;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float32Type, UInt64Type};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use tempfile::TempDir;
+
+    async fn write_recording(dir: &TempDir) -> std::path::PathBuf {
+        let recording = dir.path().join("measurements.ldr");
+        let mut file = measurements_file::write(&recording).await.unwrap();
+        for start_angle in [315.0, 337.5, 0.0] {
+            let packet = DistancePacket::new(6.5, start_angle, 0.0, vec![1000.0, 0.0]).with_signal_strengths(vec![80, 0]);
+            file.write(&Packet::Distance(packet)).await.unwrap();
+        }
+        file.finish().await.unwrap();
+
+        recording
+    }
+
+    #[tokio::test]
+    async fn exports_parquet_in_batches() {
+        let dir = TempDir::new().unwrap();
+        let recording = write_recording(&dir).await;
+        let output = dir.path().join("measurements.parquet");
+
+        let options = ExportOptions {
+            batch_size: 4,
+            source_device: Some("lidar-1".to_string()),
+            ..ExportOptions::default()
+        };
+        assert_eq!(6, export_file(&recording, &output, options).await.unwrap());
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&output).unwrap()).unwrap();
+        // Every batch is a row group
+        assert_eq!(2, builder.metadata().num_row_groups());
+        let reader = builder.build().unwrap();
+        let batches = reader.collect::<std::result::Result<Vec<_>, _>>().unwrap();
+        assert_eq!(schema(), batches[0].schema());
+        assert_eq!(6, batches.iter().map(RecordBatch::num_rows).sum::<usize>());
+
+        let scan_ids: Vec<u64> = batches
+            .iter()
+            .flat_map(|batch| batch.column(0).as_primitive::<UInt64Type>().values().to_vec())
+            .collect();
+        assert_eq!(vec![0, 0, 0, 0, 1, 1], scan_ids);
+        let distances: Vec<f32> = batches
+            .iter()
+            .flat_map(|batch| batch.column(3).as_primitive::<Float32Type>().values().to_vec())
+            .collect();
+        assert_eq!(vec![1000.0, 0.0, 1000.0, 0.0, 1000.0, 0.0], distances);
+        assert_eq!(6.5, batches[0].column(5).as_primitive::<Float32Type>().value(0));
+        assert_eq!("lidar-1", batches[0].column(6).as_string::<i32>().value(5));
+    }
+
+    #[tokio::test]
+    async fn exports_arrow_ipc() {
+        let dir = TempDir::new().unwrap();
+        let recording = write_recording(&dir).await;
+        let output = dir.path().join("measurements.arrow");
+
+        let options = ExportOptions {
+            format: ColumnarFormat::ArrowIpc,
+            ..ExportOptions::default()
+        };
+        assert_eq!(6, export_file(&recording, &output, options).await.unwrap());
+
+        let reader = arrow_ipc::reader::FileReader::try_new(std::fs::File::open(&output).unwrap(), None).unwrap();
+        assert_eq!(schema(), reader.schema());
+        let batches = reader.collect::<std::result::Result<Vec<_>, _>>().unwrap();
+        assert_eq!(1, batches.len());
+        assert_eq!(6, batches[0].num_rows());
+        // The recording doesn't know its device
+        assert!(batches[0].column(6).is_null(0));
+    }
+}
@@ -0,0 +1,180 @@
+//! Export of distance measurements to CSV, e.g. for spreadsheets or pandas
+//!
+//! Every sample becomes a row with the columns `revolution,timestamp,sector_start_angle,angle,distance,signal_strength`,
+//! optionally followed by `x,y`. Timestamps are in seconds, angles in degrees and distances and coordinates in millimeters.
+//! The revolution index starts at 0 and increases every time the start angle of a packet wraps around,
+//! so the first revolution is usually incomplete.
+use crate::packet::{DistancePacket, Packet};
+use crate::packet_stream::PacketStream;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Options of a `CsvWriter`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CsvOptions {
+    /// Add `x` and `y` columns, the x axis points at angle 0 and angles increase counter-clockwise
+    pub cartesian: bool,
+    /// Leave out samples without a return (a distance of 0)
+    pub skip_no_return: bool,
+}
+
+/// Writes the samples of distance packets as CSV rows, other packets are ignored
+pub struct CsvWriter<W> {
+    writer: W,
+    options: CsvOptions,
+    revolution: u64,
+    last_start_angle: Option<f32>,
+    rows: u64,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// Create a CSV writer, the header row is written immediately
+    pub fn new(mut writer: W, options: CsvOptions) -> io::Result<Self> {
+        write!(writer, "revolution,timestamp,sector_start_angle,angle,distance,signal_strength")?;
+        if options.cartesian {
+            write!(writer, ",x,y")?;
+        }
+        writeln!(writer)?;
+
+        Ok(CsvWriter {
+            writer,
+            options,
+            revolution: 0,
+            last_start_angle: None,
+            rows: 0,
+        })
+    }
+
+    /// Write the samples of a packet which was received at the given time
+    pub fn write_packet(&mut self, timestamp: Duration, packet: &Packet) -> io::Result<()> {
+        match packet {
+            Packet::Distance(packet) => self.write_distance_packet(timestamp, packet),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the samples of a distance packet which was received at the given time
+    pub fn write_distance_packet(&mut self, timestamp: Duration, packet: &DistancePacket) -> io::Result<()> {
+        if self.last_start_angle.map(|last| packet.start_angle() < last).unwrap_or(false) {
+            self.revolution += 1;
+        }
+        self.last_start_angle = Some(packet.start_angle());
+
+        for sample in packet.samples() {
+            if self.options.skip_no_return && sample.distance == 0.0 {
+                continue;
+            }
+
+            write!(
+                self.writer,
+                "{},{:.6},{},{},{},{}",
+                self.revolution,
+                timestamp.as_secs_f64(),
+                packet.start_angle(),
+                sample.angle,
+                sample.distance,
+                sample.signal_strength
+            )?;
+            if self.options.cartesian {
+                let point = sample.point();
+                write!(self.writer, ",{:.1},{:.1}", point.x, point.y)?;
+            }
+            writeln!(self.writer)?;
+
+            self.rows += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Amount of rows written so far, without the header
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Flush and return the inner writer
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+/// Export all packets of a stream until it ends, returns the amount of rows.
+/// The packets are timestamped with the time since the export started.
+pub async fn export_stream<S: PacketStream + ?Sized, W: Write>(stream: &mut S, writer: W, options: CsvOptions) -> io::Result<u64> {
+    let started = Instant::now();
+    let mut csv_writer = CsvWriter::new(writer, options)?;
+
+    while let Some(packet) = stream.next().await {
+        csv_writer.write_packet(started.elapsed(), &packet)?;
+    }
+
+    let rows = csv_writer.rows();
+    csv_writer.into_inner()?;
+
+    Ok(rows)
+}
+
+/// Export a measurements file (or any file which `measurements_file::read` can open) using its recorded timestamps,
+/// returns the amount of rows
+#[cfg(feature = "file")]
+pub async fn export_file<W: Write>(file_name: impl AsRef<std::path::Path>, writer: W, options: CsvOptions) -> anyhow::Result<u64> {
+    let mut file = crate::measurements_file::read(file_name).await?;
+    let mut csv_writer = CsvWriter::new(writer, options)?;
+
+    while let Some(timestamped_packet) = file.next_timestamped().await? {
+        csv_writer.write_packet(timestamped_packet.timestamp, &timestamped_packet.packet)?;
+    }
+
+    let rows = csv_writer.rows();
+    csv_writer.into_inner()?;
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::LidarSpeedPacket;
+
+    fn export(options: CsvOptions) -> String {
+        let mut csv_writer = CsvWriter::new(Vec::new(), options).unwrap();
+
+        let first = DistancePacket::new(6.5, 337.5, 0.0, vec![1000.0, 0.0]).with_signal_strengths(vec![80, 0]);
+        let second = DistancePacket::new(6.5, 0.0, 0.0, vec![2000.0]).with_signal_strengths(vec![90]);
+        csv_writer.write_packet(Duration::from_millis(10), &Packet::Distance(first)).unwrap();
+        csv_writer
+            .write_packet(Duration::from_millis(15), &Packet::LidarSpeed(LidarSpeedPacket::new(1.0)))
+            .unwrap();
+        csv_writer.write_packet(Duration::from_millis(20), &Packet::Distance(second)).unwrap();
+
+        String::from_utf8(csv_writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn writes_samples() {
+        assert_eq!(
+            "revolution,timestamp,sector_start_angle,angle,distance,signal_strength\n\
+             0,0.010000,337.5,337.5,1000,80\n\
+             0,0.010000,337.5,348.75,0,0\n\
+             1,0.020000,0,0,2000,90\n",
+            export(CsvOptions::default())
+        );
+    }
+
+    #[test]
+    fn cartesian_without_no_returns() {
+        let options = CsvOptions {
+            cartesian: true,
+            skip_no_return: true,
+        };
+
+        assert_eq!(
+            "revolution,timestamp,sector_start_angle,angle,distance,signal_strength,x,y\n\
+             0,0.010000,337.5,337.5,1000,80,923.9,-382.7\n\
+             1,0.020000,0,0,2000,90,2000.0,0.0\n",
+            export(options)
+        );
+    }
+}
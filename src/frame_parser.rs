@@ -2,6 +2,8 @@ use crate::crc::CRC;
 use thiserror::Error;
 
 const FRAME_HEADER: u8 = 0xAA;
+pub(crate) const PROTOCOL_VERSION: u8 = 0x00;
+pub(crate) const FRAME_TYPE: u8 = 0x61;
 
 /// Enum used to capture Lidar frames.
 ///
@@ -38,6 +40,12 @@ pub enum FrameParser {
     },
 }
 
+impl Default for FrameParser {
+    fn default() -> Self {
+        FrameParser::new()
+    }
+}
+
 impl FrameParser {
     /// Create a new frame parser
     pub fn new() -> Self {
@@ -149,12 +157,36 @@ impl FrameNextByteResult {
     }
 }
 
+/// The contents of a frame, from the protocol version up to (not including) the CRC
 #[derive(Debug, PartialEq)]
 pub struct Frame(Vec<u8>);
 
-impl Into<Vec<u8>> for Frame {
-    fn into(self) -> Vec<u8> {
-        self.0
+impl Frame {
+    /// Create a frame from its contents (protocol version, frame type, command byte and parameters)
+    pub fn new(data: Vec<u8>) -> Self {
+        Frame(data)
+    }
+
+    /// Encode the frame into the bytes which are sent over the wire: frame header, frame length, data and CRC
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Frame header (1B) + Frame length (2B) are included in the frame length, the CRC (2B) isn't
+        let length = self.0.len() as u16 + 3;
+
+        let mut bytes = Vec::with_capacity(length as usize + 2);
+        bytes.push(FRAME_HEADER);
+        bytes.extend_from_slice(&length.to_be_bytes());
+        bytes.extend_from_slice(&self.0);
+
+        let crc = CRC::from_bytes(&bytes);
+        bytes.extend_from_slice(&crc.as_u16().to_be_bytes());
+
+        bytes
+    }
+}
+
+impl From<Frame> for Vec<u8> {
+    fn from(frame: Frame) -> Self {
+        frame.0
     }
 }
 
@@ -348,4 +380,9 @@ mod tests {
     fn example_2() {
         test_example(&SECOND_EXAMPLE);
     }
+
+    #[test]
+    fn frame_to_bytes() {
+        assert_eq!(SECOND_EXAMPLE.to_vec(), Frame(vec![0x00, 0x61, 0xAE, 0x00, 0x01, 0x69]).to_bytes());
+    }
 }
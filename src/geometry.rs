@@ -0,0 +1,231 @@
+//! Geometry of the lidar and its surroundings
+//!
+//! All coordinates are in millimeters, angles are in degrees and increase counter-clockwise.
+use crate::packet::{DistancePacket, Sample};
+#[cfg(feature = "serialize")]
+use serde::{Deserialize, Serialize};
+use std::ops::{Add, Neg, Sub};
+
+/// How the lidar is mounted on the robot (or any other frame of reference)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct MountingPose {
+    /// Position of the center of the lidar
+    pub x: f32,
+    pub y: f32,
+    /// Direction of the 0 degree angle of the lidar
+    pub yaw: f32,
+    /// The lidar is mounted upside down, which mirrors the direction of its angles
+    pub flipped: bool,
+}
+
+impl MountingPose {
+    /// The transform from the frame of the lidar to the frame it is mounted in
+    pub fn transform(&self) -> Transform2 {
+        Transform2::new(Point2::new(self.x, self.y), self.yaw, self.flipped)
+    }
+
+    /// Position of a sample in the frame the lidar is mounted in
+    pub fn sample_to_point(&self, sample: &Sample) -> Point2 {
+        self.transform().apply(sample.point())
+    }
+
+    /// Positions of the samples of a packet in the frame the lidar is mounted in, samples without a return are left out
+    pub fn packet_points(&self, packet: &DistancePacket) -> Vec<Point2> {
+        let transform = self.transform();
+
+        packet
+            .samples()
+            .filter(|sample| sample.distance > 0.0)
+            .map(|sample| transform.apply(sample.point()))
+            .collect()
+    }
+}
+
+/// A point in the plane
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Point2 {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Point2 {
+    pub fn new(x: f32, y: f32) -> Self {
+        Point2 { x, y }
+    }
+
+    /// The point at `distance` from the origin in the direction of `angle`
+    pub fn from_polar(angle: f32, distance: f32) -> Self {
+        let (sin, cos) = angle.to_radians().sin_cos();
+
+        Point2::new(distance * cos, distance * sin)
+    }
+
+    /// Distance to the origin
+    pub fn norm(&self) -> f32 {
+        self.x.hypot(self.y)
+    }
+
+    /// Direction of the point as seen from the origin, in the range (-180, 180]
+    pub fn angle(&self) -> f32 {
+        self.y.atan2(self.x).to_degrees()
+    }
+
+    pub fn distance_to(&self, other: Point2) -> f32 {
+        (*self - other).norm()
+    }
+}
+
+impl Add for Point2 {
+    type Output = Point2;
+
+    fn add(self, other: Point2) -> Point2 {
+        Point2::new(self.x + other.x, self.y + other.y)
+    }
+}
+
+impl Sub for Point2 {
+    type Output = Point2;
+
+    fn sub(self, other: Point2) -> Point2 {
+        Point2::new(self.x - other.x, self.y - other.y)
+    }
+}
+
+impl Neg for Point2 {
+    type Output = Point2;
+
+    fn neg(self) -> Point2 {
+        Point2::new(-self.x, -self.y)
+    }
+}
+
+/// A rigid transform in the plane which can mirror
+///
+/// A point is first mirrored in the x axis (when `mirrored`), then rotated counter-clockwise by `rotation` and finally translated.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Transform2 {
+    pub translation: Point2,
+    /// Rotation in degrees
+    pub rotation: f32,
+    pub mirrored: bool,
+}
+
+impl Transform2 {
+    pub fn new(translation: Point2, rotation: f32, mirrored: bool) -> Self {
+        Transform2 {
+            translation,
+            rotation,
+            mirrored,
+        }
+    }
+
+    /// The transform which leaves every point where it is
+    pub fn identity() -> Self {
+        Transform2::default()
+    }
+
+    pub fn apply(&self, point: Point2) -> Point2 {
+        let y = if self.mirrored { -point.y } else { point.y };
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+
+        Point2::new(point.x * cos - y * sin, point.x * sin + y * cos) + self.translation
+    }
+
+    /// The transform which applies this transform followed by `other`
+    pub fn then(&self, other: &Transform2) -> Transform2 {
+        // Mirroring reverses the direction of the rotation which happened before it
+        let rotation = if other.mirrored { -self.rotation } else { self.rotation };
+
+        Transform2 {
+            translation: other.apply(self.translation),
+            rotation: other.rotation + rotation,
+            mirrored: self.mirrored != other.mirrored,
+        }
+    }
+
+    /// The transform which undoes this transform
+    pub fn inverse(&self) -> Transform2 {
+        let linear = Transform2::new(Point2::default(), if self.mirrored { self.rotation } else { -self.rotation }, self.mirrored);
+
+        Transform2 {
+            translation: -linear.apply(self.translation),
+            ..linear
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_point(x: f32, y: f32, point: Point2) {
+        assert!((point.x - x).abs() < 0.01 && (point.y - y).abs() < 0.01, "Unexpected point: {:?}", point);
+    }
+
+    #[test]
+    fn converts_polar_samples() {
+        let packet = DistancePacket::new(6.5, 0.0, 0.0, vec![1000.0, 0.0, 2000.0, 500.0]);
+        let points = MountingPose::default().packet_points(&packet);
+
+        // The samples are 5.625 degrees apart, the sample without a return is left out
+        assert_eq!(3, points.len());
+        assert_point(1000.0, 0.0, points[0]);
+        assert_point(2000.0 * 11.25f32.to_radians().cos(), 2000.0 * 11.25f32.to_radians().sin(), points[1]);
+        assert!((points[2].norm() - 500.0).abs() < 0.01);
+        assert!((points[2].angle() - 16.875).abs() < 0.01);
+    }
+
+    #[test]
+    fn mounted_upside_down_and_rotated() {
+        // Our lidar: 100 mm in front of the center of the robot, upside down with its 0 degree angle pointing left
+        let mounting_pose = MountingPose {
+            x: 100.0,
+            y: 0.0,
+            yaw: 90.0,
+            flipped: true,
+        };
+        let sample = |angle: f32| Sample {
+            angle,
+            distance: 1000.0,
+            signal_strength: 0,
+        };
+
+        assert_point(100.0, 1000.0, mounting_pose.sample_to_point(&sample(0.0)));
+        // Counter-clockwise for the lidar is clockwise for the robot
+        assert_point(1100.0, 0.0, mounting_pose.sample_to_point(&sample(90.0)));
+        assert_point(-900.0, 0.0, mounting_pose.sample_to_point(&sample(270.0)));
+
+        let mounting_pose = MountingPose {
+            flipped: false,
+            ..mounting_pose
+        };
+        assert_point(-900.0, 0.0, mounting_pose.sample_to_point(&sample(90.0)));
+    }
+
+    #[test]
+    fn composes_and_inverts_transforms() {
+        let point = Point2::new(300.0, -200.0);
+        let transforms = [
+            Transform2::identity(),
+            Transform2::new(Point2::new(10.0, 20.0), 30.0, false),
+            Transform2::new(Point2::new(-50.0, 5.0), 135.0, true),
+        ];
+
+        for first in &transforms {
+            let inverse = first.inverse();
+            let back = inverse.apply(first.apply(point));
+            assert_point(point.x, point.y, back);
+            let back = first.then(&inverse).apply(point);
+            assert_point(point.x, point.y, back);
+
+            for second in &transforms {
+                let expected = second.apply(first.apply(point));
+                let composed = first.then(second).apply(point);
+                assert_point(expected.x, expected.y, composed);
+            }
+        }
+    }
+}
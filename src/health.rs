@@ -0,0 +1,368 @@
+//! Health monitoring of a lidar sensor.
+//!
+//! The `HealthMonitor` is a small state machine which is fed with everything that happens on the serial link
+//! (received bytes, packets, link errors) and derives the state of the sensor from it.
+//! `Lidar` runs a monitor on its reader thread and exposes the result using `Lidar::health` and `Lidar::health_events`.
+use crate::packet::Packet;
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+/// State of the lidar sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthState {
+    /// The sensor was opened but no packets were received yet
+    Starting,
+    /// The sensor sends packets but the rotation speed has not reached the nominal range yet
+    SpinningUp,
+    /// Packets arrive at the expected rate and the rotation speed is within the nominal range
+    Nominal,
+    /// Packets arrive, but the rotation speed is out of range, the sensor reports speed failures,
+    /// the packet rate is too low or too many frames are corrupt
+    Degraded,
+    /// The motor stopped or no distance packets arrive anymore while the link is still up
+    Stalled,
+    /// The serial link is gone or silent
+    Disconnected,
+}
+
+/// A transition between two health states
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HealthEvent {
+    pub previous: HealthState,
+    pub current: HealthState,
+    pub at: Instant,
+}
+
+/// Thresholds used by the `HealthMonitor`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthConfig {
+    /// Rotation speed range (in revolutions per second) which is considered nominal
+    pub nominal_speed: RangeInclusive<f32>,
+    /// Rotation speeds (in revolutions per second) below this value are considered a stalled motor
+    pub stall_speed: f32,
+    /// Time the sensor gets to reach the nominal speed after the first packet
+    pub spin_up_timeout: Duration,
+    /// Maximum time without distance packets before the sensor is considered stalled
+    pub stall_timeout: Duration,
+    /// Maximum time without any bytes on the serial link before the sensor is considered disconnected
+    pub disconnect_timeout: Duration,
+    /// Minimum amount of distance packets per second
+    pub min_packet_rate: f32,
+    /// Maximum fraction of frames which fail to parse
+    pub max_error_rate: f32,
+    /// Window over which the packet and error rates are calculated
+    pub rate_window: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            nominal_speed: 5.5..=7.5,
+            stall_speed: 1.0,
+            spin_up_timeout: Duration::from_secs(5),
+            stall_timeout: Duration::from_secs(1),
+            disconnect_timeout: Duration::from_secs(3),
+            // A revolution contains 16 distance packets, at the lowest nominal speed this yields 88 packets per second
+            min_packet_rate: 50.0,
+            max_error_rate: 0.1,
+            rate_window: Duration::from_secs(1),
+        }
+    }
+}
+
+/// State machine which calculates the `HealthState` of a sensor
+#[derive(Debug)]
+pub struct HealthMonitor {
+    config: HealthConfig,
+    state: HealthState,
+    started_at: Instant,
+    first_packet_at: Option<Instant>,
+    last_byte_at: Option<Instant>,
+    last_distance_at: Option<Instant>,
+    last_speed_failure_at: Option<Instant>,
+    radar_speed: Option<f32>,
+    reached_nominal: bool,
+    disconnected: bool,
+    packets: VecDeque<Instant>,
+    errors: VecDeque<Instant>,
+}
+
+impl HealthMonitor {
+    /// Create a new health monitor, `now` is the moment the sensor was opened
+    pub fn new(config: HealthConfig, now: Instant) -> Self {
+        HealthMonitor {
+            config,
+            state: HealthState::Starting,
+            started_at: now,
+            first_packet_at: None,
+            last_byte_at: None,
+            last_distance_at: None,
+            last_speed_failure_at: None,
+            radar_speed: None,
+            reached_nominal: false,
+            disconnected: false,
+            packets: VecDeque::new(),
+            errors: VecDeque::new(),
+        }
+    }
+
+    /// The current health state
+    pub fn state(&self) -> HealthState {
+        self.state
+    }
+
+    /// The last rotation speed (in revolutions per second) reported by the sensor
+    pub fn radar_speed(&self) -> Option<f32> {
+        self.radar_speed
+    }
+
+    /// Register that bytes were received on the serial link
+    pub fn on_bytes(&mut self, now: Instant) {
+        self.last_byte_at = Some(now);
+        self.disconnected = false;
+    }
+
+    /// Register a successfully parsed packet
+    pub fn on_packet(&mut self, packet: &Packet, now: Instant) {
+        self.on_bytes(now);
+        self.first_packet_at.get_or_insert(now);
+
+        match packet {
+            Packet::Distance(distance) => {
+                self.radar_speed = Some(distance.radar_speed());
+                self.last_distance_at = Some(now);
+                self.packets.push_back(now);
+            }
+            Packet::LidarSpeed(speed) => {
+                self.radar_speed = Some(speed.radar_speed());
+                self.last_speed_failure_at = Some(now);
+            }
+        }
+    }
+
+    /// Register a frame which could not be parsed (invalid CRC, invalid length, unsupported packet...)
+    pub fn on_link_error(&mut self, now: Instant) {
+        self.on_bytes(now);
+        self.errors.push_back(now);
+    }
+
+    /// Register that the serial link is gone
+    pub fn on_disconnect(&mut self) {
+        self.disconnected = true;
+    }
+
+    /// Recalculate the health state, returns an event when the state changed
+    pub fn update(&mut self, now: Instant) -> Option<HealthEvent> {
+        self.expire_rate_window(now);
+
+        let next_state = self.evaluate(now);
+        if next_state == HealthState::Nominal {
+            self.reached_nominal = true;
+        }
+
+        if next_state == self.state {
+            return None;
+        }
+
+        let event = HealthEvent {
+            previous: self.state,
+            current: next_state,
+            at: now,
+        };
+        self.state = next_state;
+
+        Some(event)
+    }
+
+    fn evaluate(&self, now: Instant) -> HealthState {
+        let config = &self.config;
+
+        // No bytes for too long (or an explicit link failure) means the sensor is gone
+        let last_activity = self.last_byte_at.unwrap_or(self.started_at);
+        if self.disconnected || now.saturating_duration_since(last_activity) > config.disconnect_timeout {
+            return HealthState::Disconnected;
+        }
+
+        let first_packet_at = match self.first_packet_at {
+            Some(first_packet_at) => first_packet_at,
+            None => return HealthState::Starting,
+        };
+
+        let speed_in_range = self.radar_speed.map(|speed| config.nominal_speed.contains(&speed)).unwrap_or(false);
+        let spinning_up = !self.reached_nominal && now.saturating_duration_since(first_packet_at) <= config.spin_up_timeout;
+        if spinning_up && !speed_in_range {
+            return HealthState::SpinningUp;
+        }
+
+        let distance_timed_out = now.saturating_duration_since(self.last_distance_at.unwrap_or(first_packet_at)) > config.stall_timeout;
+        let motor_stopped = self.radar_speed.map(|speed| speed < config.stall_speed).unwrap_or(false);
+        if distance_timed_out || motor_stopped {
+            return HealthState::Stalled;
+        }
+
+        let speed_failure = self
+            .last_speed_failure_at
+            .map(|at| now.saturating_duration_since(at) <= config.rate_window)
+            .unwrap_or(false);
+        if !speed_in_range || speed_failure || self.error_rate() > config.max_error_rate || self.packet_rate(now) < config.min_packet_rate {
+            return HealthState::Degraded;
+        }
+
+        HealthState::Nominal
+    }
+
+    /// Distance packets per second over the rate window
+    fn packet_rate(&self, now: Instant) -> f32 {
+        // Don't judge the rate before a full window was observed
+        let observed = now.saturating_duration_since(self.started_at).min(self.config.rate_window);
+        if observed < self.config.rate_window {
+            return f32::INFINITY;
+        }
+
+        self.packets.len() as f32 / observed.as_secs_f32()
+    }
+
+    /// Fraction of frames which failed to parse over the rate window
+    fn error_rate(&self) -> f32 {
+        let total = self.packets.len() + self.errors.len();
+        if total == 0 {
+            return 0.0;
+        }
+
+        self.errors.len() as f32 / total as f32
+    }
+
+    fn expire_rate_window(&mut self, now: Instant) {
+        let window = self.config.rate_window;
+        let expired = |at: &Instant| now.saturating_duration_since(*at) > window;
+
+        while self.packets.front().map(expired).unwrap_or(false) {
+            self.packets.pop_front();
+        }
+        while self.errors.front().map(expired).unwrap_or(false) {
+            self.errors.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{DistancePacket, LidarSpeedPacket, Packet};
+
+    /// A distance packet with the given rotation speed in revolutions per second
+    fn distance_packet(radar_speed: f32) -> Packet {
+        Packet::Distance(DistancePacket::new(radar_speed, 0.0, 0.0, vec![1000.0]))
+    }
+
+    /// Feed distance packets at 100 packets per second during `duration`
+    fn feed(monitor: &mut HealthMonitor, packet: &Packet, from: Instant, duration: Duration) -> Instant {
+        let mut now = from;
+        while now < from + duration {
+            monitor.on_packet(packet, now);
+            monitor.update(now);
+            now += Duration::from_millis(10);
+        }
+        now
+    }
+
+    #[test]
+    fn starts_in_starting() {
+        let start = Instant::now();
+        let mut monitor = HealthMonitor::new(HealthConfig::default(), start);
+
+        assert_eq!(None, monitor.update(start + Duration::from_millis(100)));
+        assert_eq!(HealthState::Starting, monitor.state());
+    }
+
+    #[test]
+    fn silent_link_is_disconnected() {
+        let start = Instant::now();
+        let mut monitor = HealthMonitor::new(HealthConfig::default(), start);
+
+        let event = monitor.update(start + Duration::from_secs(4)).unwrap();
+        assert_eq!(HealthState::Starting, event.previous);
+        assert_eq!(HealthState::Disconnected, event.current);
+    }
+
+    #[test]
+    fn spin_up_to_nominal() {
+        let start = Instant::now();
+        let mut monitor = HealthMonitor::new(HealthConfig::default(), start);
+
+        // 2 r/s is too slow
+        let now = feed(&mut monitor, &distance_packet(2.0), start, Duration::from_millis(500));
+        assert_eq!(HealthState::SpinningUp, monitor.state());
+
+        // 6.5 r/s is nominal
+        feed(&mut monitor, &distance_packet(6.5), now, Duration::from_secs(2));
+        assert_eq!(HealthState::Nominal, monitor.state());
+    }
+
+    #[test]
+    fn never_reaching_nominal_speed_is_degraded() {
+        let start = Instant::now();
+        let mut monitor = HealthMonitor::new(HealthConfig::default(), start);
+
+        feed(&mut monitor, &distance_packet(2.0), start, Duration::from_secs(6));
+        assert_eq!(HealthState::Degraded, monitor.state());
+    }
+
+    #[test]
+    fn speed_failure_is_degraded() {
+        let start = Instant::now();
+        let mut monitor = HealthMonitor::new(HealthConfig::default(), start);
+
+        let now = feed(&mut monitor, &distance_packet(6.5), start, Duration::from_secs(2));
+        monitor.on_packet(&Packet::LidarSpeed(LidarSpeedPacket::new(10.05)), now);
+        let event = monitor.update(now).unwrap();
+
+        assert_eq!(HealthState::Nominal, event.previous);
+        assert_eq!(HealthState::Degraded, event.current);
+    }
+
+    #[test]
+    fn no_distance_packets_is_stalled() {
+        let start = Instant::now();
+        let mut monitor = HealthMonitor::new(HealthConfig::default(), start);
+
+        let mut now = feed(&mut monitor, &distance_packet(6.5), start, Duration::from_secs(2));
+
+        // Bytes keep arriving, but they never form a packet
+        for _ in 0..20 {
+            now += Duration::from_millis(100);
+            monitor.on_bytes(now);
+            monitor.update(now);
+        }
+
+        assert_eq!(HealthState::Stalled, monitor.state());
+    }
+
+    #[test]
+    fn corrupt_frames_are_degraded() {
+        let start = Instant::now();
+        let mut monitor = HealthMonitor::new(HealthConfig::default(), start);
+
+        let mut now = feed(&mut monitor, &distance_packet(6.5), start, Duration::from_secs(2));
+        for _ in 0..50 {
+            monitor.on_packet(&distance_packet(6.5), now);
+            monitor.on_link_error(now);
+            monitor.update(now);
+            now += Duration::from_millis(10);
+        }
+
+        assert_eq!(HealthState::Degraded, monitor.state());
+    }
+
+    #[test]
+    fn link_failure_is_disconnected() {
+        let start = Instant::now();
+        let mut monitor = HealthMonitor::new(HealthConfig::default(), start);
+
+        let now = feed(&mut monitor, &distance_packet(6.5), start, Duration::from_secs(2));
+        monitor.on_disconnect();
+
+        assert_eq!(HealthState::Disconnected, monitor.update(now).unwrap().current);
+    }
+}
@@ -0,0 +1,175 @@
+//! Revolutions in the shape of a ROS `sensor_msgs/LaserScan` message, used by the MCAP and rosbag exports
+//!
+//! Unlike the rest of this crate a laser scan uses SI units: angles are in radians and ranges in meters.
+//! Angles increase counter-clockwise, like the angles of the samples.
+use crate::packet::DistancePacket;
+use crate::resample::ResampledScan;
+use crate::scan::{Scan, ScanAssembler};
+use std::f32::consts::PI;
+use std::time::{Duration, SystemTime};
+
+/// Closest distance the Delta-2A can measure, in meters
+pub const RANGE_MIN: f32 = 0.15;
+/// Furthest distance the Delta-2A can measure, in meters
+pub const RANGE_MAX: f32 = 8.0;
+
+/// A full revolution, the samples are assumed to be spread evenly over the revolution
+#[derive(Debug, Clone, PartialEq)]
+pub struct LaserScan {
+    /// Time at which the first sample was measured
+    pub stamp: SystemTime,
+    pub frame_id: String,
+    /// Angle of the first sample
+    pub angle_min: f32,
+    /// Angle of the last sample
+    pub angle_max: f32,
+    /// Angle between two samples
+    pub angle_increment: f32,
+    /// Time between two samples in seconds
+    pub time_increment: f32,
+    /// Duration of the revolution in seconds
+    pub scan_time: f32,
+    pub range_min: f32,
+    pub range_max: f32,
+    /// A range of 0 means no return, which is outside the valid range
+    pub ranges: Vec<f32>,
+    /// The signal strengths of the samples
+    pub intensities: Vec<f32>,
+}
+
+impl LaserScan {
+    /// Convert a revolution which started at `stamp`
+    pub fn from_scan(scan: &Scan, stamp: SystemTime, frame_id: impl Into<String>) -> Self {
+        let samples = scan.samples();
+        let angle_min = samples.first().map(|sample| sample.angle.to_radians()).unwrap_or_default();
+        let angle_increment = 2.0 * PI / samples.len().max(1) as f32;
+        let scan_time = if scan.radar_speed() > 0.0 { 1.0 / scan.radar_speed() } else { 0.0 };
+
+        LaserScan {
+            stamp,
+            frame_id: frame_id.into(),
+            angle_min,
+            angle_max: angle_min + angle_increment * samples.len().saturating_sub(1) as f32,
+            angle_increment,
+            time_increment: scan_time / samples.len().max(1) as f32,
+            scan_time,
+            range_min: RANGE_MIN,
+            range_max: RANGE_MAX,
+            ranges: samples.iter().map(|sample| sample.distance / 1000.0).collect(),
+            intensities: samples.iter().map(|sample| sample.signal_strength as f32).collect(),
+        }
+    }
+
+    /// Convert a resampled revolution which started at `stamp`, bins without a return get a range of 0
+    pub fn from_resampled(resampled: &ResampledScan, stamp: SystemTime, frame_id: impl Into<String>) -> Self {
+        let bins = resampled.bins();
+        let angle_increment = resampled.bin_width().to_radians();
+        let scan_time = if resampled.radar_speed() > 0.0 { 1.0 / resampled.radar_speed() } else { 0.0 };
+
+        LaserScan {
+            stamp,
+            frame_id: frame_id.into(),
+            angle_min: 0.0,
+            angle_max: angle_increment * bins.len().saturating_sub(1) as f32,
+            angle_increment,
+            time_increment: scan_time / bins.len().max(1) as f32,
+            scan_time,
+            range_min: RANGE_MIN,
+            range_max: RANGE_MAX,
+            ranges: bins.iter().map(|bin| bin.map(|bin| bin.distance / 1000.0).unwrap_or(0.0)).collect(),
+            intensities: bins.iter().map(|bin| bin.map(|bin| bin.signal_strength as f32).unwrap_or(0.0)).collect(),
+        }
+    }
+
+    /// The stamp as seconds and nanoseconds since the unix epoch, the representation of time in ROS
+    pub fn ros_stamp(&self) -> (u32, u32) {
+        let since_epoch = self.stamp.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO);
+
+        (since_epoch.as_secs() as u32, since_epoch.subsec_nanos())
+    }
+}
+
+/// Assembles timestamped distance packets into laser scans, see `ScanAssembler`
+#[derive(Debug)]
+pub struct LaserScanAssembler {
+    scan_assembler: ScanAssembler,
+    frame_id: String,
+    last_start_angle: Option<f32>,
+    revolution_started: Option<SystemTime>,
+}
+
+impl LaserScanAssembler {
+    /// Create an assembler which sets the given frame id on all scans
+    pub fn new(frame_id: impl Into<String>) -> Self {
+        LaserScanAssembler {
+            scan_assembler: ScanAssembler::new(),
+            frame_id: frame_id.into(),
+            last_start_angle: None,
+            revolution_started: None,
+        }
+    }
+
+    /// Add a distance packet which was received at `received`, returns the previous revolution when this packet starts a new one
+    pub fn push(&mut self, packet: &DistancePacket, received: SystemTime) -> Option<LaserScan> {
+        let wrapped = self.last_start_angle.map(|last| packet.start_angle() < last).unwrap_or(false);
+        self.last_start_angle = Some(packet.start_angle());
+
+        let scan = self.scan_assembler.push(packet);
+        let laser_scan = match (&scan, self.revolution_started) {
+            (Some(scan), Some(revolution_started)) => Some(LaserScan::from_scan(scan, revolution_started, self.frame_id.clone())),
+            _ => None,
+        };
+
+        if wrapped {
+            self.revolution_started = Some(received);
+        }
+
+        laser_scan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resample::Resampler;
+
+    #[test]
+    fn assembles_timestamped_revolutions() {
+        let mut assembler = LaserScanAssembler::new("laser");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+
+        let mut laser_scans = Vec::new();
+        for index in 0..40 {
+            let packet = DistancePacket::new(5.0, (index % 16) as f32 * 22.5, 0.0, vec![1000.0, 0.0]).with_signal_strengths(vec![70, 0]);
+            laser_scans.extend(assembler.push(&packet, start + Duration::from_millis(index * 10)));
+        }
+
+        assert_eq!(1, laser_scans.len());
+        let laser_scan = &laser_scans[0];
+        assert_eq!(start + Duration::from_millis(160), laser_scan.stamp);
+        assert_eq!("laser", laser_scan.frame_id);
+        assert_eq!(0.0, laser_scan.angle_min);
+        assert!((laser_scan.angle_increment - 2.0 * PI / 32.0).abs() < 1e-6);
+        assert!((laser_scan.scan_time - 0.2).abs() < 1e-6);
+        assert_eq!(32, laser_scan.ranges.len());
+        assert_eq!(&[1.0, 0.0], &laser_scan.ranges[..2]);
+        assert_eq!(&[70.0, 0.0], &laser_scan.intensities[..2]);
+    }
+
+    #[test]
+    fn converts_resampled_revolutions() {
+        let packets: Vec<_> = (0..16)
+            .map(|index| DistancePacket::new(5.0, index as f32 * 22.5, 0.0, vec![1000.0, 0.0, 2000.0]))
+            .collect();
+        let samples = packets.iter().flat_map(DistancePacket::samples).collect();
+        let resampled = Resampler::new(360).resample(&Scan::new(5.0, 16, samples));
+
+        let laser_scan = LaserScan::from_resampled(&resampled, SystemTime::UNIX_EPOCH, "laser");
+        assert_eq!(360, laser_scan.ranges.len());
+        assert!((laser_scan.angle_increment - 1.0f32.to_radians()).abs() < 1e-6);
+        assert!((laser_scan.scan_time - 0.2).abs() < 1e-6);
+        // Samples at 0, 7.5 and 15 degrees, the sample at 7.5 degrees has no return
+        assert_eq!(&[1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0], &laser_scan.ranges[..8]);
+        assert_eq!(2.0, laser_scan.ranges[15]);
+    }
+}
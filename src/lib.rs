@@ -4,8 +4,24 @@
 //!
 //! ## Features
 //! - Read distance frames
-//! - Read lidar speed (WIP)
-//! - Read/write measurements to file + abstractions to mock sensor (behind `file` feature)
+//! - Read lidar speed failure frames
+//! - Monitor the health of the sensor (spinning up, nominal, degraded, stalled, disconnected)
+//! - Share a lidar with multiple consumers (subscriptions and latest full revolution)
+//! - Convert samples to cartesian points in the robot frame, for lidars which are mounted rotated or upside down
+//! - Clean up scans with a configurable filter chain (range, signal strength, median, moving average, speckle and shadow filters)
+//! - Mask the parts of the field of view which are blocked by the robot, with sectors and polygons in the sensor or robot frame
+//! - Resample scans into a fixed amount of angular bins (nearest, min or mean) with an explicit no return marker
+//! - Serve a lidar over TCP and consume it on another machine (behind `network` feature)
+//! - Simulate a lidar in a 2D world (behind `simulator` feature)
+//! - Expose a recorded or simulated lidar on a pseudo-terminal (behind `pty` feature, unix only)
+//! - Read/write measurements to file (JSON lines, binary or CBOR, MessagePack and bincode records) + abstractions to mock sensor, optionally gzip compressed and split over rotating files (behind `file` feature)
+//! - Capture the raw serial data, including frames which fail to parse (behind `file` feature)
+//! - Replay recordings at their original pace, with speed, pause, looping and seeking (behind `file` feature)
+//! - Export measurements to CSV for spreadsheets or pandas (`csv_export` tool behind `file` feature)
+//! - Export scans or whole recordings as PCD or PLY point clouds
+//! - Export to MCAP with LaserScan shaped messages, and replay MCAP files (behind `file` feature)
+//! - Export to ROS1 bags with `sensor_msgs/LaserScan` messages, without ROS installed (behind `file` feature)
+//! - Export to Apache Parquet or Arrow IPC for analytics, written in batches (`columnar_export` tool behind `columnar` feature)
 //!
 //! ## Dependencies
 //! This library uses the `serialport` crate which requires `libudev-dev` to be installed on your system.
@@ -46,15 +62,75 @@
 //! }
 //!# };
 //! ```
+//!
+//! ### Share a lidar with multiple consumers
+//! Every subscription receives all packets, consumers which only care about the newest data can watch the latest revolution.
+//! ```no_run
+//!# let blah = async {
+//! use delta_2a_lidar::packet_stream::PacketStream;
+//! use delta_2a_lidar::Lidar;
+//!
+//! let lidar_name = Lidar::enumerate().unwrap().next().unwrap();
+//! let lidar = Lidar::open(lidar_name).unwrap();
+//!
+//! let mut logger = lidar.subscribe();
+//! tokio::spawn(async move {
+//!     while let Some(package) = logger.next().await {
+//!         println!("Received package: {:?}", package);
+//!     }
+//! });
+//!
+//! let mut latest_scan = lidar.latest_scan();
+//! while latest_scan.changed().await.is_ok() {
+//!     if let Some(scan) = latest_scan.borrow().as_ref() {
+//!         println!("Received a revolution with {} samples", scan.samples().len());
+//!     }
+//! }
+//!# };
+//! ```
+#[cfg(feature = "columnar")]
+pub mod columnar;
 pub mod crc;
+pub mod csv_export;
 pub mod frame_parser;
+pub mod geometry;
+pub mod health;
+pub mod laser_scan;
 pub mod lidar;
+pub mod mask;
 pub mod packet;
+pub mod packet_decoder;
 pub mod packet_stream;
+pub mod point_cloud;
+pub mod resample;
+pub mod scan;
+pub mod scan_filter;
+pub mod subscription;
+
+#[cfg(feature = "file")]
+pub mod mcap;
 
 #[cfg(feature = "file")]
 pub mod measurements_file;
 
+#[cfg(feature = "network")]
+pub mod network;
+
+#[cfg(all(feature = "pty", unix))]
+pub mod pty;
+
+#[cfg(feature = "file")]
+pub mod raw_capture;
+
+#[cfg(feature = "file")]
+pub mod replay;
+
+#[cfg(feature = "file")]
+pub mod rosbag;
+
+#[cfg(feature = "simulator")]
+pub mod simulator;
+
 #[cfg(test)]
 mod mock_data;
 
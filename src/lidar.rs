@@ -1,26 +1,43 @@
-use crate::frame_parser::{FrameNextByteResult, FrameParser};
+use crate::health::{HealthConfig, HealthEvent, HealthMonitor, HealthState};
 use crate::packet::Packet;
+use crate::packet_decoder::PacketDecoder;
 use crate::packet_stream::PacketStream;
+use crate::scan::{Scan, ScanAssembler};
+use crate::subscription::PacketSubscription;
 use async_trait::async_trait;
-use derive_more::{Display, Into};
+use derive_more::Display;
 use log::{error, warn};
+#[cfg(feature = "serialize")]
+use serde::{Deserialize, Serialize};
 use serialport::SerialPortType;
 use std::borrow::Cow;
-use std::io::Read;
+use std::io::{ErrorKind, Read};
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{broadcast, watch};
 
 const CP210X_VID: u16 = 4292;
 const CP210X_PID: u16 = 60000;
 const LIDAR_BAUD_RATE: u32 = 230_400;
+const SERIAL_TIMEOUT: Duration = Duration::from_millis(500);
+const HEALTH_EVENTS_CAPACITY: usize = 16;
+// Roughly 10 seconds of packets at the nominal rotation speed
+const SUBSCRIPTION_CAPACITY: usize = 1024;
+const RAW_SUBSCRIPTION_CAPACITY: usize = 1024;
+const READ_BUFFER_SIZE: usize = 512;
 
 pub struct Lidar {
     _handle: JoinHandle<()>,
-    receiver: UnboundedReceiver<Packet>,
+    receiver: PacketSubscription,
+    device_info: DeviceInfo,
+    packets: broadcast::Sender<Packet>,
+    raw_chunks: broadcast::Sender<RawChunk>,
+    latest_scan: watch::Receiver<Option<Arc<Scan>>>,
+    health: watch::Receiver<HealthState>,
+    health_events: broadcast::Sender<HealthEvent>,
 }
 
 impl Lidar {
@@ -36,16 +53,13 @@ impl Lidar {
         });
 
         // Keep all CP210x uart bridges (the lidar doesn't have a specific vendor id but shows up as a generic CP210x uart bridge)
-        let cp210_uart_brides = usb_ports.filter_map(|(port_name, usb_info)| {
-            if usb_info.vid == CP210X_VID && usb_info.pid == CP210X_PID {
-                Some(port_name)
-            } else {
-                None
-            }
-        });
+        let cp210_uart_brides = usb_ports.filter(|(_, usb_info)| usb_info.vid == CP210X_VID && usb_info.pid == CP210X_PID);
 
         // Convert all cp210 bridges to LidarName
-        let lidar_names = cp210_uart_brides.map(|port_name| LidarName(port_name));
+        let lidar_names = cp210_uart_brides.map(|(port_name, usb_info)| LidarName {
+            port_name,
+            usb_serial_number: usb_info.serial_number,
+        });
 
         // Return the lidar names
         Ok(lidar_names)
@@ -53,54 +67,161 @@ impl Lidar {
 
     /// Opens the given lidar sensor
     pub fn open(name: LidarName) -> Result<Lidar, LidarOpenError> {
-        let serial_port_builder = serialport::new(name, LIDAR_BAUD_RATE).timeout(Duration::from_millis(500));
-        let mut serial_port = serial_port_builder.open().map_err(LidarOpenError::FailedToOpenSerialPort)?;
+        let device_info = DeviceInfo {
+            port_name: name.port_name.clone(),
+            usb_serial_number: name.usb_serial_number.clone(),
+            baud_rate: LIDAR_BAUD_RATE,
+        };
 
-        let (tx, rx) = mpsc::unbounded_channel();
+        let serial_port_builder = serialport::new(name, LIDAR_BAUD_RATE).timeout(SERIAL_TIMEOUT);
+        let serial_port = serial_port_builder.open().map_err(LidarOpenError::FailedToOpenSerialPort)?;
+
+        Ok(Lidar::spawn(serial_port, device_info))
+    }
+
+    /// Read and decode packets from `serial_port` in a background thread
+    pub(crate) fn spawn(mut serial_port: impl Read + Send + 'static, device_info: DeviceInfo) -> Lidar {
+        // `next` is served by its own subscription, so packets nobody reads don't pile up
+        let (packets_tx, packets_rx) = broadcast::channel(SUBSCRIPTION_CAPACITY);
+        let packets = packets_tx.clone();
+        let (raw_chunks_tx, _) = broadcast::channel(RAW_SUBSCRIPTION_CAPACITY);
+        let raw_chunks = raw_chunks_tx.clone();
+        let (latest_scan_tx, latest_scan_rx) = watch::channel(None);
+        let (health_tx, health_rx) = watch::channel(HealthState::Starting);
+        let (health_events_tx, _) = broadcast::channel(HEALTH_EVENTS_CAPACITY);
+        let health_events = health_events_tx.clone();
 
         let handle = thread::spawn(move || {
-            let mut buffer = [0u8];
-            let mut frame_parser = FrameParser::new();
+            let mut buffer = [0u8; READ_BUFFER_SIZE];
+            let mut packet_decoder = PacketDecoder::new();
+            let mut health_monitor = HealthMonitor::new(HealthConfig::default(), Instant::now());
+            let mut scan_assembler = ScanAssembler::new();
+
+            // Quit when the lidar and all of its subscriptions are dropped
+            while packets_tx.receiver_count() > 0 {
+                let received = match serial_port.read(&mut buffer) {
+                    Ok(read) => read,
+                    Err(e) if e.kind() == ErrorKind::TimedOut => 0,
+                    Err(e) => {
+                        warn!("Failed to read from serial port: {:?}", e);
+                        health_monitor.on_disconnect();
+
+                        // Don't spin on a serial port which keeps failing
+                        thread::sleep(SERIAL_TIMEOUT);
+                        0
+                    }
+                };
+
+                if received > 0 {
+                    let now = Instant::now();
+                    health_monitor.on_bytes(now);
 
-            loop {
-                if let Err(e) = serial_port.read(&mut buffer) {
-                    warn!("Failed to read character from serial port: {:?}", e);
-                    continue;
+                    // Only copy the bytes when somebody is listening
+                    if raw_chunks_tx.receiver_count() > 0 {
+                        let _ = raw_chunks_tx.send(RawChunk {
+                            received: now,
+                            bytes: buffer[..received].to_vec(),
+                        });
+                    }
                 }
 
-                match frame_parser.next_byte(buffer[0]) {
-                    Ok(next_result) => match next_result {
-                        FrameNextByteResult::Unfinished(parser) => frame_parser = parser,
-                        FrameNextByteResult::Finished(frame) => {
-                            match Packet::parse(frame) {
-                                Ok(packet) => {
-                                    if let Err(e) = tx.send(packet) {
-                                        error!("Failed to send packet over channel, quitting: {:?}", e);
-                                        return;
-                                    }
-                                }
-                                Err(e) => {
-                                    warn!("Failed to parse packet: {:?}", e);
+                // Publish the health state, also when nothing was received (watchdog)
+                if let Some(event) = health_monitor.update(Instant::now()) {
+                    let _ = health_tx.send(event.current);
+                    let _ = health_events_tx.send(event);
+                }
+
+                for result in packet_decoder.decode(&buffer[..received]) {
+                    match result {
+                        Ok(packet) => {
+                            health_monitor.on_packet(&packet, Instant::now());
+
+                            if let Packet::Distance(distance_packet) = &packet {
+                                if let Some(scan) = scan_assembler.push(distance_packet) {
+                                    latest_scan_tx.send_replace(Some(Arc::new(scan)));
                                 }
                             }
 
-                            frame_parser = FrameParser::new();
+                            if packets_tx.send(packet).is_err() {
+                                error!("All packet receivers were dropped, quitting");
+                                return;
+                            }
+                        }
+                        Err(e) => {
+                            warn!("{}", e);
+
+                            // Bytes which are not a frame header only mean we're not in sync with the sensor (yet)
+                            if !e.is_out_of_sync() {
+                                health_monitor.on_link_error(Instant::now());
+                            }
                         }
-                    },
-                    Err(e) => {
-                        warn!("Failed to parse frame: {:?}", e);
-                        frame_parser = FrameParser::new();
                     }
                 }
             }
         });
 
-        Ok(Lidar { _handle: handle, receiver: rx })
+        Lidar {
+            _handle: handle,
+            receiver: PacketSubscription::new(packets_rx),
+            device_info,
+            packets,
+            raw_chunks,
+            latest_scan: latest_scan_rx,
+            health: health_rx,
+            health_events,
+        }
+    }
+
+    /// Information about the serial device of this lidar
+    pub fn device_info(&self) -> &DeviceInfo {
+        &self.device_info
+    }
+
+    /// Subscribe to all packets of this lidar
+    ///
+    /// Every subscription receives the packets independently of `Lidar::next` and of the other subscriptions.
+    /// Only packets received after subscribing are delivered.
+    pub fn subscribe(&self) -> PacketSubscription {
+        PacketSubscription::new(self.packets.subscribe())
+    }
+
+    /// Subscribe to the raw bytes as they are read from the serial port, e.g. to capture them with `raw_capture`
+    ///
+    /// Every read of the serial port results in one chunk. Only chunks read after subscribing are delivered.
+    pub fn subscribe_raw(&self) -> broadcast::Receiver<RawChunk> {
+        self.raw_chunks.subscribe()
+    }
+
+    /// The sender of the raw chunks, for servers which subscribe every client independently
+    #[cfg(feature = "network")]
+    pub(crate) fn raw_chunk_sender(&self) -> broadcast::Sender<RawChunk> {
+        self.raw_chunks.clone()
+    }
+
+    /// Returns a watch on the latest complete revolution
+    ///
+    /// Use this when only the newest data matters, the value is `None` until the first revolution completed.
+    pub fn latest_scan(&self) -> watch::Receiver<Option<Arc<Scan>>> {
+        self.latest_scan.clone()
+    }
+
+    /// Returns a watch on the health state of the sensor
+    ///
+    /// The state is recalculated every time data arrives and at least every 500ms when the sensor is silent.
+    pub fn health(&self) -> watch::Receiver<HealthState> {
+        self.health.clone()
+    }
+
+    /// Subscribe to health state transitions
+    pub fn health_events(&self) -> broadcast::Receiver<HealthEvent> {
+        self.health_events.subscribe()
     }
 
     /// Read the next lidar package
+    ///
+    /// When packets are not read fast enough the oldest ones are skipped (this is logged), like a subscription.
     pub async fn next(&mut self) -> Option<Packet> {
-        self.receiver.recv().await
+        PacketStream::next(&mut self.receiver).await
     }
 }
 
@@ -111,15 +232,53 @@ impl PacketStream for Lidar {
     }
 }
 
-#[derive(Display, Into)]
-pub struct LidarName(String);
+#[derive(Display)]
+#[display(fmt = "{}", port_name)]
+pub struct LidarName {
+    port_name: String,
+    usb_serial_number: Option<String>,
+}
+
+/// Bytes received in a single read of the serial port
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawChunk {
+    pub received: Instant,
+    pub bytes: Vec<u8>,
+}
+
+impl LidarName {
+    /// Name of a serial port which is not found by `Lidar::enumerate`, e.g. a lidar behind another uart bridge or a virtual serial port
+    pub fn new(port_name: impl Into<String>) -> Self {
+        LidarName {
+            port_name: port_name.into(),
+            usb_serial_number: None,
+        }
+    }
+}
 
-impl<'a> Into<Cow<'a, str>> for LidarName {
-    fn into(self) -> Cow<'a, str> {
-        self.0.into()
+impl From<LidarName> for String {
+    fn from(name: LidarName) -> Self {
+        name.port_name
     }
 }
 
+impl<'a> From<LidarName> for Cow<'a, str> {
+    fn from(name: LidarName) -> Self {
+        name.port_name.into()
+    }
+}
+
+/// Information about the serial device a lidar is connected to
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct DeviceInfo {
+    /// Name of the serial port, e.g. /dev/ttyUSB0
+    pub port_name: String,
+    /// Serial number of the USB uart bridge, if known
+    pub usb_serial_number: Option<String>,
+    pub baud_rate: u32,
+}
+
 #[derive(Debug, Error)]
 pub enum EnumerateError {
     #[error("Failed get available ports: {0:}")]
@@ -131,3 +290,41 @@ pub enum LidarOpenError {
     #[error("Failed open serial port: {0:}")]
     FailedToOpenSerialPort(#[source] serialport::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_data::FakeSerialPort;
+    use crate::packet::DistancePacket;
+    use crate::subscription::SubscriptionError;
+
+    #[tokio::test]
+    async fn unread_packets_stay_bounded() {
+        let (sender, serial_port) = FakeSerialPort::new();
+        let device_info = DeviceInfo {
+            port_name: "fake".to_string(),
+            usb_serial_number: None,
+            baud_rate: LIDAR_BAUD_RATE,
+        };
+        let mut lidar = Lidar::spawn(serial_port, device_info);
+        let mut subscription = lidar.subscribe();
+
+        // Only the subscription is read, `Lidar::next` is never called while the packets arrive
+        let packet_count = 4 * SUBSCRIPTION_CAPACITY;
+        let packet = Packet::Distance(DistancePacket::new(6.5, 0.0, 0.0, vec![1000.0; 8]));
+        sender.send(packet.to_bytes().repeat(packet_count)).unwrap();
+
+        let mut delivered = 0;
+        while delivered < packet_count {
+            match subscription.recv().await {
+                Ok(_) => delivered += 1,
+                Err(SubscriptionError::Lagged(skipped)) => delivered += skipped as usize,
+                Err(SubscriptionError::Closed) => panic!("The lidar was closed"),
+            }
+        }
+
+        assert!(lidar.packets.len() <= SUBSCRIPTION_CAPACITY);
+        assert_eq!(Some(packet), lidar.next().await);
+        assert!(lidar.receiver.missed() >= (packet_count - SUBSCRIPTION_CAPACITY) as u64);
+    }
+}
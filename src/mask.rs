@@ -0,0 +1,323 @@
+//! Masks which hide the parts of the field of view that are blocked by the robot itself
+//!
+//! A mask consists of regions: sectors (angles in degrees) or polygons (coordinates in millimeters).
+//! Regions are defined in the frame of the lidar or in the frame of the robot, the `MountingPose` of the mask converts between both.
+//! Samples inside a region are marked as no return or dropped, see `MaskAction`.
+//!
+//! With the `serialize` feature a mask can be loaded from a JSON config file:
+//! ```json
+//! {
+//!   "mounting_pose": {"x": 100.0, "y": 0.0, "yaw": 90.0, "flipped": true},
+//!   "action": "Drop",
+//!   "regions": [
+//!     {"frame": "Sensor", "shape": {"Sector": {"start": 170.0, "end": 190.0, "max_distance": null}}},
+//!     {"frame": "Robot", "shape": {"Polygon": {"vertices": [{"x": -200.0, "y": -150.0}, {"x": 0.0, "y": -150.0}, {"x": 0.0, "y": 150.0}]}}}
+//!   ]
+//! }
+//! ```
+use crate::geometry::{MountingPose, Point2, Transform2};
+use crate::packet::{DistancePacket, Sample};
+use crate::scan::Scan;
+use crate::scan_filter::ScanFilter;
+#[cfg(feature = "serialize")]
+use serde::{Deserialize, Serialize};
+
+/// The frame of reference of a region
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum Frame {
+    /// The frame of the lidar: the x axis points at angle 0 (default)
+    #[default]
+    Sensor,
+    /// The frame the lidar is mounted in, see `MountingPose`
+    Robot,
+}
+
+/// What happens with the samples inside a mask
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum MaskAction {
+    /// Mark the samples as no return, so they keep their place in the scan (default)
+    #[default]
+    Mark,
+    /// Remove the samples from scans. Packets can't drop samples, these are always marked.
+    Drop,
+}
+
+/// The shape of a region
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum Shape {
+    /// The beams from `start` counter-clockwise to `end` (wrapping around 360 degrees), optionally only up to `max_distance`.
+    /// In the robot frame the angles are the directions of the beams in the robot frame.
+    Sector { start: f32, end: f32, max_distance: Option<f32> },
+    /// The inside of a polygon, the vertices are in order (either direction)
+    Polygon { vertices: Vec<Point2> },
+}
+
+/// A region in a frame of reference
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Region {
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub frame: Frame,
+    pub shape: Shape,
+}
+
+/// Hides samples which are inside any of its regions
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Mask {
+    /// Needed for regions in the robot frame
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub mounting_pose: MountingPose,
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub action: MaskAction,
+    pub regions: Vec<Region>,
+}
+
+impl Mask {
+    /// Create a mask without regions, which hides nothing
+    pub fn new() -> Self {
+        Mask::default()
+    }
+
+    /// Load a mask from a JSON config file
+    #[cfg(feature = "serialize")]
+    pub fn load(path: impl AsRef<std::path::Path>) -> anyhow::Result<Self> {
+        let config = std::fs::read_to_string(path)?;
+
+        Ok(serde_json::from_str(&config)?)
+    }
+
+    /// The mounting pose of the lidar, needed for regions in the robot frame
+    pub fn with_mounting_pose(mut self, mounting_pose: MountingPose) -> Self {
+        self.mounting_pose = mounting_pose;
+        self
+    }
+
+    /// Choose what happens with samples inside the mask, by default they are marked as no return
+    pub fn with_action(mut self, action: MaskAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Add a region in the given frame
+    pub fn with_region(mut self, frame: Frame, shape: Shape) -> Self {
+        self.regions.push(Region { frame, shape });
+        self
+    }
+
+    /// Whether the sample is inside any of the regions, samples without a return never are
+    pub fn contains(&self, sample: &Sample) -> bool {
+        if sample.distance <= 0.0 {
+            return false;
+        }
+
+        let transform = self.mounting_pose.transform();
+        // Only the direction of a beam matters for a sector, not the position of the lidar
+        let direction = Transform2 {
+            translation: Point2::default(),
+            ..transform
+        };
+
+        self.regions.iter().any(|region| match (&region.shape, region.frame) {
+            (Shape::Sector { start, end, max_distance }, frame) => {
+                let angle = match frame {
+                    Frame::Sensor => sample.angle,
+                    Frame::Robot => direction.apply(Point2::from_polar(sample.angle, 1.0)).angle(),
+                };
+                in_sector(angle, *start, *end) && max_distance.map(|max_distance| sample.distance <= max_distance).unwrap_or(true)
+            }
+            (Shape::Polygon { vertices }, Frame::Sensor) => in_polygon(sample.point(), vertices),
+            (Shape::Polygon { vertices }, Frame::Robot) => in_polygon(transform.apply(sample.point()), vertices),
+        })
+    }
+
+    /// Mark the samples of a packet inside the mask as no return
+    pub fn apply_packet(&self, packet: &mut DistancePacket) {
+        let masked: Vec<bool> = packet.samples().map(|sample| self.contains(&sample)).collect();
+
+        for (distance, masked) in packet.measurements_mut().iter_mut().zip(masked) {
+            if masked {
+                *distance = 0.0;
+            }
+        }
+    }
+
+    /// Mark or drop the samples of a scan inside the mask
+    pub fn apply_scan(&self, scan: &mut Scan) {
+        scan.apply_filter(self);
+    }
+}
+
+impl ScanFilter for Mask {
+    fn apply(&self, samples: &mut Vec<Sample>) {
+        match self.action {
+            MaskAction::Mark => {
+                for sample in samples.iter_mut() {
+                    if self.contains(sample) {
+                        sample.distance = 0.0;
+                    }
+                }
+            }
+            MaskAction::Drop => samples.retain(|sample| !self.contains(sample)),
+        }
+    }
+}
+
+fn in_sector(angle: f32, start: f32, end: f32) -> bool {
+    let angle = angle.rem_euclid(360.0);
+    let (start, end) = (start.rem_euclid(360.0), end.rem_euclid(360.0));
+
+    if start <= end {
+        angle >= start && angle <= end
+    } else {
+        angle >= start || angle <= end
+    }
+}
+
+/// Even-odd rule: a ray from the point crosses the edges of the polygon an odd amount of times when the point is inside
+fn in_polygon(point: Point2, vertices: &[Point2]) -> bool {
+    let mut inside = false;
+
+    for (index, current) in vertices.iter().enumerate() {
+        let previous = vertices[(index + vertices.len() - 1) % vertices.len()];
+        if (current.y > point.y) != (previous.y > point.y) {
+            let crossing = current.x + (point.y - current.y) * (previous.x - current.x) / (previous.y - current.y);
+            if point.x < crossing {
+                inside = !inside;
+            }
+        }
+    }
+
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(angle: f32, distance: f32) -> Sample {
+        Sample {
+            angle,
+            distance,
+            signal_strength: 0,
+        }
+    }
+
+    // Our lidar: in front of the center of the robot, upside down with its 0 degree angle pointing left
+    fn mounting_pose() -> MountingPose {
+        MountingPose {
+            x: 100.0,
+            y: 0.0,
+            yaw: 90.0,
+            flipped: true,
+        }
+    }
+
+    #[test]
+    fn sector_in_sensor_frame() {
+        let mask = Mask::new().with_region(
+            Frame::Sensor,
+            Shape::Sector {
+                start: 350.0,
+                end: 10.0,
+                max_distance: Some(300.0),
+            },
+        );
+
+        assert!(mask.contains(&sample(355.0, 200.0)));
+        assert!(mask.contains(&sample(5.0, 300.0)));
+        assert!(!mask.contains(&sample(5.0, 1000.0)));
+        assert!(!mask.contains(&sample(20.0, 200.0)));
+        assert!(!mask.contains(&sample(0.0, 0.0)));
+    }
+
+    #[test]
+    fn sector_in_robot_frame_of_mirrored_lidar() {
+        // Behind the robot
+        let mask = Mask::new().with_mounting_pose(mounting_pose()).with_region(
+            Frame::Robot,
+            Shape::Sector {
+                start: 170.0,
+                end: 190.0,
+                max_distance: None,
+            },
+        );
+
+        // The lidar is mirrored, so its angle 90 points at the front (0) and 270 at the back (180) of the robot
+        assert!(mask.contains(&sample(270.0, 1000.0)));
+        assert!(!mask.contains(&sample(90.0, 1000.0)));
+        assert!(!mask.contains(&sample(0.0, 1000.0)));
+    }
+
+    #[test]
+    fn polygon_in_robot_frame() {
+        // The chassis behind the lidar
+        let chassis = vec![
+            Point2::new(-300.0, -200.0),
+            Point2::new(0.0, -200.0),
+            Point2::new(0.0, 200.0),
+            Point2::new(-300.0, 200.0),
+        ];
+        let mask = Mask::new()
+            .with_mounting_pose(mounting_pose())
+            .with_region(Frame::Robot, Shape::Polygon { vertices: chassis });
+
+        // 250 mm behind the lidar is 150 mm behind the center of the robot
+        assert!(mask.contains(&sample(270.0, 250.0)));
+        assert!(!mask.contains(&sample(270.0, 500.0)));
+        // In the sensor frame the chassis would be on the other side
+        assert!(!mask.contains(&sample(90.0, 250.0)));
+    }
+
+    #[test]
+    fn marks_packets_and_drops_from_scans() {
+        let mask = Mask::new().with_region(
+            Frame::Sensor,
+            Shape::Sector {
+                start: 0.0,
+                end: 10.0,
+                max_distance: None,
+            },
+        );
+
+        let mut packet = DistancePacket::new(6.5, 0.0, 0.0, vec![1000.0, 1000.0, 1000.0, 1000.0]);
+        mask.apply_packet(&mut packet);
+        assert_eq!(&[0.0, 0.0, 1000.0, 1000.0], packet.measurements());
+
+        let samples: Vec<_> = (0..4).map(|index| sample(index as f32 * 5.625, 1000.0)).collect();
+        let mut scan = Scan::new(6.5, 1, samples.clone());
+        mask.apply_scan(&mut scan);
+        assert_eq!(4, scan.samples().len());
+        assert_eq!(0.0, scan.samples()[1].distance);
+
+        let mut scan = Scan::new(6.5, 1, samples);
+        mask.with_action(MaskAction::Drop).apply_scan(&mut scan);
+        assert_eq!(2, scan.samples().len());
+        assert_eq!(11.25, scan.samples()[0].angle);
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn loads_config() {
+        let config = r#"{
+            "mounting_pose": {"x": 100.0, "y": 0.0, "yaw": 90.0, "flipped": true},
+            "action": "Drop",
+            "regions": [
+                {"shape": {"Sector": {"start": 170.0, "end": 190.0, "max_distance": null}}},
+                {"frame": "Robot", "shape": {"Polygon": {"vertices": [{"x": 0.0, "y": 0.0}, {"x": 1.0, "y": 0.0}, {"x": 0.0, "y": 1.0}]}}}
+            ]
+        }"#;
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("mask.json");
+        std::fs::write(&path, config).unwrap();
+
+        let mask = Mask::load(&path).unwrap();
+        assert_eq!(mounting_pose(), mask.mounting_pose);
+        assert_eq!(MaskAction::Drop, mask.action);
+        assert_eq!(Frame::Sensor, mask.regions[0].frame);
+        assert_eq!(Frame::Robot, mask.regions[1].frame);
+    }
+}
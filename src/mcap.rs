@@ -0,0 +1,394 @@
+//! Export of lidar measurements to MCAP, e.g. to visualize them in Foxglove.
+//! It is hidden behind the `file` feature flag.
+//!
+//! An MCAP file contains three JSON encoded channels:
+//! - `/scan`: every revolution as a `sensor_msgs/LaserScan` shaped message, see `LaserScan`
+//! - `/packets`: every packet, as it is stored in a measurements file
+//! - `/health`: the transitions of the health state (see `HealthMonitor`), derived from the packets
+//!
+//! The log time of a message is the time at which the packet was received (nanoseconds since the unix epoch).
+//! Files are written without chunks or a summary section, readers which need an index can add it using `mcap recover`.
+use crate::health::{HealthConfig, HealthMonitor};
+use crate::laser_scan::{LaserScan, LaserScanAssembler};
+use crate::measurements_file::MeasurementReadFile;
+use crate::packet::Packet;
+use crate::packet_stream::PacketStream;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use log::warn;
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+const MAGIC: &[u8; 8] = b"\x89MCAP0\r\n";
+// Far larger than any message, anything bigger means the file is corrupt
+const MAX_RECORD_SIZE: u64 = 64 * 1024 * 1024;
+
+const OP_HEADER: u8 = 0x01;
+const OP_FOOTER: u8 = 0x02;
+const OP_SCHEMA: u8 = 0x03;
+const OP_CHANNEL: u8 = 0x04;
+const OP_MESSAGE: u8 = 0x05;
+const OP_DATA_END: u8 = 0x0F;
+
+pub const SCAN_TOPIC: &str = "/scan";
+pub const PACKETS_TOPIC: &str = "/packets";
+pub const HEALTH_TOPIC: &str = "/health";
+
+const SCAN_CHANNEL: u16 = 1;
+const PACKETS_CHANNEL: u16 = 2;
+const HEALTH_CHANNEL: u16 = 3;
+
+const LASER_SCAN_SCHEMA: &str = r#"{"type":"object","properties":{
+"header":{"type":"object","properties":{"stamp":{"type":"object","properties":{"sec":{"type":"integer"},"nsec":{"type":"integer"}}},"frame_id":{"type":"string"}}},
+"angle_min":{"type":"number"},"angle_max":{"type":"number"},"angle_increment":{"type":"number"},
+"time_increment":{"type":"number"},"scan_time":{"type":"number"},"range_min":{"type":"number"},"range_max":{"type":"number"},
+"ranges":{"type":"array","items":{"type":"number"}},"intensities":{"type":"array","items":{"type":"number"}}}}"#;
+const PACKET_SCHEMA: &str = r#"{"type":"object"}"#;
+const HEALTH_SCHEMA: &str = r#"{"type":"object","properties":{"previous":{"type":"string"},"current":{"type":"string"},"radar_speed":{"type":"number"}}}"#;
+
+/// Create a new MCAP file, an existing file is truncated
+pub async fn create(file_name: impl AsRef<Path>) -> Result<McapWriter> {
+    McapWriter::new(file_name).await
+}
+
+/// Open an MCAP file which was written by `McapWriter` for reading
+pub async fn open(file_name: impl AsRef<Path>) -> Result<McapReader> {
+    McapReader::new(file_name).await
+}
+
+/// Writes packets to an MCAP file, call `finish` when done
+pub struct McapWriter {
+    buffer: BufWriter<File>,
+    sequence: u32,
+    laser_scan_assembler: LaserScanAssembler,
+    health_monitor: Option<(HealthMonitor, SystemTime, Instant)>,
+}
+
+impl McapWriter {
+    async fn new(file_name: impl AsRef<Path>) -> Result<McapWriter> {
+        let file = File::create(file_name).await?;
+
+        let mut mcap_writer = McapWriter {
+            buffer: BufWriter::new(file),
+            sequence: 0,
+            laser_scan_assembler: LaserScanAssembler::new("laser"),
+            health_monitor: None,
+        };
+
+        mcap_writer.buffer.write_all(MAGIC).await?;
+
+        let mut header = Vec::new();
+        put_string(&mut header, "");
+        put_string(&mut header, concat!("delta_2a_lidar ", env!("CARGO_PKG_VERSION")));
+        mcap_writer.write_record(OP_HEADER, &header).await?;
+
+        let channels = [
+            (SCAN_CHANNEL, SCAN_TOPIC, "sensor_msgs/LaserScan", LASER_SCAN_SCHEMA),
+            (PACKETS_CHANNEL, PACKETS_TOPIC, "delta_2a_lidar/Packet", PACKET_SCHEMA),
+            (HEALTH_CHANNEL, HEALTH_TOPIC, "delta_2a_lidar/HealthEvent", HEALTH_SCHEMA),
+        ];
+        for (id, topic, schema_name, schema) in channels {
+            // Every channel has its own schema, with the same id
+            let mut record = id.to_le_bytes().to_vec();
+            put_string(&mut record, schema_name);
+            put_string(&mut record, "jsonschema");
+            put_bytes(&mut record, schema.as_bytes());
+            mcap_writer.write_record(OP_SCHEMA, &record).await?;
+
+            let mut record = id.to_le_bytes().to_vec();
+            record.extend_from_slice(&id.to_le_bytes());
+            put_string(&mut record, topic);
+            put_string(&mut record, "json");
+            // Empty metadata map
+            record.extend_from_slice(&0u32.to_le_bytes());
+            mcap_writer.write_record(OP_CHANNEL, &record).await?;
+        }
+
+        Ok(mcap_writer)
+    }
+
+    /// The frame id of the laser scans, `laser` by default
+    pub fn with_frame_id(mut self, frame_id: impl Into<String>) -> Self {
+        self.laser_scan_assembler = LaserScanAssembler::new(frame_id);
+        self
+    }
+
+    /// Write a packet which was received at `received`, a laser scan is written every time a revolution is completed
+    pub async fn write_packet(&mut self, received: SystemTime, packet: &Packet) -> Result<()> {
+        self.write_message(PACKETS_CHANNEL, received, &serde_json::to_vec(packet)?).await?;
+
+        if let Packet::Distance(distance_packet) = packet {
+            if let Some(laser_scan) = self.laser_scan_assembler.push(distance_packet, received) {
+                self.write_laser_scan(&laser_scan).await?;
+            }
+        }
+
+        // The health monitor works with instants, these are derived from the time the packets were received
+        let (health_monitor, first_received, first_instant) = self
+            .health_monitor
+            .get_or_insert_with(|| (HealthMonitor::new(HealthConfig::default(), Instant::now()), received, Instant::now()));
+        let now = *first_instant + received.duration_since(*first_received).unwrap_or(Duration::ZERO);
+        health_monitor.on_packet(packet, now);
+
+        if let Some(event) = health_monitor.update(now) {
+            let message = json!({
+                "previous": format!("{:?}", event.previous),
+                "current": format!("{:?}", event.current),
+                "radar_speed": health_monitor.radar_speed(),
+            });
+            self.write_message(HEALTH_CHANNEL, received, &serde_json::to_vec(&message)?).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a laser scan, e.g. one which was assembled outside of this writer
+    pub async fn write_laser_scan(&mut self, laser_scan: &LaserScan) -> Result<()> {
+        let (sec, nsec) = laser_scan.ros_stamp();
+        let message = json!({
+            "header": { "stamp": { "sec": sec, "nsec": nsec }, "frame_id": laser_scan.frame_id },
+            "angle_min": laser_scan.angle_min,
+            "angle_max": laser_scan.angle_max,
+            "angle_increment": laser_scan.angle_increment,
+            "time_increment": laser_scan.time_increment,
+            "scan_time": laser_scan.scan_time,
+            "range_min": laser_scan.range_min,
+            "range_max": laser_scan.range_max,
+            "ranges": laser_scan.ranges,
+            "intensities": laser_scan.intensities,
+        });
+
+        self.write_message(SCAN_CHANNEL, laser_scan.stamp, &serde_json::to_vec(&message)?).await
+    }
+
+    /// Write all packets of a stream until it ends, the packets are stamped with the time they pass
+    pub async fn record<S: PacketStream + ?Sized + Send>(&mut self, stream: &mut S) -> Result<()> {
+        while let Some(packet) = stream.next().await {
+            self.write_packet(SystemTime::now(), &packet).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write all packets of a measurements file, relative to the start time of the recording
+    pub async fn write_recording(&mut self, file: &mut MeasurementReadFile) -> Result<()> {
+        let start_time = file.metadata().map(|metadata| metadata.start_time).unwrap_or(SystemTime::UNIX_EPOCH);
+
+        while let Some(timestamped_packet) = file.next_timestamped().await? {
+            self.write_packet(start_time + timestamped_packet.timestamp, &timestamped_packet.packet).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Complete the file and write everything which is buffered
+    pub async fn finish(mut self) -> Result<()> {
+        // A CRC of 0 means the CRC isn't calculated
+        self.write_record(OP_DATA_END, &0u32.to_le_bytes()).await?;
+
+        // Without a summary section
+        let mut footer = Vec::with_capacity(20);
+        footer.extend_from_slice(&0u64.to_le_bytes());
+        footer.extend_from_slice(&0u64.to_le_bytes());
+        footer.extend_from_slice(&0u32.to_le_bytes());
+        self.write_record(OP_FOOTER, &footer).await?;
+
+        self.buffer.write_all(MAGIC).await?;
+        self.buffer.flush().await?;
+
+        Ok(())
+    }
+
+    async fn write_message(&mut self, channel: u16, log_time: SystemTime, data: &[u8]) -> Result<()> {
+        let log_time = log_time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO).as_nanos() as u64;
+
+        let mut record = Vec::with_capacity(22 + data.len());
+        record.extend_from_slice(&channel.to_le_bytes());
+        record.extend_from_slice(&self.sequence.to_le_bytes());
+        // Log time and publish time
+        record.extend_from_slice(&log_time.to_le_bytes());
+        record.extend_from_slice(&log_time.to_le_bytes());
+        record.extend_from_slice(data);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        self.write_record(OP_MESSAGE, &record).await
+    }
+
+    async fn write_record(&mut self, opcode: u8, content: &[u8]) -> Result<()> {
+        self.buffer.write_all(&[opcode]).await?;
+        self.buffer.write_all(&(content.len() as u64).to_le_bytes()).await?;
+        self.buffer.write_all(content).await?;
+
+        Ok(())
+    }
+}
+
+fn put_string(record: &mut Vec<u8>, value: &str) {
+    put_bytes(record, value.as_bytes());
+}
+
+fn put_bytes(record: &mut Vec<u8>, value: &[u8]) {
+    record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    record.extend_from_slice(value);
+}
+
+/// Reads the packets of an MCAP file. This file implements `PacketStream` and can be used to mock a Lidar sensor
+pub struct McapReader {
+    buffer: BufReader<File>,
+    // Channel id to topic
+    topics: HashMap<u16, String>,
+}
+
+impl McapReader {
+    async fn new(file_name: impl AsRef<Path>) -> Result<McapReader> {
+        let file = File::open(file_name).await?;
+        let mut buffer = BufReader::new(file);
+
+        let mut magic = [0u8; 8];
+        buffer.read_exact(&mut magic).await?;
+        if &magic != MAGIC {
+            bail!("Not an MCAP file");
+        }
+
+        Ok(McapReader {
+            buffer,
+            topics: HashMap::new(),
+        })
+    }
+
+    /// Reads the next packet and the time at which it was received.
+    /// Returns `Ok(None)` at the end of the file, messages of other channels are skipped.
+    pub async fn next_packet(&mut self) -> Result<Option<(SystemTime, Packet)>> {
+        loop {
+            let opcode = match self.buffer.read_u8().await {
+                Ok(opcode) => opcode,
+                // A truncated file ends between two records
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            let length = self.buffer.read_u64_le().await?;
+            if length > MAX_RECORD_SIZE {
+                bail!("Invalid record length: {}", length);
+            }
+            let mut content = vec![0u8; length as usize];
+            self.buffer.read_exact(&mut content).await?;
+
+            match opcode {
+                OP_CHANNEL if content.len() >= 4 => {
+                    let id = u16::from_le_bytes(content[..2].try_into()?);
+                    let (topic, _) = get_string(&content[4..])?;
+                    self.topics.insert(id, topic.to_string());
+                }
+                OP_MESSAGE if content.len() >= 22 => {
+                    let channel = u16::from_le_bytes(content[..2].try_into()?);
+                    if self.topics.get(&channel).map(String::as_str) != Some(PACKETS_TOPIC) {
+                        continue;
+                    }
+
+                    let log_time = u64::from_le_bytes(content[6..14].try_into()?);
+                    let packet = serde_json::from_slice(&content[22..])?;
+
+                    return Ok(Some((SystemTime::UNIX_EPOCH + Duration::from_nanos(log_time), packet)));
+                }
+                OP_DATA_END | OP_FOOTER => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn get_string(content: &[u8]) -> Result<(&str, &[u8])> {
+    if content.len() < 4 {
+        bail!("Truncated string");
+    }
+
+    let length = u32::from_le_bytes(content[..4].try_into()?) as usize;
+    match content.get(4..4 + length) {
+        Some(value) => Ok((std::str::from_utf8(value)?, &content[4 + length..])),
+        None => bail!("Truncated string"),
+    }
+}
+
+#[async_trait]
+impl PacketStream for McapReader {
+    async fn next(&mut self) -> Option<Packet> {
+        match self.next_packet().await {
+            Ok(packet) => packet.map(|(_, packet)| packet),
+            Err(e) => {
+                warn!("Stopped reading MCAP file: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{DistancePacket, LidarSpeedPacket};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn write_then_read() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.mcap");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let mut writer = create(&path).await.unwrap().with_frame_id("lidar");
+        for index in 0..40 {
+            let packet = DistancePacket::new(6.5, (index % 16) as f32 * 22.5, 0.0, vec![1000.0]);
+            writer
+                .write_packet(start + Duration::from_millis(index * 10), &Packet::Distance(packet))
+                .await
+                .unwrap();
+        }
+        writer
+            .write_packet(start + Duration::from_secs(1), &Packet::LidarSpeed(LidarSpeedPacket::new(1.0)))
+            .await
+            .unwrap();
+        writer.finish().await.unwrap();
+
+        let bytes = tokio::fs::read(&path).await.unwrap();
+        assert_eq!(MAGIC, &bytes[..8]);
+        assert_eq!(MAGIC, &bytes[bytes.len() - 8..]);
+
+        let mut reader = open(&path).await.unwrap();
+        let (received, packet) = reader.next_packet().await.unwrap().unwrap();
+        assert_eq!(start, received);
+        assert!(matches!(packet, Packet::Distance(_)));
+
+        let mut packets = 1;
+        while let Some(packet) = reader.next().await {
+            packets += 1;
+            if packets == 41 {
+                assert!(matches!(packet, Packet::LidarSpeed(_)));
+            }
+        }
+        assert_eq!(41, packets);
+    }
+
+    #[tokio::test]
+    async fn writes_laser_scans_and_health() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.mcap");
+
+        let mut writer = create(&path).await.unwrap();
+        for index in 0..40 {
+            let packet = DistancePacket::new(6.5, (index % 16) as f32 * 22.5, 0.0, vec![1000.0]);
+            writer.write_packet(SystemTime::now(), &Packet::Distance(packet)).await.unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        let bytes = tokio::fs::read(&path).await.unwrap();
+        let text = String::from_utf8_lossy(&bytes);
+        assert_eq!(1, text.matches(r#""frame_id":"laser""#).count());
+        assert!(text.contains(r#""previous":"Starting""#));
+    }
+}
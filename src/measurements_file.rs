@@ -1,74 +1,1121 @@
 //! This module is meant for mocking and recording lidar measurements.
 //! It is hidden behind the `file` feature flag.
+//!
+//! Three formats are supported, `read` detects the format of a file by itself:
+//! - JSON lines (default): every line contains a JSON encoded `TimestampedPacket`.
+//!   Files which were recorded before timestamps were added contain a bare `Packet` per line, these can still be read.
+//! - Binary: the packets as they are sent by the lidar with a timestamp, followed by an index for fast seeking.
+//!   These files are about 5 times smaller than JSON lines.
+//! - Codec: length delimited `TimestampedPacket`s serialized with JSON, CBOR, MessagePack or bincode, see `Codec`.
+//!
+//! Files start with a `Metadata` header which describes the recording, older files without a header can still be read.
+//!
+//! All formats can be compressed using gzip, which is done by default for file names ending with `.gz`.
+//! Compressed files are detected by `read` as well, they can't be seeked quickly because they have to be decompressed from the beginning.
+//!
+//! Long recordings can be split over numbered files, see `Rotation`. Use `read_rotated` to read all files as a single recording.
+use crate::geometry::MountingPose;
+use crate::lidar::DeviceInfo;
 use crate::packet::Packet;
-use crate::packet_stream::PacketStream;
-use anyhow::Result;
+use crate::packet_stream::{CorruptEntryPolicy, PacketStream, StreamError, TryPacketStream};
+use anyhow::{bail, Result};
 use async_trait::async_trait;
-use std::path::Path;
-use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
+use binary::{BinaryReader, BinaryWriter};
+use codec::{CodecReader, CodecWriter};
+use compression::{Input, Output};
+use json::{JsonReader, JsonWriter};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::fs::File;
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+
+mod binary;
+mod codec;
+mod compression;
+mod json;
+mod rotation;
+
+pub use codec::Codec;
+pub use rotation::{Retention, Rotation};
+
+// Roughly 10 seconds of packets at the nominal rotation speed
+const RECORDING_QUEUE_SIZE: usize = 1024;
 
 /// Open a file with measurements which were recorded using the `write` function
 pub async fn read(file_name: impl AsRef<Path>) -> Result<MeasurementReadFile> {
     MeasurementReadFile::new(file_name).await
 }
 
+/// Open all files of a recording which was split using `Rotation`, `file_name` is the name which was passed to `write_with_options`
+pub async fn read_rotated(file_name: impl AsRef<Path>) -> Result<MeasurementReadFile> {
+    let parts: Vec<_> = rotation::parts(file_name.as_ref()).await?.into_iter().map(|(_, path)| path).collect();
+    if parts.is_empty() {
+        bail!("No rotated measurement files found for {}", file_name.as_ref().display());
+    }
+
+    MeasurementReadFile::from_parts(parts).await
+}
+
 /// Open a measurements file for reading, the recorded packages can later be read using the `read` function
 pub async fn write(file_name: impl AsRef<Path>) -> Result<MeasurementWriteFile> {
-    MeasurementWriteFile::new(file_name).await
+    MeasurementWriteFile::new(file_name, WriteOptions::default()).await
+}
+
+/// Open a measurements file for writing with the given options
+pub async fn write_with_options(file_name: impl AsRef<Path>, options: WriteOptions) -> Result<MeasurementWriteFile> {
+    MeasurementWriteFile::new(file_name, options).await
+}
+
+/// Record all packets which pass through a stream to a new measurements file, see `RecordingStream`
+pub async fn record<S: PacketStream>(stream: S, file_name: impl AsRef<Path>) -> Result<RecordingStream<S>> {
+    let file = write(file_name).await?;
+
+    Ok(RecordingStream::new(stream, file))
+}
+
+/// Time between two packets of a legacy recording (without timestamps), one sector at the nominal speed of 6.5 r/s
+pub const DEFAULT_LEGACY_INTERVAL: Duration = Duration::from_nanos(1_000_000_000 / (65 * 16 / 10));
+
+/// A packet and the time at which it was received, relative to the start of the recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimestampedPacket {
+    pub timestamp: Duration,
+    pub packet: Packet,
+}
+
+/// Description of a recording, stored at the start of a measurements file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Version of the file format (JSON and binary files have their own versions)
+    pub format_version: u16,
+    /// Version of this crate which wrote the file
+    pub crate_version: String,
+    /// Serial device of the recorded lidar (port, USB serial number and baud rate)
+    #[serde(default)]
+    pub device_info: Option<DeviceInfo>,
+    /// Wall clock time at which the recording started, the timestamps of the packets are relative to this time
+    pub start_time: SystemTime,
+    #[serde(default)]
+    pub host_name: Option<String>,
+    /// Free form tags, e.g. the location or the scenario of the recording
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub mounting_pose: Option<MountingPose>,
+}
+
+/// Format of a measurements file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// A JSON encoded packet per line, easy to inspect (default)
+    #[default]
+    Json,
+    /// Compact binary packets with an index for seeking
+    Binary,
+    /// Packets serialized with the given codec
+    Codec(Codec),
+}
+
+/// Compression of a measurements file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Gzip when the file name ends with `.gz`, otherwise no compression (default)
+    #[default]
+    Auto,
+    None,
+    Gzip,
+}
+
+/// When a measurements file forces its data to the disk
+///
+/// Written packets are buffered, a crash (or power loss) loses everything which wasn't synced yet.
+/// Syncing often costs performance, especially on slow storage like SD cards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Only sync when the file is finished (default)
+    #[default]
+    OnFinish,
+    /// Sync after every given amount of packets
+    EveryPackets(u64),
+    /// Sync when the given time passed since the previous sync
+    Interval(Duration),
+}
+
+/// Options for `write_with_options`
+///
+/// The device info, tags and mounting pose are stored in the `Metadata` of the file.
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    pub format: Format,
+    pub device_info: Option<DeviceInfo>,
+    pub tags: BTreeMap<String, String>,
+    pub mounting_pose: Option<MountingPose>,
+    pub sync_policy: SyncPolicy,
+    pub compression: Compression,
+    /// Split the recording over numbered files, the file name passed to `write_with_options` is only used as the base name
+    pub rotation: Option<Rotation>,
+}
+
+enum Reader {
+    Json(JsonReader),
+    Binary(BinaryReader),
+    Codec(CodecReader),
+}
+
+impl Reader {
+    async fn open(file_name: &Path) -> Result<(Reader, Option<Metadata>)> {
+        let mut input = Input::open(file_name).await?;
+
+        // Peek at the start of the (decompressed) file to detect the format
+        let start = input.fill_buf().await?;
+        if start.starts_with(binary::MAGIC) {
+            let (reader, metadata) = BinaryReader::new(input).await?;
+            Ok((Reader::Binary(reader), metadata))
+        } else if start.starts_with(codec::MAGIC) {
+            let (reader, metadata) = CodecReader::new(input).await?;
+            Ok((Reader::Codec(reader), metadata))
+        } else {
+            let (reader, metadata) = JsonReader::new(input).await?;
+            Ok((Reader::Json(reader), metadata))
+        }
+    }
 }
 
 /// File containing lidar measurements. This file implements `PacketStream` and can be used to mock a Lidar sensor
+///
+/// Use `TryPacketStream::try_next` to find out why the stream ended, `PacketStream::next` logs the error and ends the stream.
 pub struct MeasurementReadFile {
-    lines: Lines<BufReader<File>>,
+    reader: Reader,
+    metadata: Option<Metadata>,
+    corrupt_entry_policy: CorruptEntryPolicy,
+    legacy_interval: Duration,
+    peeked: Option<TimestampedPacket>,
+    parts: Vec<PathBuf>,
+    part: usize,
+    // Added to the timestamps of the current part, so the parts of separate recordings don't overlap
+    time_offset: Duration,
 }
 
 impl MeasurementReadFile {
     async fn new(file_name: impl AsRef<Path>) -> Result<MeasurementReadFile> {
-        // Open the file in read mode
-        let file = OpenOptions::new().read(true).open(file_name).await?;
+        MeasurementReadFile::from_parts(vec![file_name.as_ref().to_path_buf()]).await
+    }
+
+    async fn from_parts(parts: Vec<PathBuf>) -> Result<MeasurementReadFile> {
+        let (reader, metadata) = Reader::open(&parts[0]).await?;
+
+        Ok(MeasurementReadFile {
+            reader,
+            metadata,
+            corrupt_entry_policy: CorruptEntryPolicy::default(),
+            legacy_interval: DEFAULT_LEGACY_INTERVAL,
+            peeked: None,
+            parts,
+            part: 0,
+            time_offset: Duration::ZERO,
+        })
+    }
+
+    /// Choose what happens with entries which can't be decoded, by default the stream stops
+    pub fn with_corrupt_entry_policy(mut self, corrupt_entry_policy: CorruptEntryPolicy) -> Self {
+        self.corrupt_entry_policy = corrupt_entry_policy;
+        self
+    }
+
+    /// Time between two packets of a legacy recording, which doesn't contain timestamps.
+    /// By default `DEFAULT_LEGACY_INTERVAL` is used.
+    pub fn with_legacy_interval(mut self, legacy_interval: Duration) -> Self {
+        self.legacy_interval = legacy_interval;
+        if let Reader::Json(reader) = &mut self.reader {
+            reader.set_legacy_interval(legacy_interval);
+        }
+        self
+    }
+
+    /// Continue with the given part of a rotated recording, or start the current part again
+    async fn select_part(&mut self, part: usize) -> Result<(), StreamError> {
+        if part == self.part {
+            return match &mut self.reader {
+                Reader::Json(reader) => reader.rewind().await,
+                Reader::Binary(reader) => reader.rewind().await,
+                Reader::Codec(reader) => reader.rewind().await,
+            };
+        }
+
+        let (mut reader, metadata) = Reader::open(&self.parts[part]).await.map_err(|e| match e.downcast::<std::io::Error>() {
+            Ok(source) => StreamError::Io { line: 0, source },
+            Err(e) => StreamError::Corrupt { line: 0, source: e.into() },
+        })?;
+        if let Reader::Json(reader) = &mut reader {
+            reader.set_legacy_interval(self.legacy_interval);
+        }
+
+        // The parts of a single recording share their start time
+        let start_time = |metadata: Option<&Metadata>| metadata.map(|metadata| metadata.start_time);
+        self.time_offset = match (start_time(self.metadata.as_ref()), start_time(metadata.as_ref())) {
+            (Some(first), Some(current)) => current.duration_since(first).unwrap_or_default(),
+            _ => Duration::ZERO,
+        };
+        self.reader = reader;
+        self.part = part;
+
+        Ok(())
+    }
+
+    /// Description of the recording, `None` for files which were written before metadata was added
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Format of this file
+    pub fn format(&self) -> Format {
+        match &self.reader {
+            Reader::Json(_) => Format::Json,
+            Reader::Binary(_) => Format::Binary,
+            Reader::Codec(reader) => Format::Codec(reader.codec()),
+        }
+    }
+
+    /// Reads the next packet together with its timestamp.
+    /// Returns `Ok(None)` if the file has ended.
+    ///
+    /// The packets of a legacy recording are timestamped using the legacy interval.
+    pub async fn next_timestamped(&mut self) -> Result<Option<TimestampedPacket>, StreamError> {
+        if let Some(timestamped_packet) = self.peeked.take() {
+            return Ok(Some(timestamped_packet));
+        }
+
+        loop {
+            let result = match &mut self.reader {
+                Reader::Json(reader) => reader.next().await,
+                Reader::Binary(reader) => reader.next().await,
+                Reader::Codec(reader) => reader.next().await,
+            };
+
+            match result {
+                Ok(Some(mut timestamped_packet)) => {
+                    timestamped_packet.timestamp += self.time_offset;
+                    return Ok(Some(timestamped_packet));
+                }
+                Ok(None) if self.part + 1 < self.parts.len() => self.select_part(self.part + 1).await?,
+                Err(e @ StreamError::Corrupt { .. }) if self.corrupt_entry_policy == CorruptEntryPolicy::Skip => {
+                    warn!("Skipping entry: {}", e)
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Start reading from the beginning of the file (or the first file of a rotated recording) again
+    pub async fn rewind(&mut self) -> Result<(), StreamError> {
+        self.peeked = None;
 
-        // Create a buffered reader end get a lines() iterator
-        let buffered_reader = BufReader::new(file);
-        let lines = buffered_reader.lines();
+        self.select_part(0).await
+    }
+
+    /// Continue reading at the given packet, the first packet is number 0
+    ///
+    /// Uncompressed binary files jump close to the packet using the index, other files (and rotated recordings) are read from the beginning.
+    pub async fn seek_to_packet(&mut self, packet_number: u64) -> Result<(), StreamError> {
+        self.peeked = None;
+
+        let mut current = match &mut self.reader {
+            Reader::Binary(reader) if self.parts.len() == 1 => reader.seek_to_packet(packet_number).await?,
+            _ => self.select_part(0).await.map(|_| 0)?,
+        };
+
+        while current < packet_number {
+            if self.next_timestamped().await?.is_none() {
+                break;
+            }
+            current += 1;
+        }
+
+        Ok(())
+    }
 
-        Ok(MeasurementReadFile { lines })
+    /// Continue reading at the first packet with a timestamp at or after the given timestamp
+    ///
+    /// Uncompressed binary files jump close to the packet using the index, other files are read from the beginning.
+    /// A rotated recording continues in the last file which starts at or before the timestamp.
+    pub async fn seek_to_time(&mut self, timestamp: Duration) -> Result<(), StreamError> {
+        self.peeked = None;
+
+        let mut part = self.parts.len() - 1;
+        while part > 0 {
+            self.select_part(part).await?;
+            match self.next_timestamped().await? {
+                Some(first) if first.timestamp <= timestamp && self.part == part => break,
+                _ => part -= 1,
+            }
+        }
+
+        self.select_part(part).await?;
+        if let Reader::Binary(reader) = &mut self.reader {
+            reader.seek_to_time(timestamp.saturating_sub(self.time_offset)).await?;
+        }
+
+        while let Some(timestamped_packet) = self.next_timestamped().await? {
+            if timestamped_packet.timestamp >= timestamp {
+                self.peeked = Some(timestamped_packet);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TryPacketStream for MeasurementReadFile {
+    async fn try_next(&mut self) -> Result<Option<Packet>, StreamError> {
+        let timestamped_packet = self.next_timestamped().await?;
+
+        Ok(timestamped_packet.map(|timestamped_packet| timestamped_packet.packet))
     }
 }
 
 #[async_trait]
 impl PacketStream for MeasurementReadFile {
     async fn next(&mut self) -> Option<Packet> {
-        let line = self.lines.next_line().await.ok().flatten()?;
+        match self.try_next().await {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Stopped reading measurements: {}", e);
+                None
+            }
+        }
+    }
+}
+
+enum Writer {
+    Json(JsonWriter),
+    Binary(BinaryWriter),
+    Codec(CodecWriter),
+}
+
+impl Writer {
+    async fn create(file_name: &Path, options: &WriteOptions, metadata: &Metadata) -> Result<Writer> {
+        // Will create a new file or truncate to the existing file
+        let file = File::create(file_name).await?;
+        let output = Output::new(file, options.compression.for_file(file_name));
+
+        match options.format {
+            Format::Json => Ok(Writer::Json(JsonWriter::new(output, metadata).await?)),
+            Format::Binary => Ok(Writer::Binary(BinaryWriter::new(output, metadata).await?)),
+            Format::Codec(codec) => Ok(Writer::Codec(CodecWriter::new(output, codec, metadata).await?)),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self {
+            Writer::Json(writer) => writer.size(),
+            Writer::Binary(writer) => writer.size(),
+            Writer::Codec(writer) => writer.size(),
+        }
+    }
 
-        serde_json::from_str(&line).ok()
+    async fn finish(&mut self) -> Result<()> {
+        match self {
+            Writer::Json(writer) => writer.finish().await,
+            Writer::Binary(writer) => writer.finish().await,
+            Writer::Codec(writer) => writer.finish().await,
+        }
     }
 }
 
 /// A helper struct to write lidar measurements to a file
+///
+/// Call `finish` when done, buffered packets are lost when the file is dropped and binary files are only seekable once they are finished.
 pub struct MeasurementWriteFile {
-    buffer: BufWriter<File>,
+    writer: Writer,
+    started: Instant,
+    sync_policy: SyncPolicy,
+    unsynced_packets: u64,
+    last_sync: Instant,
+    rotator: Option<rotation::Rotator>,
 }
 
 impl MeasurementWriteFile {
-    async fn new(file_name: impl AsRef<Path>) -> Result<MeasurementWriteFile> {
-        // Open the file in write mode.
-        // Will create a new file or truncate to the existing file
-        let file = File::create(file_name).await?;
+    async fn new(file_name: impl AsRef<Path>, options: WriteOptions) -> Result<MeasurementWriteFile> {
+        let started = Instant::now();
+        let metadata = Metadata {
+            format_version: match options.format {
+                Format::Json => json::FORMAT_VERSION,
+                Format::Binary => binary::FORMAT_VERSION,
+                Format::Codec(_) => codec::FORMAT_VERSION,
+            },
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            device_info: options.device_info.clone(),
+            start_time: SystemTime::now(),
+            host_name: hostname::get().ok().and_then(|host_name| host_name.into_string().ok()),
+            tags: options.tags.clone(),
+            mounting_pose: options.mounting_pose,
+        };
 
-        // Create a buffered writer
-        let buffer = BufWriter::new(file);
+        let (writer, rotator) = match options.rotation {
+            Some(rotation) => {
+                let (rotator, writer) = rotation::Rotator::new(file_name.as_ref(), rotation, options.clone(), metadata).await?;
+                (writer, Some(rotator))
+            }
+            None => (Writer::create(file_name.as_ref(), &options, &metadata).await?, None),
+        };
 
-        Ok(MeasurementWriteFile { buffer })
+        Ok(MeasurementWriteFile {
+            writer,
+            started,
+            sync_policy: options.sync_policy,
+            unsynced_packets: 0,
+            last_sync: started,
+            rotator,
+        })
     }
 
-    /// Write a packet to the file
+    /// Write a packet to the file, it is timestamped with the time since the file was created
     pub async fn write(&mut self, packet: &Packet) -> Result<()> {
-        let bytes = serde_json::to_string(packet)?;
+        let timestamped_packet = TimestampedPacket {
+            timestamp: self.started.elapsed(),
+            packet: packet.clone(),
+        };
+
+        self.write_timestamped(&timestamped_packet).await
+    }
+
+    /// Write a packet with a known timestamp to the file, e.g. when copying a recording
+    pub async fn write_timestamped(&mut self, timestamped_packet: &TimestampedPacket) -> Result<()> {
+        if let Some(rotator) = &mut self.rotator {
+            if rotator.is_due(timestamped_packet.timestamp, self.writer.size()) {
+                // Finishing syncs the file
+                self.writer.finish().await?;
+                self.writer = rotator.rotate(timestamped_packet.timestamp).await?;
+                self.unsynced_packets = 0;
+                self.last_sync = Instant::now();
+            }
+        }
+
+        match &mut self.writer {
+            Writer::Json(writer) => writer.write(timestamped_packet).await?,
+            Writer::Binary(writer) => writer.write(timestamped_packet).await?,
+            Writer::Codec(writer) => writer.write(timestamped_packet).await?,
+        }
+        self.unsynced_packets += 1;
 
-        self.buffer.write(bytes.as_bytes()).await?;
-        self.buffer.write(b"\n").await?;
+        let sync_due = match self.sync_policy {
+            SyncPolicy::OnFinish => false,
+            SyncPolicy::EveryPackets(packets) => self.unsynced_packets >= packets,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+
+        if sync_due {
+            self.sync().await?;
+        }
 
         Ok(())
     }
+
+    /// Write all buffered packets to the file (the operating system might still cache them)
+    pub async fn flush(&mut self) -> Result<()> {
+        match &mut self.writer {
+            Writer::Json(writer) => writer.flush().await,
+            Writer::Binary(writer) => writer.flush().await,
+            Writer::Codec(writer) => writer.flush().await,
+        }
+    }
+
+    /// Write all buffered packets to the file and wait until they are stored on the disk
+    pub async fn sync(&mut self) -> Result<()> {
+        match &mut self.writer {
+            Writer::Json(writer) => writer.sync().await?,
+            Writer::Binary(writer) => writer.sync().await?,
+            Writer::Codec(writer) => writer.sync().await?,
+        }
+
+        self.unsynced_packets = 0;
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    /// Complete the file (e.g. write the index of a binary file) and wait until it is stored on the disk
+    pub async fn finish(mut self) -> Result<()> {
+        self.writer.finish().await
+    }
+}
+
+/// A packet stream which records every packet that passes through to a measurements file
+///
+/// The packets are written by a background task, so a slow disk never blocks the consumer.
+/// When the disk can't keep up for a while, packets are dropped from the recording (not from the stream), see `dropped`.
+/// Must be created from within a tokio runtime.
+/// ```no_run
+///# let blah = async {
+/// use delta_2a_lidar::packet_stream::PacketStream;
+/// use delta_2a_lidar::{measurements_file, Lidar};
+///
+/// let lidar_name = Lidar::enumerate().unwrap().next().unwrap();
+/// let mut lidar = measurements_file::record(Lidar::open(lidar_name).unwrap(), "./measurements.ldr").await.unwrap();
+///
+/// while let Some(package) = lidar.next().await {
+///     println!("Received (and recorded) package: {:?}", package);
+/// }
+///# };
+/// ```
+pub struct RecordingStream<S> {
+    stream: S,
+    // `None` once the writer stopped
+    sender: Option<mpsc::Sender<TimestampedPacket>>,
+    started: Instant,
+    writer: JoinHandle<Result<()>>,
+    dropped: u64,
+    dropping: bool,
+}
+
+impl<S: PacketStream> RecordingStream<S> {
+    /// Record the packets of `stream` to `file`
+    pub fn new(stream: S, mut file: MeasurementWriteFile) -> Self {
+        // The packets are timestamped when they pass, not when the writer gets to them
+        let started = file.started;
+        let (sender, mut receiver) = mpsc::channel(RECORDING_QUEUE_SIZE);
+
+        let writer = tokio::spawn(async move {
+            while let Some(timestamped_packet) = receiver.recv().await {
+                if let Err(e) = file.write_timestamped(&timestamped_packet).await {
+                    warn!("Failed to record packet, stop recording: {}", e);
+                    return Err(e);
+                }
+            }
+
+            file.finish().await
+        });
+
+        RecordingStream {
+            stream,
+            sender: Some(sender),
+            started,
+            writer,
+            dropped: 0,
+            dropping: false,
+        }
+    }
+
+    /// Amount of packets which were not recorded because the writer fell behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Stop recording, waits until all packets are written and returns the inner stream
+    ///
+    /// Returns an error when the recording failed.
+    pub async fn finish(self) -> Result<S> {
+        let RecordingStream {
+            stream,
+            sender,
+            writer,
+            dropped,
+            ..
+        } = self;
+
+        // Closing the channel stops the writer once all pending packets are written
+        drop(sender);
+        writer.await??;
+
+        if dropped > 0 {
+            warn!("The recording is missing {} packets, the writer could not keep up", dropped);
+        }
+
+        Ok(stream)
+    }
+}
+
+#[async_trait]
+impl<S: PacketStream + Send> PacketStream for RecordingStream<S> {
+    async fn next(&mut self) -> Option<Packet> {
+        let packet = self.stream.next().await?;
+
+        if let Some(sender) = &self.sender {
+            let timestamped_packet = TimestampedPacket {
+                timestamp: self.started.elapsed(),
+                packet: packet.clone(),
+            };
+
+            match sender.try_send(timestamped_packet) {
+                Ok(()) => self.dropping = false,
+                Err(TrySendError::Full(_)) => {
+                    if !self.dropping {
+                        warn!("The recording can't keep up, dropping packets");
+                        self.dropping = true;
+                    }
+                    self.dropped += 1;
+                }
+                // The writer only stops early on an error, which is reported by `finish`
+                Err(TrySendError::Closed(_)) => self.sender = None,
+            }
+        }
+
+        Some(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{DistancePacket, LidarSpeedPacket};
+    use tempfile::TempDir;
+
+    async fn write_lines(dir: &TempDir, lines: &[&str]) -> MeasurementReadFile {
+        let path = dir.path().join("measurements.ldr");
+        tokio::fs::write(&path, lines.join("\n")).await.unwrap();
+
+        read(&path).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn write_then_read() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.ldr");
+
+        let mut file = write(&path).await.unwrap();
+        file.write(&Packet::Distance(DistancePacket::new(6.5, 22.5, 0.0, vec![1000.0]))).await.unwrap();
+        file.write(&Packet::LidarSpeed(LidarSpeedPacket::new(1.0))).await.unwrap();
+        file.finish().await.unwrap();
+
+        let mut file = read(&path).await.unwrap();
+        assert_eq!(Format::Json, file.format());
+        assert!(matches!(file.try_next().await, Ok(Some(Packet::Distance(_)))));
+        assert!(matches!(file.try_next().await, Ok(Some(Packet::LidarSpeed(_)))));
+        assert!(matches!(file.try_next().await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn metadata_header() {
+        let dir = TempDir::new().unwrap();
+
+        let codecs = [Codec::Json, Codec::Cbor, Codec::MessagePack, Codec::Bincode].map(Format::Codec);
+        for format in [Format::Json, Format::Binary].iter().chain(&codecs).copied() {
+            let path = dir.path().join("measurements.ldr");
+            let options = WriteOptions {
+                format,
+                device_info: Some(DeviceInfo {
+                    port_name: "/dev/ttyUSB0".to_string(),
+                    usb_serial_number: Some("0001".to_string()),
+                    baud_rate: 230_400,
+                }),
+                tags: vec![("location".to_string(), "hallway".to_string())].into_iter().collect(),
+                mounting_pose: Some(MountingPose {
+                    x: 100.0,
+                    y: -50.0,
+                    yaw: 90.0,
+                    flipped: true,
+                }),
+                ..WriteOptions::default()
+            };
+
+            let mut file = write_with_options(&path, options.clone()).await.unwrap();
+            file.write(&Packet::LidarSpeed(LidarSpeedPacket::new(1.0))).await.unwrap();
+            file.finish().await.unwrap();
+
+            let mut file = read(&path).await.unwrap();
+            let metadata = file.metadata().unwrap().clone();
+            assert_eq!(env!("CARGO_PKG_VERSION"), metadata.crate_version);
+            assert_eq!(options.device_info, metadata.device_info);
+            assert_eq!(options.tags, metadata.tags);
+            assert_eq!(options.mounting_pose, metadata.mounting_pose);
+
+            // The header isn't an entry
+            assert!(matches!(file.try_next().await, Ok(Some(Packet::LidarSpeed(_)))));
+            assert!(matches!(file.try_next().await, Ok(None)));
+        }
+
+        let file = write_lines(&dir, &[r#"{"LidarSpeed":{"radar_speed":1.0}}"#]).await;
+        assert_eq!(None, file.metadata());
+    }
+
+    #[tokio::test]
+    async fn records_passing_packets() {
+        let dir = TempDir::new().unwrap();
+        let source = write_lines(&dir, &[r#"{"LidarSpeed":{"radar_speed":1.0}}"#, r#"{"LidarSpeed":{"radar_speed":2.0}}"#]).await;
+        let path = dir.path().join("recording.ldr");
+
+        let mut recording = record(source, &path).await.unwrap();
+        assert!(recording.next().await.is_some());
+        assert!(recording.next().await.is_some());
+        assert!(recording.next().await.is_none());
+        recording.finish().await.unwrap();
+
+        let mut file = read(&path).await.unwrap();
+        assert!(matches!(file.next().await, Some(Packet::LidarSpeed(_))));
+        assert!(matches!(file.next().await, Some(Packet::LidarSpeed(_))));
+        assert!(file.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn drops_packets_when_the_writer_falls_behind() {
+        struct RepeatStream;
+
+        #[async_trait]
+        impl PacketStream for RepeatStream {
+            async fn next(&mut self) -> Option<Packet> {
+                Some(Packet::LidarSpeed(LidarSpeedPacket::new(1.0)))
+            }
+        }
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("recording.ldr");
+
+        // The writer task only runs once the test yields, so the queue fills up
+        let mut recording = record(RepeatStream, &path).await.unwrap();
+        for _ in 0..2 * RECORDING_QUEUE_SIZE {
+            assert!(recording.next().await.is_some());
+        }
+        assert_eq!(RECORDING_QUEUE_SIZE as u64, recording.dropped());
+        recording.finish().await.unwrap();
+
+        let mut file = read(&path).await.unwrap();
+        let mut recorded = 0;
+        while file.next().await.is_some() {
+            recorded += 1;
+        }
+        assert_eq!(RECORDING_QUEUE_SIZE, recorded);
+    }
+
+    #[tokio::test]
+    async fn reads_legacy_and_timestamped_entries() {
+        let dir = TempDir::new().unwrap();
+        let mut file = write_lines(
+            &dir,
+            &[
+                r#"{"timestamp":{"secs":1,"nanos":500},"packet":{"LidarSpeed":{"radar_speed":1.0}}}"#,
+                r#"{"LidarSpeed":{"radar_speed":1.0}}"#,
+            ],
+        )
+        .await
+        .with_legacy_interval(Duration::from_millis(10));
+
+        assert_eq!(Duration::new(1, 500), file.next_timestamped().await.unwrap().unwrap().timestamp);
+        assert_eq!(Duration::new(1, 10_000_500), file.next_timestamped().await.unwrap().unwrap().timestamp);
+
+        file.rewind().await.unwrap();
+        assert_eq!(Duration::new(1, 500), file.next_timestamped().await.unwrap().unwrap().timestamp);
+    }
+
+    #[tokio::test]
+    async fn stops_on_corrupt_entry() {
+        let dir = TempDir::new().unwrap();
+        let mut file = write_lines(
+            &dir,
+            &[r#"{"LidarSpeed":{"radar_speed":1.0}}"#, "{garbage", r#"{"LidarSpeed":{"radar_speed":1.0}}"#],
+        )
+        .await;
+
+        assert!(matches!(file.try_next().await, Ok(Some(_))));
+        assert!(matches!(file.try_next().await, Err(StreamError::Corrupt { line: 2, .. })));
+    }
+
+    #[tokio::test]
+    async fn skips_corrupt_entry() {
+        let dir = TempDir::new().unwrap();
+        let mut file = write_lines(&dir, &["{garbage", r#"{"LidarSpeed":{"radar_speed":1.0}}"#, ""])
+            .await
+            .with_corrupt_entry_policy(CorruptEntryPolicy::Skip);
+
+        assert!(matches!(file.next().await, Some(Packet::LidarSpeed(_))));
+        assert!(file.next().await.is_none());
+    }
+
+    fn speed_packet(index: u64) -> TimestampedPacket {
+        TimestampedPacket {
+            timestamp: Duration::from_millis(index * 10),
+            packet: Packet::LidarSpeed(LidarSpeedPacket::new(index as f32 * 0.05)),
+        }
+    }
+
+    fn index_of(timestamped_packet: Option<TimestampedPacket>) -> u64 {
+        (timestamped_packet.unwrap().timestamp.as_millis() / 10) as u64
+    }
+
+    async fn write_binary(path: &Path, packets: u64) -> MeasurementWriteFile {
+        let options = WriteOptions {
+            format: Format::Binary,
+            ..WriteOptions::default()
+        };
+        let mut file = write_with_options(path, options).await.unwrap();
+        for index in 0..packets {
+            file.write_timestamped(&speed_packet(index)).await.unwrap();
+        }
+        file
+    }
+
+    #[tokio::test]
+    async fn binary_write_then_read() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.ldr");
+
+        let mut file = write_binary(&path, 0).await;
+        let distance_packet = DistancePacket::new(6.5, 22.5, 1.35, vec![0.0, 1000.0]).with_signal_strengths(vec![0, 0x46]);
+        file.write(&Packet::Distance(distance_packet.clone())).await.unwrap();
+        file.write(&Packet::LidarSpeed(LidarSpeedPacket::new(1.0))).await.unwrap();
+        file.finish().await.unwrap();
+
+        let mut file = read(&path).await.unwrap();
+        assert_eq!(Format::Binary, file.format());
+        match file.try_next().await {
+            Ok(Some(Packet::Distance(packet))) => assert_eq!(distance_packet, packet),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(matches!(file.try_next().await, Ok(Some(Packet::LidarSpeed(_)))));
+        assert!(matches!(file.try_next().await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn binary_seek() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.ldr");
+        write_binary(&path, 1000).await.finish().await.unwrap();
+
+        let mut file = read(&path).await.unwrap();
+        file.seek_to_packet(700).await.unwrap();
+        assert_eq!(700, index_of(file.next_timestamped().await.unwrap()));
+
+        file.seek_to_time(Duration::from_millis(3005)).await.unwrap();
+        assert_eq!(301, index_of(file.next_timestamped().await.unwrap()));
+
+        file.seek_to_packet(999).await.unwrap();
+        assert_eq!(999, index_of(file.next_timestamped().await.unwrap()));
+        assert!(file.next_timestamped().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn ignores_truncated_last_entry() {
+        let dir = TempDir::new().unwrap();
+        let mut file = write_lines(&dir, &[r#"{"LidarSpeed":{"radar_speed":1.0}}"#, r#"{"LidarSpeed":{"radar_"#]).await;
+
+        assert!(matches!(file.try_next().await, Ok(Some(_))));
+        assert!(matches!(file.try_next().await, Ok(None)));
+
+        let path = dir.path().join("measurements.bin");
+        write_binary(&path, 10).await.sync().await.unwrap();
+        let length = tokio::fs::metadata(&path).await.unwrap().len();
+        tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .await
+            .unwrap()
+            .set_len(length - 3)
+            .await
+            .unwrap();
+
+        let mut file = read(&path).await.unwrap();
+        file.seek_to_packet(8).await.unwrap();
+        assert_eq!(8, index_of(file.next_timestamped().await.unwrap()));
+        assert!(file.next_timestamped().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn syncs_every_packets() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.ldr");
+        let options = WriteOptions {
+            sync_policy: SyncPolicy::EveryPackets(2),
+            ..WriteOptions::default()
+        };
+
+        let mut writer = write_with_options(&path, options).await.unwrap();
+        for index in 0..3 {
+            writer.write_timestamped(&speed_packet(index)).await.unwrap();
+        }
+
+        // The third packet is still buffered
+        let mut file = read(&path).await.unwrap();
+        assert_eq!(0, index_of(file.next_timestamped().await.unwrap()));
+        assert_eq!(1, index_of(file.next_timestamped().await.unwrap()));
+        assert!(file.next_timestamped().await.unwrap().is_none());
+
+        writer.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn binary_without_index() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.ldr");
+        write_binary(&path, 300).await.flush().await.unwrap();
+
+        let mut file = read(&path).await.unwrap();
+        file.seek_to_time(Duration::from_millis(2000)).await.unwrap();
+        assert_eq!(200, index_of(file.next_timestamped().await.unwrap()));
+
+        file.seek_to_packet(299).await.unwrap();
+        assert_eq!(299, index_of(file.next_timestamped().await.unwrap()));
+        assert!(file.next_timestamped().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn codecs_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let distance_packet = Packet::Distance(DistancePacket::new(6.5, 22.5, 1.35, vec![0.0, 1000.0]).with_signal_strengths(vec![0, 0x46]));
+
+        for codec in [Codec::Json, Codec::Cbor, Codec::MessagePack, Codec::Bincode] {
+            let path = dir.path().join("measurements.ldr");
+            let options = WriteOptions {
+                format: Format::Codec(codec),
+                ..WriteOptions::default()
+            };
+
+            let mut file = write_with_options(&path, options).await.unwrap();
+            file.write(&distance_packet).await.unwrap();
+            for index in 1..10 {
+                file.write_timestamped(&speed_packet(index)).await.unwrap();
+            }
+            file.finish().await.unwrap();
+
+            // An interrupted recording ends in the middle of a record
+            let length = std::fs::metadata(&path).unwrap().len();
+            tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .await
+                .unwrap()
+                .set_len(length - 2)
+                .await
+                .unwrap();
+
+            let mut file = read(&path).await.unwrap();
+            assert_eq!(Format::Codec(codec), file.format());
+            assert!(file.metadata().is_some());
+            assert_eq!(Some(distance_packet.clone()), file.try_next().await.unwrap());
+
+            file.seek_to_packet(8).await.unwrap();
+            assert_eq!(8, index_of(file.next_timestamped().await.unwrap()));
+            assert!(file.next_timestamped().await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn gzip_by_extension() {
+        let dir = TempDir::new().unwrap();
+
+        for format in [Format::Json, Format::Binary] {
+            let path = dir.path().join("measurements.ldr.gz");
+            let options = WriteOptions {
+                format,
+                ..WriteOptions::default()
+            };
+
+            let mut file = write_with_options(&path, options).await.unwrap();
+            for index in 0..300 {
+                file.write_timestamped(&speed_packet(index)).await.unwrap();
+            }
+            file.finish().await.unwrap();
+
+            let bytes = tokio::fs::read(&path).await.unwrap();
+            assert_eq!(&[0x1F, 0x8B], &bytes[..2]);
+
+            let mut file = read(&path).await.unwrap();
+            assert_eq!(format, file.format());
+            assert!(file.metadata().is_some());
+            file.seek_to_packet(280).await.unwrap();
+            assert_eq!(280, index_of(file.next_timestamped().await.unwrap()));
+            file.seek_to_time(Duration::from_millis(1005)).await.unwrap();
+            assert_eq!(101, index_of(file.next_timestamped().await.unwrap()));
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_unfinished_gzip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.ldr");
+        let options = WriteOptions {
+            compression: Compression::Gzip,
+            ..WriteOptions::default()
+        };
+
+        let mut writer = write_with_options(&path, options).await.unwrap();
+        for index in 0..3 {
+            writer.write_timestamped(&speed_packet(index)).await.unwrap();
+        }
+        writer.sync().await.unwrap();
+
+        let mut file = read(&path).await.unwrap();
+        for index in 0..3 {
+            assert_eq!(index, index_of(file.next_timestamped().await.unwrap()));
+        }
+        assert!(file.next_timestamped().await.unwrap().is_none());
+    }
+
+    async fn write_rotating(path: &Path, packets: u64, rotation: Rotation) {
+        let options = WriteOptions {
+            rotation: Some(rotation),
+            ..WriteOptions::default()
+        };
+
+        let mut file = write_with_options(path, options).await.unwrap();
+        for index in 0..packets {
+            file.write_timestamped(&speed_packet(index)).await.unwrap();
+        }
+        file.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotates_by_duration() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.ldr");
+        let rotation = Rotation {
+            max_file_duration: Some(Duration::from_millis(100)),
+            ..Rotation::default()
+        };
+        write_rotating(&path, 100, rotation).await;
+
+        assert!(!path.exists());
+        assert_eq!(10, rotation::parts(&path).await.unwrap().len());
+
+        let mut file = read_rotated(&path).await.unwrap();
+        for index in 0..100 {
+            assert_eq!(index, index_of(file.next_timestamped().await.unwrap()));
+        }
+        assert!(file.next_timestamped().await.unwrap().is_none());
+
+        file.seek_to_time(Duration::from_millis(555)).await.unwrap();
+        assert_eq!(56, index_of(file.next_timestamped().await.unwrap()));
+        file.seek_to_packet(42).await.unwrap();
+        assert_eq!(42, index_of(file.next_timestamped().await.unwrap()));
+        file.rewind().await.unwrap();
+        assert_eq!(0, index_of(file.next_timestamped().await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn rotates_by_size_and_keeps_last_files() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.ldr.gz");
+        let rotation = Rotation {
+            max_file_size: Some(1000),
+            retention: Retention::Files(3),
+            ..Rotation::default()
+        };
+        write_rotating(&path, 100, rotation).await;
+
+        let parts = rotation::parts(&path).await.unwrap();
+        assert_eq!(3, parts.len());
+        assert!(parts.iter().all(|(_, part)| part.to_str().unwrap().ends_with(".ldr.gz")));
+
+        // The oldest packets are removed, the last packet is kept
+        let mut file = read_rotated(&path).await.unwrap();
+        let first = index_of(file.next_timestamped().await.unwrap());
+        assert!(first > 0);
+        let mut last = first;
+        while let Some(timestamped_packet) = file.next_timestamped().await.unwrap() {
+            last = index_of(Some(timestamped_packet));
+        }
+        assert_eq!(99, last);
+
+        // A new recording continues the numbering and the retention
+        write_rotating(&path, 1, rotation).await;
+        let numbers: Vec<_> = rotation::parts(&path).await.unwrap().into_iter().map(|(number, _)| number).collect();
+        let last_number = parts.last().unwrap().0;
+        assert_eq!(vec![last_number - 1, last_number, last_number + 1], numbers);
+    }
 }
@@ -0,0 +1,335 @@
+//! Compact binary format
+//!
+//! The file starts with the magic bytes `D2ALDR` and a format version (u16).
+//! Next are records: a record type (u8), the payload length (u32) and the payload.
+//! The first record contains the JSON encoded metadata.
+//! A packet record contains the timestamp in microseconds (u64) followed by the packet as it is sent by the lidar (a frame).
+//!
+//! `finish` appends an index record with an entry (packet number, timestamp, offset; all u64) for every `INDEX_INTERVAL` packets,
+//! followed by the offset of the index record (u64) and the magic bytes `D2AIDX`.
+//! Without an index (e.g. the recording was interrupted or the file is compressed) the file can still be read, seeking then starts from the beginning.
+//! A truncated last record is ignored.
+//!
+//! All integers are little endian.
+use super::compression::{Input, Output};
+use super::{Metadata, TimestampedPacket};
+use crate::packet_decoder::PacketDecoder;
+use crate::packet_stream::StreamError;
+use anyhow::{bail, Result};
+use log::warn;
+use std::convert::TryInto;
+use std::io::{ErrorKind, SeekFrom};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+pub(super) const MAGIC: &[u8; 6] = b"D2ALDR";
+pub(super) const FORMAT_VERSION: u16 = 1;
+const HEADER_SIZE: u64 = 8;
+const INDEX_MAGIC: &[u8; 6] = b"D2AIDX";
+// Index offset (u64) + index magic
+const TRAILER_SIZE: u64 = 14;
+// Record type (u8) + payload length (u32)
+const RECORD_HEADER_SIZE: u64 = 5;
+const INDEX_ENTRY_SIZE: usize = 24;
+const INDEX_INTERVAL: u64 = 256;
+// Far larger than any packet, anything bigger means the file is corrupt
+const MAX_RECORD_SIZE: usize = 16 * 1024 * 1024;
+
+const RECORD_PACKET: u8 = 1;
+const RECORD_INDEX: u8 = 2;
+const RECORD_METADATA: u8 = 3;
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    packet_number: u64,
+    timestamp: Duration,
+    offset: u64,
+}
+
+pub(super) struct BinaryWriter {
+    buffer: Output,
+    offset: u64,
+    packets: u64,
+    index: Vec<IndexEntry>,
+}
+
+impl BinaryWriter {
+    pub(super) async fn new(mut buffer: Output, metadata: &Metadata) -> Result<Self> {
+        buffer.write_all(MAGIC).await?;
+        buffer.write_all(&FORMAT_VERSION.to_le_bytes()).await?;
+
+        let mut binary_writer = BinaryWriter {
+            buffer,
+            offset: HEADER_SIZE,
+            packets: 0,
+            index: Vec::new(),
+        };
+
+        let payload = serde_json::to_vec(metadata)?;
+        binary_writer.write_record(RECORD_METADATA, &payload).await?;
+
+        Ok(binary_writer)
+    }
+
+    pub(super) async fn write(&mut self, timestamped_packet: &TimestampedPacket) -> Result<()> {
+        if self.packets % INDEX_INTERVAL == 0 {
+            self.index.push(IndexEntry {
+                packet_number: self.packets,
+                timestamp: timestamped_packet.timestamp,
+                offset: self.offset,
+            });
+        }
+
+        let mut payload = (timestamped_packet.timestamp.as_micros() as u64).to_le_bytes().to_vec();
+        payload.extend(timestamped_packet.packet.to_bytes());
+
+        self.write_record(RECORD_PACKET, &payload).await?;
+        self.packets += 1;
+
+        Ok(())
+    }
+
+    /// Amount of bytes written so far, before compression
+    pub(super) fn size(&self) -> u64 {
+        self.offset
+    }
+
+    pub(super) async fn flush(&mut self) -> Result<()> {
+        self.buffer.flush().await?;
+
+        Ok(())
+    }
+
+    pub(super) async fn sync(&mut self) -> Result<()> {
+        self.buffer.sync().await?;
+
+        Ok(())
+    }
+
+    /// Write the index and the trailer which points to it
+    pub(super) async fn finish(&mut self) -> Result<()> {
+        let index_offset = self.offset;
+
+        let mut payload = Vec::with_capacity(self.index.len() * INDEX_ENTRY_SIZE);
+        for entry in &self.index {
+            payload.extend_from_slice(&entry.packet_number.to_le_bytes());
+            payload.extend_from_slice(&(entry.timestamp.as_micros() as u64).to_le_bytes());
+            payload.extend_from_slice(&entry.offset.to_le_bytes());
+        }
+
+        self.write_record(RECORD_INDEX, &payload).await?;
+        self.buffer.write_all(&index_offset.to_le_bytes()).await?;
+        self.buffer.write_all(INDEX_MAGIC).await?;
+        self.buffer.finish().await?;
+
+        Ok(())
+    }
+
+    async fn write_record(&mut self, record_type: u8, payload: &[u8]) -> Result<()> {
+        self.buffer.write_all(&[record_type]).await?;
+        self.buffer.write_all(&(payload.len() as u32).to_le_bytes()).await?;
+        self.buffer.write_all(payload).await?;
+
+        self.offset += RECORD_HEADER_SIZE + payload.len() as u64;
+
+        Ok(())
+    }
+}
+
+pub(super) struct BinaryReader {
+    reader: Input,
+    offset: u64,
+    packet_number: u64,
+    index: Vec<IndexEntry>,
+    data_end: Option<u64>,
+}
+
+impl BinaryReader {
+    pub(super) async fn new(mut reader: Input) -> Result<(Self, Option<Metadata>)> {
+        let mut magic = [0u8; 6];
+        reader.read_exact(&mut magic).await?;
+        if &magic != MAGIC {
+            bail!("Not a binary measurements file");
+        }
+
+        let version = reader.read_u16_le().await?;
+        if version != FORMAT_VERSION {
+            bail!("Unsupported binary measurements version: {}", version);
+        }
+
+        let mut binary_reader = BinaryReader {
+            reader,
+            offset: HEADER_SIZE,
+            packet_number: 0,
+            index: Vec::new(),
+            data_end: None,
+        };
+
+        // Only the first record can contain the metadata
+        let metadata = match binary_reader.read_record().await? {
+            Some((RECORD_METADATA, payload)) => Some(serde_json::from_slice(&payload)?),
+            _ => None,
+        };
+
+        binary_reader.read_index().await?;
+        binary_reader.rewind().await?;
+
+        Ok((binary_reader, metadata))
+    }
+
+    /// Load the index when the file was finished, compressed files are never indexed
+    async fn read_index(&mut self) -> Result<()> {
+        let reader = match self.reader.seekable() {
+            Some(reader) => reader,
+            None => return Ok(()),
+        };
+
+        let length = reader.get_ref().metadata().await?.len();
+        if length < HEADER_SIZE + RECORD_HEADER_SIZE + TRAILER_SIZE {
+            return Ok(());
+        }
+
+        let mut trailer = [0u8; TRAILER_SIZE as usize];
+        reader.seek(SeekFrom::End(-(TRAILER_SIZE as i64))).await?;
+        reader.read_exact(&mut trailer).await?;
+        if &trailer[8..] != INDEX_MAGIC {
+            return Ok(());
+        }
+
+        let index_offset = u64::from_le_bytes(trailer[..8].try_into()?);
+        reader.seek(SeekFrom::Start(index_offset)).await?;
+
+        let record_type = reader.read_u8().await?;
+        let length = reader.read_u32_le().await? as usize;
+        if record_type != RECORD_INDEX || length % INDEX_ENTRY_SIZE != 0 || length > MAX_RECORD_SIZE {
+            bail!("Corrupt index");
+        }
+
+        let mut payload = vec![0u8; length];
+        reader.read_exact(&mut payload).await?;
+
+        self.index = payload
+            .chunks(INDEX_ENTRY_SIZE)
+            .map(|entry| IndexEntry {
+                packet_number: u64::from_le_bytes(entry[..8].try_into().unwrap()),
+                timestamp: Duration::from_micros(u64::from_le_bytes(entry[8..16].try_into().unwrap())),
+                offset: u64::from_le_bytes(entry[16..].try_into().unwrap()),
+            })
+            .collect();
+        self.data_end = Some(index_offset);
+
+        Ok(())
+    }
+
+    pub(super) async fn next(&mut self) -> Result<Option<TimestampedPacket>, StreamError> {
+        loop {
+            if self.data_end.map(|data_end| self.offset >= data_end).unwrap_or(false) {
+                return Ok(None);
+            }
+
+            let (record_type, payload) = match self.read_record().await {
+                Ok(Some(record)) => record,
+                Ok(None) => return Ok(None),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    warn!("Ignoring truncated record after packet {}", self.packet_number);
+                    return Ok(None);
+                }
+                Err(source) => {
+                    return Err(StreamError::Io {
+                        line: self.packet_number as usize + 1,
+                        source,
+                    })
+                }
+            };
+
+            match record_type {
+                RECORD_PACKET => {
+                    self.packet_number += 1;
+                    return parse_packet(&payload).map(Some).map_err(|source| StreamError::Corrupt {
+                        line: self.packet_number as usize,
+                        source,
+                    });
+                }
+                // The index is the last record
+                RECORD_INDEX => return Ok(None),
+                RECORD_METADATA => {}
+                // Newer versions can add records, these are skipped
+                _ => {}
+            }
+        }
+    }
+
+    async fn read_record(&mut self) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+        // The end of the file is only valid between two records
+        let record_type = match self.reader.read_u8().await {
+            Ok(record_type) => record_type,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let length = self.reader.read_u32_le().await? as usize;
+        if length > MAX_RECORD_SIZE {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, format!("Invalid record length: {}", length)));
+        }
+
+        let mut payload = vec![0u8; length];
+        self.reader.read_exact(&mut payload).await?;
+        self.offset += RECORD_HEADER_SIZE + length as u64;
+
+        Ok(Some((record_type, payload)))
+    }
+
+    pub(super) async fn rewind(&mut self) -> Result<(), StreamError> {
+        self.seek_to(HEADER_SIZE, 0).await
+    }
+
+    /// Moves close to (but not past) the given packet, returns the number of the packet which is read next
+    pub(super) async fn seek_to_packet(&mut self, packet_number: u64) -> Result<u64, StreamError> {
+        let entry = self.index.iter().rev().find(|entry| entry.packet_number <= packet_number).copied();
+
+        match entry {
+            Some(entry) => self.seek_to(entry.offset, entry.packet_number).await?,
+            None => self.rewind().await?,
+        }
+
+        Ok(self.packet_number)
+    }
+
+    /// Moves close to (but not past) the first packet at or after the given timestamp
+    pub(super) async fn seek_to_time(&mut self, timestamp: Duration) -> Result<(), StreamError> {
+        let entry = self.index.iter().rev().find(|entry| entry.timestamp <= timestamp).copied();
+
+        match entry {
+            Some(entry) => self.seek_to(entry.offset, entry.packet_number).await,
+            None => self.rewind().await,
+        }
+    }
+
+    async fn seek_to(&mut self, offset: u64, packet_number: u64) -> Result<(), StreamError> {
+        if let Err(source) = self.reader.seek_to(offset).await {
+            return Err(StreamError::Io {
+                line: packet_number as usize + 1,
+                source,
+            });
+        }
+
+        self.offset = offset;
+        self.packet_number = packet_number;
+
+        Ok(())
+    }
+}
+
+fn parse_packet(payload: &[u8]) -> Result<TimestampedPacket, Box<dyn std::error::Error + Send + Sync>> {
+    if payload.len() < 8 {
+        return Err("Packet record is too short".into());
+    }
+
+    let timestamp = Duration::from_micros(u64::from_le_bytes(payload[..8].try_into()?));
+
+    match PacketDecoder::new().decode(&payload[8..]).pop() {
+        Some(Ok(packet)) => Ok(TimestampedPacket { timestamp, packet }),
+        Some(Err(e)) => Err(Box::new(e)),
+        None => Err("Packet record doesn't contain a complete frame".into()),
+    }
+}
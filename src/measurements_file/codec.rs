@@ -0,0 +1,256 @@
+//! Length delimited records which are serialized using a serde codec
+//!
+//! The file starts with the magic bytes `D2ASER`, a format version (u16) and the id of the codec (u8).
+//! Next are records: the payload length (u32) and the payload, a `Metadata` in the first record and a `TimestampedPacket` in every other one.
+//! A truncated last record is ignored.
+//!
+//! All integers are little endian.
+use super::compression::{Input, Output};
+use super::{Metadata, TimestampedPacket};
+use crate::packet_stream::StreamError;
+use anyhow::{bail, Result};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::ErrorKind;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+pub(super) const MAGIC: &[u8; 6] = b"D2ASER";
+pub(super) const FORMAT_VERSION: u16 = 1;
+// Magic + format version + codec
+const HEADER_SIZE: u64 = 9;
+// Far larger than any packet, anything bigger means the file is corrupt
+const MAX_RECORD_SIZE: usize = 16 * 1024 * 1024;
+
+type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Serialization of the records of a `Format::Codec` file, the codec is stored in the file so `read` detects it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// JSON, readable with any tool (default)
+    #[default]
+    Json,
+    /// Concise Binary Object Representation (RFC 8949)
+    Cbor,
+    /// MessagePack, with field names so other tools can decode the records
+    MessagePack,
+    /// Bincode (version 1), the smallest and fastest but only readable with serde
+    Bincode,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::Json => 1,
+            Codec::Cbor => 2,
+            Codec::MessagePack => 3,
+            Codec::Bincode => 4,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Codec> {
+        [Codec::Json, Codec::Cbor, Codec::MessagePack, Codec::Bincode]
+            .iter()
+            .copied()
+            .find(|codec| codec.id() == id)
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+            Codec::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(value, &mut bytes)?;
+                Ok(bytes)
+            }
+            Codec::MessagePack => Ok(rmp_serde::to_vec_named(value)?),
+            Codec::Bincode => Ok(bincode::serialize(value)?),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Codec::Json => Ok(serde_json::from_slice(bytes)?),
+            Codec::Cbor => Ok(ciborium::de::from_reader(bytes)?),
+            Codec::MessagePack => Ok(rmp_serde::from_slice(bytes)?),
+            Codec::Bincode => Ok(bincode::deserialize(bytes)?),
+        }
+    }
+}
+
+pub(super) struct CodecWriter {
+    buffer: Output,
+    codec: Codec,
+    size: u64,
+}
+
+impl CodecWriter {
+    pub(super) async fn new(mut buffer: Output, codec: Codec, metadata: &Metadata) -> Result<Self> {
+        buffer.write_all(MAGIC).await?;
+        buffer.write_all(&FORMAT_VERSION.to_le_bytes()).await?;
+        buffer.write_all(&[codec.id()]).await?;
+
+        let mut codec_writer = CodecWriter {
+            buffer,
+            codec,
+            size: HEADER_SIZE,
+        };
+
+        let payload = codec.encode(metadata).map_err(anyhow::Error::msg)?;
+        codec_writer.write_record(&payload).await?;
+
+        Ok(codec_writer)
+    }
+
+    pub(super) async fn write(&mut self, timestamped_packet: &TimestampedPacket) -> Result<()> {
+        let payload = self.codec.encode(timestamped_packet).map_err(anyhow::Error::msg)?;
+
+        self.write_record(&payload).await
+    }
+
+    async fn write_record(&mut self, payload: &[u8]) -> Result<()> {
+        self.buffer.write_all(&(payload.len() as u32).to_le_bytes()).await?;
+        self.buffer.write_all(payload).await?;
+        self.size += 4 + payload.len() as u64;
+
+        Ok(())
+    }
+
+    /// Amount of bytes written so far, before compression
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(super) async fn flush(&mut self) -> Result<()> {
+        self.buffer.flush().await?;
+
+        Ok(())
+    }
+
+    pub(super) async fn sync(&mut self) -> Result<()> {
+        self.buffer.sync().await?;
+
+        Ok(())
+    }
+
+    pub(super) async fn finish(&mut self) -> Result<()> {
+        self.buffer.finish().await?;
+
+        Ok(())
+    }
+}
+
+pub(super) struct CodecReader {
+    reader: Input,
+    codec: Codec,
+    packet_number: u64,
+}
+
+impl CodecReader {
+    pub(super) async fn new(mut reader: Input) -> Result<(Self, Option<Metadata>)> {
+        let mut magic = [0u8; 6];
+        reader.read_exact(&mut magic).await?;
+        if &magic != MAGIC {
+            bail!("Not a codec measurements file");
+        }
+
+        let version = reader.read_u16_le().await?;
+        if version != FORMAT_VERSION {
+            bail!("Unsupported codec measurements version: {}", version);
+        }
+
+        let id = reader.read_u8().await?;
+        let codec = match Codec::from_id(id) {
+            Some(codec) => codec,
+            None => bail!("Unsupported codec: {}", id),
+        };
+
+        let mut codec_reader = CodecReader {
+            reader,
+            codec,
+            packet_number: 0,
+        };
+
+        let metadata = match codec_reader.read_record().await? {
+            Some(payload) => Some(codec.decode(&payload).map_err(anyhow::Error::msg)?),
+            None => None,
+        };
+
+        Ok((codec_reader, metadata))
+    }
+
+    pub(super) fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub(super) async fn next(&mut self) -> Result<Option<TimestampedPacket>, StreamError> {
+        let payload = match self.read_record().await {
+            Ok(Some(payload)) => payload,
+            Ok(None) => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                warn!("Ignoring truncated record after packet {}", self.packet_number);
+                return Ok(None);
+            }
+            Err(source) => {
+                return Err(StreamError::Io {
+                    line: self.packet_number as usize + 1,
+                    source,
+                })
+            }
+        };
+
+        self.packet_number += 1;
+        self.codec.decode(&payload).map(Some).map_err(|source| StreamError::Corrupt {
+            line: self.packet_number as usize,
+            source,
+        })
+    }
+
+    async fn read_record(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        // The end of the file is only valid between two records
+        let mut length = [0u8; 4];
+        match self.reader.read(&mut length[..1]).await? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut length[1..]).await?,
+        };
+
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_RECORD_SIZE {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, format!("Invalid record length: {}", length)));
+        }
+
+        let mut payload = vec![0u8; length];
+        self.reader.read_exact(&mut payload).await?;
+
+        Ok(Some(payload))
+    }
+
+    /// Start at the first packet again, the metadata record is skipped
+    pub(super) async fn rewind(&mut self) -> Result<(), StreamError> {
+        let result = async {
+            self.reader.seek_to(HEADER_SIZE).await?;
+            self.read_record().await
+        };
+
+        if let Err(source) = result.await {
+            return Err(StreamError::Io { line: 0, source });
+        }
+
+        self.packet_number = 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_ids_round_trip() {
+        for codec in [Codec::Json, Codec::Cbor, Codec::MessagePack, Codec::Bincode] {
+            assert_eq!(Some(codec), Codec::from_id(codec.id()));
+        }
+        assert_eq!(None, Codec::from_id(0));
+    }
+}
@@ -0,0 +1,162 @@
+//! Gzip compression of measurement files
+//!
+//! Compressed data is streamed through the encoder and decoder, so the memory usage doesn't grow with the size of the file.
+//! A compressed file can't be seeked, it is read again from the beginning instead.
+use super::Compression;
+use async_compression::tokio::bufread::GzipDecoder;
+use async_compression::tokio::write::GzipEncoder;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::fs::File;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, ReadBuf};
+
+const GZIP_MAGIC: &[u8; 2] = &[0x1F, 0x8B];
+
+impl Compression {
+    /// Resolves `Auto` using the extension of the file
+    pub(super) fn for_file(self, file_name: &Path) -> Compression {
+        match self {
+            Compression::Auto if file_name.extension().map(|extension| extension == "gz").unwrap_or(false) => Compression::Gzip,
+            Compression::Auto => Compression::None,
+            compression => compression,
+        }
+    }
+}
+
+/// The (decompressed) contents of a measurements file
+pub(super) enum Input {
+    Plain(BufReader<File>),
+    Gzip {
+        path: PathBuf,
+        reader: BufReader<GzipDecoder<BufReader<File>>>,
+    },
+}
+
+impl Input {
+    /// Opens a file, compressed files are detected by their magic bytes
+    pub(super) async fn open(file_name: &Path) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(file_name).await?);
+
+        if file.fill_buf().await?.starts_with(GZIP_MAGIC) {
+            Ok(Input::Gzip {
+                path: file_name.to_path_buf(),
+                reader: BufReader::new(GzipDecoder::new(file)),
+            })
+        } else {
+            Ok(Input::Plain(file))
+        }
+    }
+
+    /// The underlying file, `None` when it doesn't map to the decompressed contents
+    pub(super) fn seekable(&mut self) -> Option<&mut BufReader<File>> {
+        match self {
+            Input::Plain(reader) => Some(reader),
+            Input::Gzip { .. } => None,
+        }
+    }
+
+    /// Continue reading at the given offset in the decompressed contents
+    pub(super) async fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        match self {
+            // Seeking the buffered reader also discards its buffer
+            Input::Plain(reader) => reader.seek(SeekFrom::Start(offset)).await.map(|_| ()),
+            Input::Gzip { path, reader } => {
+                let file = BufReader::new(File::open(path.as_path()).await?);
+                *reader = BufReader::new(GzipDecoder::new(file));
+
+                let skipped = tokio::io::copy(&mut (&mut *reader).take(offset), &mut tokio::io::sink()).await?;
+                if skipped < offset {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl AsyncRead for Input {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Input::Plain(reader) => Pin::new(reader).poll_read(cx, buf),
+            Input::Gzip { reader, .. } => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncBufRead for Input {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        match self.get_mut() {
+            Input::Plain(reader) => Pin::new(reader).poll_fill_buf(cx),
+            Input::Gzip { reader, .. } => Pin::new(reader).poll_fill_buf(cx),
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amount: usize) {
+        match self.get_mut() {
+            Input::Plain(reader) => Pin::new(reader).consume(amount),
+            Input::Gzip { reader, .. } => Pin::new(reader).consume(amount),
+        }
+    }
+}
+
+/// Writes (and compresses) the contents of a measurements file
+pub(super) enum Output {
+    Plain(BufWriter<File>),
+    Gzip(GzipEncoder<BufWriter<File>>),
+}
+
+impl Output {
+    /// `compression` must already be resolved using `Compression::for_file`
+    pub(super) fn new(file: File, compression: Compression) -> Self {
+        match compression {
+            Compression::Gzip => Output::Gzip(GzipEncoder::new(BufWriter::new(file))),
+            _ => Output::Plain(BufWriter::new(file)),
+        }
+    }
+
+    fn file(&self) -> &File {
+        match self {
+            Output::Plain(writer) => writer.get_ref(),
+            Output::Gzip(writer) => writer.get_ref().get_ref(),
+        }
+    }
+
+    /// Write everything which is buffered and wait until it is stored on the disk.
+    /// Compressed data which is flushed can be decompressed, even when the file is never finished.
+    pub(super) async fn sync(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        self.file().sync_data().await
+    }
+
+    /// Like `sync`, also completes the compressed stream. Nothing can be written afterwards.
+    pub(super) async fn finish(&mut self) -> io::Result<()> {
+        self.shutdown().await?;
+        self.file().sync_data().await
+    }
+}
+
+impl AsyncWrite for Output {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Output::Plain(writer) => Pin::new(writer).poll_write(cx, buf),
+            Output::Gzip(writer) => Pin::new(writer).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Output::Plain(writer) => Pin::new(writer).poll_flush(cx),
+            Output::Gzip(writer) => Pin::new(writer).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Output::Plain(writer) => Pin::new(writer).poll_shutdown(cx),
+            Output::Gzip(writer) => Pin::new(writer).poll_shutdown(cx),
+        }
+    }
+}
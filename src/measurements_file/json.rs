@@ -0,0 +1,172 @@
+//! JSON lines format, every line contains a `TimestampedPacket` (or a bare `Packet` in legacy recordings)
+//!
+//! The first line contains the metadata: `{"metadata": {...}}`.
+use super::compression::{Input, Output};
+use super::{Metadata, TimestampedPacket, DEFAULT_LEGACY_INTERVAL};
+use crate::packet::Packet;
+use crate::packet_stream::StreamError;
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+// Version 1 files contain bare packets, without timestamps and metadata
+pub(super) const FORMAT_VERSION: u16 = 2;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Timestamped(TimestampedPacket),
+    Header { metadata: Metadata },
+    Legacy(Packet),
+}
+
+#[derive(Serialize)]
+struct HeaderRef<'a> {
+    metadata: &'a Metadata,
+}
+
+pub(super) struct JsonReader {
+    reader: Input,
+    line: usize,
+    legacy_interval: Duration,
+    last_timestamp: Option<Duration>,
+}
+
+impl JsonReader {
+    pub(super) async fn new(reader: Input) -> Result<(Self, Option<Metadata>)> {
+        let mut json_reader = JsonReader {
+            reader,
+            line: 0,
+            legacy_interval: DEFAULT_LEGACY_INTERVAL,
+            last_timestamp: None,
+        };
+
+        // Only the first line can be a header, reading the entries starts from the beginning (and skips it)
+        let mut first_line = String::new();
+        json_reader.reader.read_line(&mut first_line).await?;
+        let metadata = match serde_json::from_str(&first_line) {
+            Ok(Entry::Header { metadata }) => Some(metadata),
+            _ => None,
+        };
+        json_reader.rewind().await?;
+
+        Ok((json_reader, metadata))
+    }
+
+    pub(super) fn set_legacy_interval(&mut self, legacy_interval: Duration) {
+        self.legacy_interval = legacy_interval;
+    }
+
+    pub(super) async fn next(&mut self) -> Result<Option<TimestampedPacket>, StreamError> {
+        loop {
+            self.line += 1;
+
+            let mut line = String::new();
+            match self.reader.read_line(&mut line).await {
+                Ok(0) => return Ok(None),
+                Ok(_) => {}
+                // A compressed recording which was interrupted ends in the middle of the compressed stream
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    warn!("Ignoring truncated data after line {}", self.line - 1);
+                    return Ok(None);
+                }
+                Err(source) => return Err(StreamError::Io { line: self.line, source }),
+            }
+
+            // Blank lines (e.g. a trailing new line) are not entries
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let timestamped_packet = match serde_json::from_str(&line) {
+                Ok(Entry::Timestamped(timestamped_packet)) => timestamped_packet,
+                Ok(Entry::Header { .. }) => continue,
+                Ok(Entry::Legacy(packet)) => TimestampedPacket {
+                    timestamp: self.last_timestamp.map(|last| last + self.legacy_interval).unwrap_or_default(),
+                    packet,
+                },
+                // Only the last line can lack a new line, the recording was interrupted while writing it
+                Err(e) if !line.ends_with('\n') => {
+                    warn!("Ignoring truncated entry on line {}: {}", self.line, e);
+                    return Ok(None);
+                }
+                Err(e) => {
+                    return Err(StreamError::Corrupt {
+                        line: self.line,
+                        source: Box::new(e),
+                    })
+                }
+            };
+
+            self.last_timestamp = Some(timestamped_packet.timestamp);
+            return Ok(Some(timestamped_packet));
+        }
+    }
+
+    pub(super) async fn rewind(&mut self) -> Result<(), StreamError> {
+        if let Err(source) = self.reader.seek_to(0).await {
+            return Err(StreamError::Io { line: 0, source });
+        }
+
+        self.line = 0;
+        self.last_timestamp = None;
+
+        Ok(())
+    }
+}
+
+pub(super) struct JsonWriter {
+    buffer: Output,
+    size: u64,
+}
+
+impl JsonWriter {
+    pub(super) async fn new(buffer: Output, metadata: &Metadata) -> Result<Self> {
+        let mut json_writer = JsonWriter { buffer, size: 0 };
+
+        let bytes = serde_json::to_string(&HeaderRef { metadata })?;
+        json_writer.write_line(&bytes).await?;
+
+        Ok(json_writer)
+    }
+
+    pub(super) async fn write(&mut self, timestamped_packet: &TimestampedPacket) -> Result<()> {
+        let bytes = serde_json::to_string(timestamped_packet)?;
+
+        self.write_line(&bytes).await
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<()> {
+        self.buffer.write_all(line.as_bytes()).await?;
+        self.buffer.write_all(b"\n").await?;
+        self.size += line.len() as u64 + 1;
+
+        Ok(())
+    }
+
+    /// Amount of bytes written so far, before compression
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(super) async fn flush(&mut self) -> Result<()> {
+        self.buffer.flush().await?;
+
+        Ok(())
+    }
+
+    pub(super) async fn sync(&mut self) -> Result<()> {
+        self.buffer.sync().await?;
+
+        Ok(())
+    }
+
+    pub(super) async fn finish(&mut self) -> Result<()> {
+        self.buffer.finish().await?;
+
+        Ok(())
+    }
+}
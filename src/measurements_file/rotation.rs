@@ -0,0 +1,190 @@
+//! Rotation of recordings over numbered files
+//!
+//! The parts of `measurements.ldr.gz` are named `measurements.000000.ldr.gz`, `measurements.000001.ldr.gz`, ...
+//! All parts of a recording share the same `Metadata`, so their timestamps are relative to the start of the whole recording.
+//! A new recording with the same name continues the numbering, the existing parts count towards the retention.
+use super::{Metadata, WriteOptions, Writer};
+use anyhow::Result;
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// When a recording continues in a new file and which files are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rotation {
+    /// Start a new file once the current file contains this many bytes (before compression)
+    pub max_file_size: Option<u64>,
+    /// Start a new file once the current file spans this much time
+    pub max_file_duration: Option<Duration>,
+    pub retention: Retention,
+}
+
+/// Which files of a rotated recording are kept, older files are removed when a new file is started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Retention {
+    /// Keep all files (default)
+    #[default]
+    All,
+    /// Keep the given amount of files, including the file which is being written
+    Files(usize),
+    /// Keep the files which were finished less than the given time ago
+    Age(Duration),
+}
+
+/// Path of part `number` of the rotated recording `base`
+fn part_path(base: &Path, number: u64) -> PathBuf {
+    let (stem, suffix) = split_file_name(base);
+
+    base.with_file_name(format!("{}.{:06}{}", stem, number, suffix))
+}
+
+/// Splits the file name at the first dot, `measurements.ldr.gz` becomes `measurements` and `.ldr.gz`
+fn split_file_name(base: &Path) -> (&str, &str) {
+    let file_name = base.file_name().and_then(|file_name| file_name.to_str()).unwrap_or_default();
+
+    match file_name.find('.') {
+        Some(dot) if dot > 0 => file_name.split_at(dot),
+        _ => (file_name, ""),
+    }
+}
+
+/// The existing parts of the rotated recording `base` and their numbers, in order
+pub(super) async fn parts(base: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let (stem, suffix) = split_file_name(base);
+    let directory = match base.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let mut parts = Vec::new();
+    let mut entries = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let number = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(stem)?.strip_prefix('.')?.strip_suffix(suffix))
+            .filter(|number| number.len() >= 6 && number.bytes().all(|byte| byte.is_ascii_digit()))
+            .and_then(|number| number.parse().ok());
+
+        if let Some(number) = number {
+            parts.push((number, base.with_file_name(file_name)));
+        }
+    }
+    parts.sort();
+
+    Ok(parts)
+}
+
+/// Switches a `MeasurementWriteFile` to the next part
+pub(super) struct Rotator {
+    base: PathBuf,
+    rotation: Rotation,
+    options: WriteOptions,
+    metadata: Metadata,
+    number: u64,
+    part_started: Option<Duration>,
+    finished: VecDeque<(PathBuf, SystemTime)>,
+}
+
+impl Rotator {
+    /// Creates the first part of a new recording
+    pub(super) async fn new(base: &Path, rotation: Rotation, options: WriteOptions, metadata: Metadata) -> Result<(Self, Writer)> {
+        let mut finished = VecDeque::new();
+        let mut number = 0;
+
+        for (existing, path) in parts(base).await? {
+            let modified = tokio::fs::metadata(&path).await?.modified()?;
+            finished.push_back((path, modified));
+            number = existing + 1;
+        }
+
+        let mut rotator = Rotator {
+            base: base.to_path_buf(),
+            rotation,
+            options,
+            metadata,
+            number,
+            part_started: None,
+            finished,
+        };
+        let writer = rotator.create().await?;
+
+        Ok((rotator, writer))
+    }
+
+    /// Returns true when the packet with the given timestamp belongs in a new part
+    pub(super) fn is_due(&mut self, timestamp: Duration, size: u64) -> bool {
+        // Every part contains at least one packet
+        let part_started = match self.part_started {
+            Some(part_started) => part_started,
+            None => {
+                self.part_started = Some(timestamp);
+                return false;
+            }
+        };
+
+        let too_large = self.rotation.max_file_size.map(|max_file_size| size >= max_file_size).unwrap_or(false);
+        let too_long = self
+            .rotation
+            .max_file_duration
+            .map(|max_file_duration| timestamp.saturating_sub(part_started) >= max_file_duration)
+            .unwrap_or(false);
+
+        too_large || too_long
+    }
+
+    /// Creates the next part, starting with the packet with the given timestamp. The current part must be finished.
+    pub(super) async fn rotate(&mut self, timestamp: Duration) -> Result<Writer> {
+        self.finished.push_back((part_path(&self.base, self.number), SystemTime::now()));
+        self.number += 1;
+        self.part_started = Some(timestamp);
+
+        self.create().await
+    }
+
+    async fn create(&mut self) -> Result<Writer> {
+        self.remove_expired().await;
+
+        let path = part_path(&self.base, self.number);
+        info!("Recording to {}", path.display());
+
+        Writer::create(&path, &self.options, &self.metadata).await
+    }
+
+    async fn remove_expired(&mut self) {
+        while let Some((path, finished)) = self.finished.front() {
+            let expired = match self.rotation.retention {
+                Retention::All => false,
+                // The part which is about to be created counts as well
+                Retention::Files(files) => self.finished.len() >= files.max(1),
+                Retention::Age(age) => finished.elapsed().map(|elapsed| elapsed >= age).unwrap_or(false),
+            };
+            if !expired {
+                break;
+            }
+
+            match tokio::fs::remove_file(path).await {
+                Ok(()) => info!("Removed {}", path.display()),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => warn!("Failed to remove {}: {}", path.display(), e),
+            }
+            self.finished.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn part_names() {
+        assert_eq!(
+            PathBuf::from("logs/measurements.000012.ldr.gz"),
+            part_path(Path::new("logs/measurements.ldr.gz"), 12)
+        );
+        assert_eq!(PathBuf::from("measurements.000000"), part_path(Path::new("measurements"), 0));
+    }
+}
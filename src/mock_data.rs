@@ -1,3 +1,7 @@
+use std::io::{ErrorKind, Read};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
 // Examples taken from the documentation
 // Example 1: Measurement data frame
 pub const FIRST_EXAMPLE: [u8; 156] = [
@@ -11,3 +15,33 @@ pub const FIRST_EXAMPLE: [u8; 156] = [
 
 // Example 2: Radar speed failure frame
 pub const SECOND_EXAMPLE: [u8; 11] = [0xAA, 0x00, 0x09, 0x00, 0x61, 0xAE, 0x00, 0x01, 0x69, 0x02, 0x2C];
+
+/// A serial port which returns the bytes sent through its channel and times out otherwise
+pub struct FakeSerialPort {
+    receiver: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl FakeSerialPort {
+    pub fn new() -> (Sender<Vec<u8>>, FakeSerialPort) {
+        let (sender, receiver) = channel();
+
+        (sender, FakeSerialPort { receiver, pending: Vec::new() })
+    }
+}
+
+impl Read for FakeSerialPort {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.receiver.recv_timeout(Duration::from_millis(10)) {
+                Ok(bytes) => self.pending = bytes,
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => return Err(ErrorKind::TimedOut.into()),
+            }
+        }
+
+        let read = buffer.len().min(self.pending.len());
+        buffer[..read].copy_from_slice(&self.pending[..read]);
+        self.pending.drain(..read);
+        Ok(read)
+    }
+}
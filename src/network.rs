@@ -0,0 +1,568 @@
+//! This module bridges a lidar over TCP, so it can be used from another machine.
+//! It is hidden behind the `network` feature flag.
+//!
+//! The `LidarServer` serves any `PacketStream` (e.g. a `Lidar`) to multiple clients,
+//! `RemoteLidar` connects to a server and implements `PacketStream` itself.
+//!
+//! Every connection starts with a handshake of two JSON lines:
+//! the client sends the protocol version and the stream mode, the server answers with the device info.
+//! Afterwards the server sends packets as JSON lines (`StreamMode::Packets`), as Delta-2A frames (`StreamMode::Frames`)
+//! or the bytes exactly as they were read from the serial port (`StreamMode::Raw`).
+//!
+//! The frames are encoded again from the decoded packets, so they are not the bytes the lidar sent:
+//! values are rounded to the resolution of the protocol and corrupt frames and bytes outside of frames are lost.
+//! The raw bytes are only available when a `Lidar` is served with `LidarServer::serve_lidar`.
+use crate::lidar::{DeviceInfo, Lidar, RawChunk};
+use crate::packet::Packet;
+use crate::packet_decoder::PacketDecoder;
+use crate::packet_stream::PacketStream;
+use crate::subscription::PacketSubscription;
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+const PROTOCOL_VERSION: u32 = 1;
+const SUBSCRIPTION_CAPACITY: usize = 1024;
+// Far larger than any handshake message or packet, a longer line means the peer is misbehaving
+const MAX_MESSAGE_SIZE: u64 = 64 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The way packets are sent over the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamMode {
+    /// Every packet is sent as a JSON line
+    Packets,
+    /// Every packet is encoded again as a Delta-2A frame, a compact but lossy copy of the serial data (see the module documentation)
+    Frames,
+    /// The bytes read from the serial port, including corrupt frames, only available when serving a `Lidar`
+    Raw,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Hello {
+    protocol_version: u32,
+    mode: StreamMode,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum HandshakeResponse {
+    Welcome { device_info: DeviceInfo },
+    Rejected { reason: String },
+}
+
+/// Serves a packet stream to all clients which connect to it
+pub struct LidarServer {
+    listener: TcpListener,
+    device_info: DeviceInfo,
+}
+
+impl LidarServer {
+    /// Listen on the given address, `device_info` is sent to every client during the handshake
+    pub async fn bind(addr: impl ToSocketAddrs, device_info: DeviceInfo) -> Result<LidarServer, NetworkError> {
+        let listener = TcpListener::bind(addr).await?;
+
+        Ok(LidarServer { listener, device_info })
+    }
+
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr, NetworkError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serve the packets of `stream` to all clients until the stream ends
+    ///
+    /// Clients only receive the packets which arrive after they connected. Clients which ask for `StreamMode::Raw` are rejected.
+    pub async fn serve<S: PacketStream + Send + 'static>(self, stream: S) -> Result<(), NetworkError> {
+        self.serve_sources(stream, None).await
+    }
+
+    /// Serve a lidar to all clients until it is closed, clients can choose any `StreamMode`
+    pub async fn serve_lidar(self, lidar: Lidar) -> Result<(), NetworkError> {
+        let raw_chunks = lidar.raw_chunk_sender();
+
+        self.serve_sources(lidar, Some(raw_chunks)).await
+    }
+
+    async fn serve_sources<S: PacketStream + Send + 'static>(self, mut stream: S, raw_chunks: Option<broadcast::Sender<RawChunk>>) -> Result<(), NetworkError> {
+        let (packets, _) = broadcast::channel(SUBSCRIPTION_CAPACITY);
+        let device_info = Arc::new(self.device_info);
+
+        let pump_packets = packets.clone();
+        let mut pump = tokio::spawn(async move {
+            while let Some(packet) = stream.next().await {
+                let _ = pump_packets.send(packet);
+            }
+        });
+
+        loop {
+            tokio::select! {
+                _ = &mut pump => break,
+                accepted = self.listener.accept() => match accepted {
+                    Ok((socket, peer)) => {
+                        info!("Client {} connected", peer);
+
+                        // Subscribe before the handshake, so the client doesn't miss packets sent right after it
+                        let subscription = PacketSubscription::new(packets.subscribe());
+                        let raw_subscription = raw_chunks.as_ref().map(broadcast::Sender::subscribe);
+                        let device_info = device_info.clone();
+
+                        tokio::spawn(async move {
+                            match serve_client(socket, subscription, raw_subscription, &device_info).await {
+                                Ok(()) => info!("Client {} finished", peer),
+                                Err(e) => info!("Client {} disconnected: {}", peer, e),
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept client: {}", e),
+                },
+            }
+        }
+
+        info!("Packet stream ended, stopped serving");
+
+        Ok(())
+    }
+}
+
+async fn serve_client(
+    socket: TcpStream,
+    mut subscription: PacketSubscription,
+    raw_subscription: Option<broadcast::Receiver<RawChunk>>,
+    device_info: &DeviceInfo,
+) -> Result<(), NetworkError> {
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let hello: Hello = match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_message(&mut reader)).await {
+        Ok(hello) => hello?,
+        Err(_) => return Err(NetworkError::HandshakeTimeout),
+    };
+    if hello.protocol_version != PROTOCOL_VERSION {
+        let reason = format!("Unsupported protocol version {}, expected {}", hello.protocol_version, PROTOCOL_VERSION);
+        write_message(&mut writer, &HandshakeResponse::Rejected { reason }).await?;
+
+        return Err(NetworkError::UnsupportedProtocolVersion(hello.protocol_version));
+    }
+
+    let raw_subscription = match (hello.mode, raw_subscription) {
+        (StreamMode::Raw, None) => {
+            let reason = "Raw bytes are only available when the server serves a lidar".to_string();
+            write_message(&mut writer, &HandshakeResponse::Rejected { reason: reason.clone() }).await?;
+
+            return Err(NetworkError::Rejected(reason));
+        }
+        (StreamMode::Raw, raw_subscription) => raw_subscription,
+        // Don't make the lidar copy chunks nobody reads
+        _ => None,
+    };
+
+    let welcome = HandshakeResponse::Welcome {
+        device_info: device_info.clone(),
+    };
+    write_message(&mut writer, &welcome).await?;
+
+    if let Some(mut raw_subscription) = raw_subscription {
+        loop {
+            match raw_subscription.recv().await {
+                Ok(chunk) => writer.write_all(&chunk.bytes).await?,
+                // Like a serial line nobody listens to, the bytes are lost
+                Err(RecvError::Lagged(skipped)) => warn!("Client lagged behind, skipped {} raw chunks", skipped),
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+
+    while let Some(packet) = subscription.next().await {
+        match hello.mode {
+            StreamMode::Packets => write_message(&mut writer, &packet).await?,
+            StreamMode::Frames | StreamMode::Raw => writer.write_all(&packet.to_bytes()).await?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Policy used by `RemoteLidar` to reconnect after losing the connection
+///
+/// The delay between two attempts doubles after every failed attempt, up to `max_delay`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Maximum amount of consecutive attempts, `None` keeps trying forever
+    pub max_attempts: Option<u32>,
+}
+
+impl ReconnectPolicy {
+    /// Never reconnect, the stream ends when the connection is lost
+    pub fn never() -> Self {
+        ReconnectPolicy {
+            max_attempts: Some(0),
+            ..ReconnectPolicy::default()
+        }
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            max_attempts: None,
+        }
+    }
+}
+
+/// A lidar which is served by a `LidarServer` on another machine
+pub struct RemoteLidar {
+    addr: String,
+    mode: StreamMode,
+    reconnect_policy: ReconnectPolicy,
+    device_info: DeviceInfo,
+    connection: Option<Connection>,
+}
+
+impl RemoteLidar {
+    /// Connect to a lidar server, fails when the first connection can't be established
+    pub async fn connect(addr: impl ToString, mode: StreamMode) -> Result<RemoteLidar, NetworkError> {
+        let addr = addr.to_string();
+        let (connection, device_info) = Connection::open(&addr, mode).await?;
+
+        Ok(RemoteLidar {
+            addr,
+            mode,
+            reconnect_policy: ReconnectPolicy::default(),
+            device_info,
+            connection: Some(connection),
+        })
+    }
+
+    /// Set the policy used to reconnect after losing the connection
+    pub fn with_reconnect_policy(mut self, reconnect_policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = reconnect_policy;
+        self
+    }
+
+    /// Information about the lidar the server is serving (received during the last handshake)
+    pub fn device_info(&self) -> &DeviceInfo {
+        &self.device_info
+    }
+
+    async fn reconnect(&mut self) -> bool {
+        let mut delay = self.reconnect_policy.initial_delay;
+        let mut attempt = 0;
+
+        while self.reconnect_policy.max_attempts.map(|max| attempt < max).unwrap_or(true) {
+            attempt += 1;
+            tokio::time::sleep(delay).await;
+
+            match Connection::open(&self.addr, self.mode).await {
+                Ok((connection, device_info)) => {
+                    info!("Reconnected to {}", self.addr);
+                    self.connection = Some(connection);
+                    self.device_info = device_info;
+                    return true;
+                }
+                Err(e) => warn!("Failed to reconnect to {} (attempt {}): {}", self.addr, attempt, e),
+            }
+
+            delay = (delay * 2).min(self.reconnect_policy.max_delay);
+        }
+
+        false
+    }
+}
+
+/// Reconnects according to the `ReconnectPolicy`, the stream ends when the server can't be reached anymore
+#[async_trait]
+impl PacketStream for RemoteLidar {
+    async fn next(&mut self) -> Option<Packet> {
+        loop {
+            if self.connection.is_none() && !self.reconnect().await {
+                return None;
+            }
+
+            let connection = self.connection.as_mut()?;
+            match connection.next_packet(self.mode).await {
+                Ok(packet) => return Some(packet),
+                Err(e) => {
+                    warn!("Lost connection to {}: {}", self.addr, e);
+                    self.connection = None;
+                }
+            }
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    decoder: PacketDecoder,
+    pending: VecDeque<Packet>,
+}
+
+impl Connection {
+    async fn open(addr: &str, mode: StreamMode) -> Result<(Connection, DeviceInfo), NetworkError> {
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, Connection::handshake(addr, mode)).await {
+            Ok(result) => result,
+            Err(_) => Err(NetworkError::HandshakeTimeout),
+        }
+    }
+
+    async fn handshake(addr: &str, mode: StreamMode) -> Result<(Connection, DeviceInfo), NetworkError> {
+        let mut reader = BufReader::new(TcpStream::connect(addr).await?);
+
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            mode,
+        };
+        write_message(reader.get_mut(), &hello).await?;
+
+        let device_info = match read_message(&mut reader).await? {
+            HandshakeResponse::Welcome { device_info } => device_info,
+            HandshakeResponse::Rejected { reason } => return Err(NetworkError::Rejected(reason)),
+        };
+
+        let connection = Connection {
+            reader,
+            decoder: PacketDecoder::new(),
+            pending: VecDeque::new(),
+        };
+
+        Ok((connection, device_info))
+    }
+
+    async fn next_packet(&mut self, mode: StreamMode) -> Result<Packet, NetworkError> {
+        match mode {
+            StreamMode::Packets => read_message(&mut self.reader).await,
+            StreamMode::Frames | StreamMode::Raw => loop {
+                if let Some(packet) = self.pending.pop_front() {
+                    return Ok(packet);
+                }
+
+                let mut buffer = [0u8; 1024];
+                let read = self.reader.read(&mut buffer).await?;
+                if read == 0 {
+                    return Err(NetworkError::ConnectionClosed);
+                }
+
+                for result in self.decoder.decode(&buffer[..read]) {
+                    match result {
+                        Ok(packet) => self.pending.push_back(packet),
+                        // Raw bytes start in the middle of a frame
+                        Err(e) if e.is_out_of_sync() => debug!("{}", e),
+                        Err(e) => warn!("{}", e),
+                    }
+                }
+            },
+        }
+    }
+}
+
+async fn read_message<T: for<'de> Deserialize<'de>>(reader: &mut (impl AsyncBufReadExt + AsyncRead + Unpin)) -> Result<T, NetworkError> {
+    let mut line = String::new();
+    let read = reader.take(MAX_MESSAGE_SIZE).read_line(&mut line).await?;
+    if read == 0 {
+        return Err(NetworkError::ConnectionClosed);
+    }
+    if read as u64 == MAX_MESSAGE_SIZE && !line.ends_with('\n') {
+        return Err(NetworkError::MessageTooLong);
+    }
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+async fn write_message<T: Serialize>(writer: &mut (impl AsyncWrite + Unpin), message: &T) -> Result<(), NetworkError> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum NetworkError {
+    #[error("IO error: {0:}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid message: {0:}")]
+    InvalidMessage(#[from] serde_json::Error),
+    #[error("Unsupported protocol version: {0:}")]
+    UnsupportedProtocolVersion(u32),
+    #[error("The server rejected the connection: {0:}")]
+    Rejected(String),
+    #[error("The connection was closed")]
+    ConnectionClosed,
+    #[error("The message is longer than {} bytes", MAX_MESSAGE_SIZE)]
+    MessageTooLong,
+    #[error("The handshake did not finish within {:?}", HANDSHAKE_TIMEOUT)]
+    HandshakeTimeout,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_data::*;
+    use crate::packet::{DistancePacket, LidarSpeedPacket};
+    use tokio::sync::mpsc;
+
+    struct ChannelStream(mpsc::UnboundedReceiver<Packet>);
+
+    #[async_trait]
+    impl PacketStream for ChannelStream {
+        async fn next(&mut self) -> Option<Packet> {
+            self.0.recv().await
+        }
+    }
+
+    struct RepeatStream(Packet);
+
+    #[async_trait]
+    impl PacketStream for RepeatStream {
+        async fn next(&mut self) -> Option<Packet> {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Some(self.0.clone())
+        }
+    }
+
+    fn device_info() -> DeviceInfo {
+        DeviceInfo {
+            port_name: "/dev/ttyUSB0".to_string(),
+            usb_serial_number: Some("0001".to_string()),
+            baud_rate: 230_400,
+        }
+    }
+
+    fn distance_packet() -> Packet {
+        Packet::Distance(DistancePacket::new(6.5, 22.5, 1.35, vec![0.0, 1000.0, 2126.5]).with_signal_strengths(vec![0, 0x46, 0x54]))
+    }
+
+    async fn stream_packets(mode: StreamMode) {
+        let server = LidarServer::bind("127.0.0.1:0", device_info()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let server = tokio::spawn(server.serve(ChannelStream(receiver)));
+
+        let mut remote = RemoteLidar::connect(addr, mode).await.unwrap().with_reconnect_policy(ReconnectPolicy::never());
+        assert_eq!(&device_info(), remote.device_info());
+
+        sender.send(distance_packet()).unwrap();
+        sender.send(Packet::LidarSpeed(LidarSpeedPacket::new(10.05))).unwrap();
+        assert_eq!(Some(distance_packet()), remote.next().await);
+        assert_eq!(Some(Packet::LidarSpeed(LidarSpeedPacket::new(10.05))), remote.next().await);
+
+        // Ending the source stops the server and closes the connection
+        drop(sender);
+        server.await.unwrap().unwrap();
+        assert_eq!(None, remote.next().await);
+    }
+
+    #[tokio::test]
+    async fn stream_packets_as_json() {
+        stream_packets(StreamMode::Packets).await;
+    }
+
+    #[tokio::test]
+    async fn stream_packets_as_frames() {
+        stream_packets(StreamMode::Frames).await;
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_server_restart() {
+        let server = LidarServer::bind("127.0.0.1:0", device_info()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let first_server = tokio::spawn(server.serve(ChannelStream(receiver)));
+
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            max_attempts: Some(50),
+        };
+        let mut remote = RemoteLidar::connect(addr, StreamMode::Frames).await.unwrap().with_reconnect_policy(policy);
+
+        sender.send(distance_packet()).unwrap();
+        assert_eq!(Some(distance_packet()), remote.next().await);
+
+        drop(sender);
+        first_server.await.unwrap().unwrap();
+
+        let speed_packet = Packet::LidarSpeed(LidarSpeedPacket::new(5.25));
+        let second_server = LidarServer::bind(addr, device_info()).await.unwrap();
+        let second_server = tokio::spawn(second_server.serve(RepeatStream(speed_packet.clone())));
+
+        assert_eq!(Some(speed_packet), remote.next().await);
+        second_server.abort();
+    }
+
+    #[tokio::test]
+    async fn stream_raw_bytes_of_a_lidar() {
+        let server = LidarServer::bind("127.0.0.1:0", device_info()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let (serial_port, fake_serial_port) = FakeSerialPort::new();
+        let server = tokio::spawn(server.serve_lidar(Lidar::spawn(fake_serial_port, device_info())));
+
+        let mut raw_client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            mode: StreamMode::Raw,
+        };
+        write_message(raw_client.get_mut(), &hello).await.unwrap();
+        assert!(matches!(read_message(&mut raw_client).await.unwrap(), HandshakeResponse::Welcome { .. }));
+        let mut remote = RemoteLidar::connect(addr, StreamMode::Raw).await.unwrap();
+
+        // Garbage and a corrupt frame are forwarded as is
+        let mut corrupt = SECOND_EXAMPLE;
+        corrupt[9] ^= 0xFF;
+        let mut bytes = vec![0x12, 0x34];
+        bytes.extend_from_slice(&corrupt);
+        bytes.extend_from_slice(&FIRST_EXAMPLE);
+        serial_port.send(bytes.clone()).unwrap();
+
+        let mut received = vec![0u8; bytes.len()];
+        raw_client.read_exact(&mut received).await.unwrap();
+        assert_eq!(bytes, received);
+
+        assert!(matches!(remote.next().await, Some(Packet::Distance(_))));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn rejects_raw_bytes_of_a_packet_stream() {
+        let server = LidarServer::bind("127.0.0.1:0", device_info()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let server = tokio::spawn(server.serve(RepeatStream(distance_packet())));
+
+        assert!(matches!(RemoteLidar::connect(addr, StreamMode::Raw).await, Err(NetworkError::Rejected(_))));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn rejects_too_long_messages() {
+        let mut line = vec![b'a'; MAX_MESSAGE_SIZE as usize + 1];
+        line.push(b'\n');
+
+        let result = read_message::<Hello>(&mut BufReader::new(&line[..])).await;
+        assert!(matches!(result, Err(NetworkError::MessageTooLong)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_connections_without_handshake() {
+        let server = LidarServer::bind("127.0.0.1:0", device_info()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let server = tokio::spawn(server.serve(RepeatStream(distance_packet())));
+
+        // The client never sends its hello
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut buffer = [0u8; 16];
+        assert_eq!(0, client.read(&mut buffer).await.unwrap());
+        server.abort();
+    }
+}
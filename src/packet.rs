@@ -1,4 +1,5 @@
-use crate::frame_parser::Frame;
+use crate::frame_parser::{Frame, FRAME_TYPE, PROTOCOL_VERSION};
+use crate::geometry::Point2;
 #[cfg(feature = "serialize")]
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -6,14 +7,38 @@ use thiserror::Error;
 const I3LIDAR_NEW_DISTANCE: u8 = 0xAD;
 const I3LIDAR_LIDAR_SPEED: u8 = 0xAE;
 
-#[derive(Debug, PartialEq)]
+/// A revolution is reported in 16 distance packets, each packet covers 22.5 degrees
+pub const SECTOR_ANGLE: f32 = 22.5;
+
+#[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum Packet {
     Distance(DistancePacket),
-    LidarSpeed,
+    LidarSpeed(LidarSpeedPacket),
 }
 
 impl Packet {
+    /// Encode this packet into a frame, this is the inverse of `Packet::parse`
+    pub fn to_frame(&self) -> Frame {
+        let (command_byte, parameters) = match self {
+            Packet::Distance(distance_packet) => (I3LIDAR_NEW_DISTANCE, distance_packet.encode_parameters()),
+            Packet::LidarSpeed(speed_packet) => (I3LIDAR_LIDAR_SPEED, vec![encode_radar_speed(speed_packet.radar_speed)]),
+        };
+
+        let parameters_length = parameters.len() as u16;
+        let mut data = Vec::with_capacity(parameters.len() + 5);
+        data.extend_from_slice(&[PROTOCOL_VERSION, FRAME_TYPE, command_byte]);
+        data.extend_from_slice(&parameters_length.to_be_bytes());
+        data.extend(parameters);
+
+        Frame::new(data)
+    }
+
+    /// Encode this packet into the bytes the lidar sends over the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_frame().to_bytes()
+    }
+
     pub fn parse(frame: Frame) -> Result<Self, PacketParseError> {
         let bytes: Vec<_> = frame.into();
         let frame_len = bytes.len();
@@ -35,8 +60,8 @@ impl Packet {
     fn parse_distance(data: &[u8]) -> Result<Self, PacketParseError> {
         let actual_length = data.len();
 
-        // A measuring package is at least 5 bytes long (header)
-        if actual_length < 5 {
+        // A measuring package is at least 7 bytes long (header)
+        if actual_length < 7 {
             return Err(PacketParseError::FrameTooShort(actual_length));
         }
 
@@ -60,14 +85,14 @@ impl Packet {
         let start_angle = (((data[5] as u16) << 8) | (data[6] as u16)) as f32 * 0.01f32;
 
         // Convert all remaining bytes into data
-        // Drop signal strength (only useful for sensor debugging purposes according to the datasheet)
-        let measurements = data[7..]
+        // The signal strength is only useful for sensor debugging purposes according to the datasheet
+        let (signal_strengths, measurements) = data[7..]
             .chunks(3)
             .filter_map(|values| match values {
-                [_signal_strength, value_high, value_low] => Some((((*value_high as u16) << 8) | (*value_low as u16)) as f32 * 0.25f32),
+                [signal_strength, value_high, value_low] => Some((*signal_strength, (((*value_high as u16) << 8) | (*value_low as u16)) as f32 * 0.25f32)),
                 _ => None,
             })
-            .collect();
+            .unzip();
 
         // Return the distance packet
         Ok(Packet::Distance(DistancePacket {
@@ -75,11 +100,32 @@ impl Packet {
             start_angle,
             offset_angle,
             measurements,
+            signal_strengths,
         }))
     }
 
-    fn parse_lidar_speed(_data: &[u8]) -> Result<Self, PacketParseError> {
-        todo!()
+    fn parse_lidar_speed(data: &[u8]) -> Result<Self, PacketParseError> {
+        let actual_length = data.len();
+
+        // A speed package is 3 bytes long: effective data length (2B) + radar speed (1B)
+        if actual_length < 3 {
+            return Err(PacketParseError::FrameTooShort(actual_length));
+        }
+
+        // Calculate the effective length
+        let effective_data_length = ((data[0] as u16) << 8) | (data[1] as u16);
+        let actual_data_length = (actual_length - 2) as u16;
+        if effective_data_length != actual_data_length {
+            return Err(PacketParseError::UnexpectedFrameLength {
+                actual: actual_data_length,
+                expected: effective_data_length,
+            });
+        }
+
+        // Get the radar speed
+        let radar_speed = 0.05f32 * data[2] as f32;
+
+        Ok(Packet::LidarSpeed(LidarSpeedPacket { radar_speed }))
     }
 }
 
@@ -93,23 +139,161 @@ pub enum PacketParseError {
     UnexpectedFrameLength { actual: u16, expected: u16 },
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct DistancePacket {
     radar_speed: f32,
     start_angle: f32,
     offset_angle: f32,
     measurements: Vec<f32>,
+    // Recordings made before the signal strength was kept don't contain it
+    #[cfg_attr(feature = "serialize", serde(default))]
+    signal_strengths: Vec<u8>,
+}
+
+impl DistancePacket {
+    /// Create a new distance packet, angles are in degrees, the radar speed in revolutions per second and distances in millimeters
+    ///
+    /// The signal strength of all measurements is 0, use `with_signal_strengths` to set them.
+    pub fn new(radar_speed: f32, start_angle: f32, offset_angle: f32, measurements: Vec<f32>) -> Self {
+        DistancePacket {
+            radar_speed,
+            start_angle,
+            offset_angle,
+            signal_strengths: vec![0; measurements.len()],
+            measurements,
+        }
+    }
+
+    /// Set the signal strength of every measurement, missing values are filled with 0
+    pub fn with_signal_strengths(mut self, mut signal_strengths: Vec<u8>) -> Self {
+        signal_strengths.resize(self.measurements.len(), 0);
+        self.signal_strengths = signal_strengths;
+        self
+    }
+
+    /// Rotation speed of the lidar in revolutions per second
+    pub fn radar_speed(&self) -> f32 {
+        self.radar_speed
+    }
+
+    /// Angle of the first measurement in this packet, in degrees
+    pub fn start_angle(&self) -> f32 {
+        self.start_angle
+    }
+
+    /// Zero offset angle in degrees (debugging information of the sensor)
+    pub fn offset_angle(&self) -> f32 {
+        self.offset_angle
+    }
+
+    /// Measured distances in millimeters, a distance of 0 means no return
+    pub fn measurements(&self) -> &[f32] {
+        &self.measurements
+    }
+
+    /// Measured distances in millimeters, e.g. to mark measurements as no return
+    pub fn measurements_mut(&mut self) -> &mut [f32] {
+        &mut self.measurements
+    }
+
+    /// Signal strength of every measurement (debugging information of the sensor)
+    pub fn signal_strengths(&self) -> &[u8] {
+        &self.signal_strengths
+    }
+
+    /// Signal strength of the measurement at `index`, 0 when unknown
+    pub fn signal_strength_of(&self, index: usize) -> u8 {
+        self.signal_strengths.get(index).copied().unwrap_or(0)
+    }
+
+    /// Angle (in degrees) of the measurement at `index`
+    ///
+    /// The measurements are spread evenly over the sector starting at the start angle.
+    pub fn angle_of(&self, index: usize) -> f32 {
+        let angle = self.start_angle + SECTOR_ANGLE * index as f32 / self.measurements.len() as f32;
+        angle % 360.0
+    }
+
+    /// Iterate over all measurements together with their angle
+    pub fn samples(&self) -> impl Iterator<Item = Sample> + '_ {
+        self.measurements.iter().enumerate().map(move |(index, distance)| Sample {
+            angle: self.angle_of(index),
+            distance: *distance,
+            signal_strength: self.signal_strength_of(index),
+        })
+    }
+
+    fn encode_parameters(&self) -> Vec<u8> {
+        let mut parameters = Vec::with_capacity(5 + 3 * self.measurements.len());
+        parameters.push(encode_radar_speed(self.radar_speed));
+        parameters.extend_from_slice(&encode_scaled(self.offset_angle, 0.01).to_be_bytes());
+        parameters.extend_from_slice(&encode_scaled(self.start_angle, 0.01).to_be_bytes());
+
+        for (index, distance) in self.measurements.iter().enumerate() {
+            parameters.push(self.signal_strength_of(index));
+            parameters.extend_from_slice(&encode_scaled(*distance, 0.25).to_be_bytes());
+        }
+
+        parameters
+    }
+}
+
+fn encode_radar_speed(radar_speed: f32) -> u8 {
+    (radar_speed / 0.05).round().clamp(0.0, u8::MAX as f32) as u8
+}
+
+fn encode_scaled(value: f32, resolution: f32) -> u16 {
+    (value / resolution).round().clamp(0.0, u16::MAX as f32) as u16
+}
+
+/// A single measurement of a `DistancePacket`
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Sample {
+    /// Angle in degrees
+    pub angle: f32,
+    /// Distance in millimeters, 0 means no return
+    pub distance: f32,
+    /// Signal strength (debugging information of the sensor)
+    pub signal_strength: u8,
+}
+
+impl Sample {
+    /// Position of the sample in the frame of the lidar: the x axis points at angle 0, see `MountingPose` for other frames
+    pub fn point(&self) -> Point2 {
+        Point2::from_polar(self.angle, self.distance)
+    }
+}
+
+/// Health information sent by the lidar when the rotation speed is out of spec (speed failure frame)
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct LidarSpeedPacket {
+    radar_speed: f32,
+}
+
+impl LidarSpeedPacket {
+    /// Create a new speed failure packet, the radar speed is in revolutions per second
+    pub fn new(radar_speed: f32) -> Self {
+        LidarSpeedPacket { radar_speed }
+    }
+
+    /// Rotation speed of the lidar in revolutions per second
+    pub fn radar_speed(&self) -> f32 {
+        self.radar_speed
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crc::CRC;
     use crate::frame_parser::{FrameNextByteResult, FrameParser};
-    use crate::mock_data::FIRST_EXAMPLE;
+    use crate::mock_data::{FIRST_EXAMPLE, SECOND_EXAMPLE};
 
-    fn first_example_package() -> DistancePacket {
-        let frame = FIRST_EXAMPLE
+    fn parse_example(example: &[u8]) -> Packet {
+        let frame = example
             .iter()
             .fold(FrameNextByteResult::Unfinished(FrameParser::new()), |acc, current_byte| match acc {
                 FrameNextByteResult::Finished(frame) => FrameNextByteResult::Finished(frame),
@@ -118,11 +302,13 @@ mod tests {
             .finished()
             .unwrap();
 
-        let packet = Packet::parse(frame).unwrap();
+        Packet::parse(frame).unwrap()
+    }
 
-        match packet {
+    fn first_example_package() -> DistancePacket {
+        match parse_example(&FIRST_EXAMPLE) {
             Packet::Distance(distance_packet) => distance_packet,
-            Packet::LidarSpeed => panic!("First example is distance, not lidar speed"),
+            Packet::LidarSpeed(_) => panic!("First example is distance, not lidar speed"),
         }
     }
 
@@ -141,8 +327,69 @@ mod tests {
                     5323.25f32, 5742.5f32, 3038f32, 3001f32, 0f32, 2999.5f32, 3001f32, 3028f32, 3001f32, 3033f32, 3038f32, 5762.75f32, 5887.25f32, 5876.75f32,
                     5898f32, 5898f32, 5887.25f32, 6028.5
                 ],
+                signal_strengths: vec![
+                    0x00, 0x46, 0x54, 0x00, 0x00, 0x91, 0x82, 0x93, 0x6D, 0x51, 0x00, 0x5D, 0x66, 0x68, 0x41, 0x86, 0x4D, 0x89, 0x8E, 0x92, 0x8C, 0x63, 0x6D,
+                    0x7C, 0x92, 0x89, 0x90, 0x89, 0x93, 0x4B, 0x57, 0x43, 0x41, 0x00, 0x54, 0x6B, 0x6B, 0x58, 0x7E, 0x5D, 0x3F, 0x5A, 0x57, 0x5B, 0x59, 0x59,
+                    0x5E
+                ],
             },
             packet
         );
     }
+
+    #[test]
+    fn test_example_1_sample_angles() {
+        let packet = first_example_package();
+        let samples: Vec<_> = packet.samples().collect();
+
+        assert_eq!(47, samples.len());
+        assert_eq!(
+            Sample {
+                angle: 270.0,
+                distance: 0.0,
+                signal_strength: 0x00
+            },
+            samples[0]
+        );
+        assert_eq!(
+            Sample {
+                angle: 270.0 + 22.5 * 46.0 / 47.0,
+                distance: 6028.5,
+                signal_strength: 0x5E
+            },
+            samples[46]
+        );
+    }
+
+    #[test]
+    fn test_example_1_encode() {
+        // The example uses protocol version 0x01, the encoder always writes 0x00
+        let mut expected = FIRST_EXAMPLE.to_vec();
+        expected[3] = PROTOCOL_VERSION;
+        let crc = CRC::from_bytes(&expected[..expected.len() - 2]).as_u16();
+        let crc_position = expected.len() - 2;
+        expected[crc_position..].copy_from_slice(&crc.to_be_bytes());
+
+        assert_eq!(expected, Packet::Distance(first_example_package()).to_bytes());
+    }
+
+    #[test]
+    fn test_example_2_encode() {
+        assert_eq!(SECOND_EXAMPLE.to_vec(), parse_example(&SECOND_EXAMPLE).to_bytes());
+    }
+
+    #[test]
+    fn test_example_2_lidar_speed() {
+        let packet = parse_example(&SECOND_EXAMPLE);
+
+        assert_eq!(Packet::LidarSpeed(LidarSpeedPacket { radar_speed: 5.25f32 }), packet);
+    }
+
+    #[test]
+    fn test_lidar_speed_unexpected_length() {
+        assert_eq!(
+            Err(PacketParseError::UnexpectedFrameLength { actual: 1, expected: 2 }),
+            Packet::parse_lidar_speed(&[0x00, 0x02, 0x69])
+        );
+    }
 }
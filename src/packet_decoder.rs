@@ -0,0 +1,85 @@
+use crate::frame_parser::{FrameNextByteResult, FrameParseError, FrameParser};
+use crate::packet::{Packet, PacketParseError};
+use thiserror::Error;
+
+/// Decodes a stream of bytes (as received from the lidar) into packets.
+///
+/// The decoder combines the `FrameParser` and `Packet::parse`, after an error it waits for the next frame header.
+#[derive(Debug, Default)]
+pub struct PacketDecoder {
+    frame_parser: FrameParser,
+}
+
+impl PacketDecoder {
+    /// Create a new packet decoder
+    pub fn new() -> Self {
+        PacketDecoder::default()
+    }
+
+    /// Feed the next byte into the decoder
+    ///
+    /// Returns `None` while a frame is incomplete and the decoded packet (or the error) once a frame is finished.
+    pub fn next_byte(&mut self, value: u8) -> Option<Result<Packet, DecodeError>> {
+        let frame_parser = std::mem::take(&mut self.frame_parser);
+
+        match frame_parser.next_byte(value) {
+            Ok(FrameNextByteResult::Unfinished(frame_parser)) => {
+                self.frame_parser = frame_parser;
+                None
+            }
+            Ok(FrameNextByteResult::Finished(frame)) => Some(Packet::parse(frame).map_err(DecodeError::Packet)),
+            Err(e) => Some(Err(DecodeError::Frame(e))),
+        }
+    }
+
+    /// Feed a slice of bytes into the decoder, returns the results of all frames which were finished
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<Result<Packet, DecodeError>> {
+        bytes.iter().filter_map(|byte| self.next_byte(*byte)).collect()
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DecodeError {
+    #[error("Failed to parse frame: {0:}")]
+    Frame(#[source] FrameParseError),
+    #[error("Failed to parse packet: {0:}")]
+    Packet(#[source] PacketParseError),
+}
+
+impl DecodeError {
+    /// Returns true when the byte was not the start of a frame, this only means the decoder isn't in sync with the sensor (yet)
+    pub fn is_out_of_sync(&self) -> bool {
+        matches!(self, DecodeError::Frame(FrameParseError::InvalidFrameHeader(_)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_data::*;
+
+    #[test]
+    fn decodes_consecutive_frames() {
+        let mut bytes = FIRST_EXAMPLE.to_vec();
+        bytes.extend_from_slice(&SECOND_EXAMPLE);
+
+        let results = PacketDecoder::new().decode(&bytes);
+
+        assert_eq!(2, results.len());
+        assert!(matches!(results[0], Ok(Packet::Distance(_))));
+        assert!(matches!(results[1], Ok(Packet::LidarSpeed(_))));
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let mut bytes = vec![0x12, 0x34];
+        bytes.extend_from_slice(&SECOND_EXAMPLE);
+
+        let results = PacketDecoder::new().decode(&bytes);
+
+        assert_eq!(3, results.len());
+        assert!(results[0].as_ref().unwrap_err().is_out_of_sync());
+        assert!(results[1].as_ref().unwrap_err().is_out_of_sync());
+        assert!(matches!(results[2], Ok(Packet::LidarSpeed(_))));
+    }
+}
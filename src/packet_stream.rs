@@ -1,5 +1,9 @@
 use crate::packet::Packet;
 use async_trait::async_trait;
+use log::warn;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use thiserror::Error;
 
 /// Abstraction over a packet stream
 ///
@@ -11,3 +15,492 @@ pub trait PacketStream {
     /// Returns None if the stream has ended.
     async fn next(&mut self) -> Option<Packet>;
 }
+
+/// Packet stream which reports why it failed, instead of silently ending
+///
+/// `PacketStream::next` can't distinguish the end of a recording from a read error or a corrupt entry,
+/// accept this abstraction when the difference matters.
+#[async_trait]
+pub trait TryPacketStream {
+    /// Reads the next lidar package.
+    /// Returns `Ok(None)` if the stream has ended.
+    async fn try_next(&mut self) -> Result<Option<Packet>, StreamError>;
+}
+
+/// What a stream does when it encounters an entry which can't be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorruptEntryPolicy {
+    /// Log the corrupt entry and continue with the next one
+    Skip,
+    /// Return a `StreamError::Corrupt` (default)
+    #[default]
+    Stop,
+}
+
+#[derive(Debug, Error)]
+pub enum StreamError {
+    #[error("Failed to read line {line:}: {source:}")]
+    Io {
+        line: usize,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Corrupt entry on line {line:}: {source:}")]
+    Corrupt {
+        line: usize,
+        #[source]
+        source: Box<dyn Error + Send + Sync>,
+    },
+}
+
+impl StreamError {
+    /// Line (or record) number on which the error occurred, starting at 1
+    pub fn line(&self) -> usize {
+        match self {
+            StreamError::Io { line, .. } => *line,
+            StreamError::Corrupt { line, .. } => *line,
+        }
+    }
+}
+
+/// Combinators for packet streams, implemented for every `PacketStream`
+///
+/// Every combinator (except `chunks_by_revolution`) returns a new `PacketStream`, so pipelines can be composed:
+/// ```no_run
+///# let blah = async {
+/// use delta_2a_lidar::packet_stream::{PacketStream, PacketStreamExt};
+/// use delta_2a_lidar::Lidar;
+/// use std::time::Duration;
+///
+/// let lidar_name = Lidar::enumerate().unwrap().next().unwrap();
+/// let mut packets = Lidar::open(lidar_name)
+///     .unwrap()
+///     .distance_only()
+///     .timeout(Duration::from_secs(1))
+///     .throttle(Duration::from_millis(100));
+///
+/// while let Some(package) = packets.next().await {
+///     println!("Received package: {:?}", package);
+/// }
+///# };
+/// ```
+pub trait PacketStreamExt: PacketStream {
+    /// Transform packets, packets for which the function returns `None` are dropped
+    fn filter_map<F>(self, f: F) -> FilterMap<Self, F>
+    where
+        Self: Sized,
+        F: FnMut(Packet) -> Option<Packet> + Send,
+    {
+        FilterMap { stream: self, f }
+    }
+
+    /// Only keep the distance packets
+    fn distance_only(self) -> DistanceOnly<Self>
+    where
+        Self: Sized,
+    {
+        DistanceOnly { stream: self }
+    }
+
+    /// End the stream at the first packet which matches the predicate, this packet is not emitted
+    fn take_until<P>(self, predicate: P) -> TakeUntil<Self, P>
+    where
+        Self: Sized,
+        P: FnMut(&Packet) -> bool + Send,
+    {
+        TakeUntil {
+            stream: self,
+            predicate,
+            done: false,
+        }
+    }
+
+    /// End the stream when no packet was received within the given duration
+    fn timeout(self, duration: Duration) -> Timeout<Self>
+    where
+        Self: Sized,
+    {
+        Timeout {
+            stream: self,
+            duration,
+            timed_out: false,
+        }
+    }
+
+    /// Emit at most one packet per interval, the packets in between are dropped
+    fn throttle(self, interval: Duration) -> Throttle<Self>
+    where
+        Self: Sized,
+    {
+        Throttle {
+            stream: self,
+            interval,
+            last_emitted: None,
+        }
+    }
+
+    /// Group the packets per revolution of the lidar
+    ///
+    /// A new revolution starts when the start angle of a distance packet wraps around.
+    /// The first and the last chunk can contain a partial revolution.
+    fn chunks_by_revolution(self) -> RevolutionChunks<Self>
+    where
+        Self: Sized,
+    {
+        RevolutionChunks {
+            stream: self,
+            pending: Vec::new(),
+            last_start_angle: None,
+        }
+    }
+
+    /// Call a function for every packet which passes, e.g. for logging
+    fn inspect<F>(self, f: F) -> Inspect<Self, F>
+    where
+        Self: Sized,
+        F: FnMut(&Packet) + Send,
+    {
+        Inspect { stream: self, f }
+    }
+
+    /// Continue with another stream once this stream has ended
+    fn chain<T>(self, other: T) -> Chain<Self, T>
+    where
+        Self: Sized,
+        T: PacketStream,
+    {
+        Chain {
+            first: self,
+            second: other,
+            first_done: false,
+        }
+    }
+}
+
+impl<S: PacketStream + ?Sized> PacketStreamExt for S {}
+
+/// Packet stream returned by `PacketStreamExt::filter_map`
+pub struct FilterMap<S, F> {
+    stream: S,
+    f: F,
+}
+
+#[async_trait]
+impl<S, F> PacketStream for FilterMap<S, F>
+where
+    S: PacketStream + Send,
+    F: FnMut(Packet) -> Option<Packet> + Send,
+{
+    async fn next(&mut self) -> Option<Packet> {
+        loop {
+            let packet = self.stream.next().await?;
+
+            if let Some(packet) = (self.f)(packet) {
+                return Some(packet);
+            }
+        }
+    }
+}
+
+/// Packet stream returned by `PacketStreamExt::distance_only`
+pub struct DistanceOnly<S> {
+    stream: S,
+}
+
+#[async_trait]
+impl<S: PacketStream + Send> PacketStream for DistanceOnly<S> {
+    async fn next(&mut self) -> Option<Packet> {
+        loop {
+            let packet = self.stream.next().await?;
+
+            if let Packet::Distance(_) = packet {
+                return Some(packet);
+            }
+        }
+    }
+}
+
+/// Packet stream returned by `PacketStreamExt::take_until`
+pub struct TakeUntil<S, P> {
+    stream: S,
+    predicate: P,
+    done: bool,
+}
+
+#[async_trait]
+impl<S, P> PacketStream for TakeUntil<S, P>
+where
+    S: PacketStream + Send,
+    P: FnMut(&Packet) -> bool + Send,
+{
+    async fn next(&mut self) -> Option<Packet> {
+        if self.done {
+            return None;
+        }
+
+        let packet = self.stream.next().await?;
+
+        if (self.predicate)(&packet) {
+            self.done = true;
+            return None;
+        }
+
+        Some(packet)
+    }
+}
+
+/// Packet stream returned by `PacketStreamExt::timeout`
+pub struct Timeout<S> {
+    stream: S,
+    duration: Duration,
+    timed_out: bool,
+}
+
+impl<S> Timeout<S> {
+    /// Returns true when the stream ended because no packet was received in time
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+}
+
+#[async_trait]
+impl<S: PacketStream + Send> PacketStream for Timeout<S> {
+    async fn next(&mut self) -> Option<Packet> {
+        if self.timed_out {
+            return None;
+        }
+
+        match tokio::time::timeout(self.duration, self.stream.next()).await {
+            Ok(packet) => packet,
+            Err(_) => {
+                warn!("No packet received within {:?}, ending the stream", self.duration);
+                self.timed_out = true;
+                None
+            }
+        }
+    }
+}
+
+/// Packet stream returned by `PacketStreamExt::throttle`
+pub struct Throttle<S> {
+    stream: S,
+    interval: Duration,
+    last_emitted: Option<Instant>,
+}
+
+#[async_trait]
+impl<S: PacketStream + Send> PacketStream for Throttle<S> {
+    async fn next(&mut self) -> Option<Packet> {
+        loop {
+            let packet = self.stream.next().await?;
+            let now = Instant::now();
+
+            let due = self.last_emitted.map(|last| now.duration_since(last) >= self.interval).unwrap_or(true);
+            if due {
+                self.last_emitted = Some(now);
+                return Some(packet);
+            }
+        }
+    }
+}
+
+/// Stream of revolutions returned by `PacketStreamExt::chunks_by_revolution`
+pub struct RevolutionChunks<S> {
+    stream: S,
+    pending: Vec<Packet>,
+    last_start_angle: Option<f32>,
+}
+
+impl<S: PacketStream> RevolutionChunks<S> {
+    /// Reads all packets of the next revolution.
+    /// Returns None if the stream has ended.
+    pub async fn next(&mut self) -> Option<Vec<Packet>> {
+        loop {
+            let packet = match self.stream.next().await {
+                Some(packet) => packet,
+                None if self.pending.is_empty() => return None,
+                None => return Some(std::mem::take(&mut self.pending)),
+            };
+
+            let wrapped = match &packet {
+                Packet::Distance(distance_packet) => {
+                    let start_angle = distance_packet.start_angle();
+                    let wrapped = self.last_start_angle.map(|last| start_angle < last).unwrap_or(false);
+                    self.last_start_angle = Some(start_angle);
+                    wrapped
+                }
+                Packet::LidarSpeed(_) => false,
+            };
+
+            if wrapped && !self.pending.is_empty() {
+                let chunk = std::mem::replace(&mut self.pending, vec![packet]);
+                return Some(chunk);
+            }
+
+            self.pending.push(packet);
+        }
+    }
+}
+
+/// Packet stream returned by `PacketStreamExt::inspect`
+pub struct Inspect<S, F> {
+    stream: S,
+    f: F,
+}
+
+#[async_trait]
+impl<S, F> PacketStream for Inspect<S, F>
+where
+    S: PacketStream + Send,
+    F: FnMut(&Packet) + Send,
+{
+    async fn next(&mut self) -> Option<Packet> {
+        let packet = self.stream.next().await?;
+        (self.f)(&packet);
+
+        Some(packet)
+    }
+}
+
+/// Packet stream returned by `PacketStreamExt::chain`
+pub struct Chain<S, T> {
+    first: S,
+    second: T,
+    first_done: bool,
+}
+
+#[async_trait]
+impl<S, T> PacketStream for Chain<S, T>
+where
+    S: PacketStream + Send,
+    T: PacketStream + Send,
+{
+    async fn next(&mut self) -> Option<Packet> {
+        if !self.first_done {
+            match self.first.next().await {
+                Some(packet) => return Some(packet),
+                None => self.first_done = true,
+            }
+        }
+
+        self.second.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{DistancePacket, LidarSpeedPacket};
+    use std::collections::VecDeque;
+
+    struct VecStream(VecDeque<Packet>);
+
+    impl VecStream {
+        fn new(packets: Vec<Packet>) -> Self {
+            VecStream(packets.into())
+        }
+    }
+
+    #[async_trait]
+    impl PacketStream for VecStream {
+        async fn next(&mut self) -> Option<Packet> {
+            self.0.pop_front()
+        }
+    }
+
+    struct PendingStream;
+
+    #[async_trait]
+    impl PacketStream for PendingStream {
+        async fn next(&mut self) -> Option<Packet> {
+            std::future::pending().await
+        }
+    }
+
+    fn distance(start_angle: f32) -> Packet {
+        Packet::Distance(DistancePacket::new(6.5, start_angle, 0.0, vec![]))
+    }
+
+    fn speed() -> Packet {
+        Packet::LidarSpeed(LidarSpeedPacket::new(1.0))
+    }
+
+    fn start_angle(packet: &Packet) -> Option<f32> {
+        match packet {
+            Packet::Distance(distance_packet) => Some(distance_packet.start_angle()),
+            Packet::LidarSpeed(_) => None,
+        }
+    }
+
+    async fn collect<S: PacketStream>(mut stream: S) -> Vec<Packet> {
+        let mut packets = Vec::new();
+        while let Some(packet) = stream.next().await {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn filter_map_and_distance_only() {
+        let stream = VecStream::new(vec![distance(0.0), speed(), distance(22.5), distance(45.0)])
+            .distance_only()
+            .filter_map(|packet| if start_angle(&packet) == Some(22.5) { None } else { Some(packet) });
+
+        let start_angles: Vec<_> = collect(stream).await.iter().map(start_angle).collect();
+        assert_eq!(vec![Some(0.0), Some(45.0)], start_angles);
+    }
+
+    #[tokio::test]
+    async fn take_until_ends_before_matching_packet() {
+        let mut stream = VecStream::new(vec![distance(0.0), speed(), distance(22.5)]).take_until(|packet| matches!(packet, Packet::LidarSpeed(_)));
+
+        assert_eq!(Some(0.0), stream.next().await.as_ref().and_then(start_angle));
+        assert!(stream.next().await.is_none());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn inspect_and_chain() {
+        let mut inspected = 0;
+        let stream = VecStream::new(vec![distance(0.0)])
+            .chain(VecStream::new(vec![speed(), distance(22.5)]))
+            .inspect(|_| inspected += 1);
+
+        let packets = collect(stream).await;
+        assert_eq!(3, packets.len());
+        assert_eq!(3, inspected);
+        assert!(matches!(packets[1], Packet::LidarSpeed(_)));
+    }
+
+    #[tokio::test]
+    async fn throttle_drops_packets_within_interval() {
+        let stream = VecStream::new(vec![distance(0.0), distance(22.5), distance(45.0)]).throttle(Duration::from_secs(60));
+
+        let start_angles: Vec<_> = collect(stream).await.iter().map(start_angle).collect();
+        assert_eq!(vec![Some(0.0)], start_angles);
+    }
+
+    #[tokio::test]
+    async fn timeout_ends_stalled_stream() {
+        let mut stream = VecStream::new(vec![speed()]).chain(PendingStream).timeout(Duration::from_millis(10));
+
+        assert!(stream.next().await.is_some());
+        assert!(!stream.timed_out());
+        assert!(stream.next().await.is_none());
+        assert!(stream.timed_out());
+    }
+
+    #[tokio::test]
+    async fn chunks_by_revolution() {
+        let mut chunks = VecStream::new(vec![distance(315.0), distance(337.5), distance(0.0), speed(), distance(22.5), distance(0.0)]).chunks_by_revolution();
+
+        assert_eq!(2, chunks.next().await.unwrap().len());
+
+        let revolution = chunks.next().await.unwrap();
+        assert_eq!(3, revolution.len());
+        assert_eq!(Some(0.0), start_angle(&revolution[0]));
+
+        assert_eq!(1, chunks.next().await.unwrap().len());
+        assert!(chunks.next().await.is_none());
+    }
+}
@@ -0,0 +1,318 @@
+//! Export of distance measurements as point clouds, e.g. to inspect them in CloudCompare or PCL tools
+//!
+//! Point clouds are written as PCD or PLY files (ASCII or binary) with the fields `x`, `y`, `z` and `intensity`.
+//! Coordinates are in millimeters, `z` is always 0 and the intensity is the signal strength of the sample.
+//! Samples without a return are left out.
+use crate::geometry::MountingPose;
+use crate::packet::{DistancePacket, Sample};
+use crate::scan::Scan;
+use std::io::{self, Write};
+
+/// A single point of a `PointCloud`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub intensity: f32,
+}
+
+/// File format of an exported point cloud
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PointCloudFormat {
+    /// Point Cloud Data, the format of the Point Cloud Library (default)
+    #[default]
+    Pcd,
+    /// Polygon File Format
+    Ply,
+}
+
+/// Encoding of the points in an exported point cloud
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Human readable text (default)
+    #[default]
+    Ascii,
+    /// Little endian floats, smaller and faster to load
+    Binary,
+}
+
+/// Collects the samples of distance packets or scans as points
+///
+/// Without a mounting pose the points are in the frame of the lidar: the x axis points at angle 0 and angles increase counter-clockwise.
+#[derive(Debug, Clone, Default)]
+pub struct PointCloud {
+    mounting_pose: Option<MountingPose>,
+    points: Vec<Point>,
+}
+
+impl PointCloud {
+    /// Create an empty point cloud
+    pub fn new() -> Self {
+        PointCloud::default()
+    }
+
+    /// Transform the samples which are added from now on to the frame the lidar is mounted in
+    pub fn with_mounting_pose(mut self, mounting_pose: MountingPose) -> Self {
+        self.mounting_pose = Some(mounting_pose);
+        self
+    }
+
+    /// Add the samples of a distance packet
+    pub fn add_packet(&mut self, packet: &DistancePacket) {
+        self.add_samples(packet.samples());
+    }
+
+    /// Add the samples of a full revolution
+    pub fn add_scan(&mut self, scan: &Scan) {
+        self.add_samples(scan.samples().iter().copied());
+    }
+
+    /// Add samples, samples without a return are skipped
+    pub fn add_samples(&mut self, samples: impl IntoIterator<Item = Sample>) {
+        let transform = self.mounting_pose.unwrap_or_default().transform();
+
+        self.points.extend(samples.into_iter().filter(|sample| sample.distance > 0.0).map(|sample| {
+            let point = transform.apply(sample.point());
+
+            Point {
+                x: point.x,
+                y: point.y,
+                z: 0.0,
+                intensity: sample.signal_strength as f32,
+            }
+        }));
+    }
+
+    /// All points which were added so far
+    pub fn points(&self) -> &[Point] {
+        &self.points
+    }
+
+    /// Remove all points, e.g. to reuse the point cloud for the next scan
+    pub fn clear(&mut self) {
+        self.points.clear();
+    }
+
+    /// Write the point cloud in the given format
+    pub fn write<W: Write>(&self, writer: W, format: PointCloudFormat, encoding: Encoding) -> io::Result<()> {
+        match format {
+            PointCloudFormat::Pcd => self.write_pcd(writer, encoding),
+            PointCloudFormat::Ply => self.write_ply(writer, encoding),
+        }
+    }
+
+    /// Write the point cloud as a PCD (version 0.7) file
+    pub fn write_pcd<W: Write>(&self, mut writer: W, encoding: Encoding) -> io::Result<()> {
+        writeln!(writer, "# .PCD v0.7 - Point Cloud Data file format")?;
+        writeln!(writer, "VERSION 0.7")?;
+        writeln!(writer, "FIELDS x y z intensity")?;
+        writeln!(writer, "SIZE 4 4 4 4")?;
+        writeln!(writer, "TYPE F F F F")?;
+        writeln!(writer, "COUNT 1 1 1 1")?;
+        writeln!(writer, "WIDTH {}", self.points.len())?;
+        writeln!(writer, "HEIGHT 1")?;
+        writeln!(writer, "VIEWPOINT 0 0 0 1 0 0 0")?;
+        writeln!(writer, "POINTS {}", self.points.len())?;
+        match encoding {
+            Encoding::Ascii => writeln!(writer, "DATA ascii")?,
+            Encoding::Binary => writeln!(writer, "DATA binary")?,
+        }
+
+        self.write_points(writer, encoding)
+    }
+
+    /// Write the point cloud as a PLY file
+    pub fn write_ply<W: Write>(&self, mut writer: W, encoding: Encoding) -> io::Result<()> {
+        writeln!(writer, "ply")?;
+        match encoding {
+            Encoding::Ascii => writeln!(writer, "format ascii 1.0")?,
+            Encoding::Binary => writeln!(writer, "format binary_little_endian 1.0")?,
+        }
+        writeln!(writer, "element vertex {}", self.points.len())?;
+        for property in ["x", "y", "z", "intensity"] {
+            writeln!(writer, "property float {}", property)?;
+        }
+        writeln!(writer, "end_header")?;
+
+        self.write_points(writer, encoding)
+    }
+
+    fn write_points<W: Write>(&self, mut writer: W, encoding: Encoding) -> io::Result<()> {
+        for point in &self.points {
+            match encoding {
+                Encoding::Ascii => writeln!(writer, "{} {} {} {}", point.x, point.y, point.z, point.intensity)?,
+                Encoding::Binary => {
+                    for value in [point.x, point.y, point.z, point.intensity] {
+                        writer.write_all(&value.to_le_bytes())?;
+                    }
+                }
+            }
+        }
+
+        writer.flush()
+    }
+}
+
+/// Options for `export_file`
+#[cfg(feature = "file")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportOptions {
+    pub format: PointCloudFormat,
+    pub encoding: Encoding,
+    /// Transform the points to the frame the lidar is mounted in, by default the mounting pose of the recording is used (if any)
+    pub mounting_pose: Option<MountingPose>,
+    /// Write every complete revolution to its own file instead of accumulating all samples in a single file
+    pub per_scan: bool,
+}
+
+/// Export the samples of a measurements file, returns the amount of files which were written
+///
+/// A single point cloud is written to `output`. Per scan, the files are numbered: `cloud.pcd` becomes `cloud_00000.pcd`, `cloud_00001.pcd`, ...
+#[cfg(feature = "file")]
+pub async fn export_file(file_name: impl AsRef<std::path::Path>, output: impl AsRef<std::path::Path>, options: ExportOptions) -> anyhow::Result<usize> {
+    use crate::packet::Packet;
+    use crate::scan::ScanAssembler;
+
+    let write = |point_cloud: &PointCloud, path: &std::path::Path| -> io::Result<()> {
+        let file = io::BufWriter::new(std::fs::File::create(path)?);
+        point_cloud.write(file, options.format, options.encoding)
+    };
+
+    let mut file = crate::measurements_file::read(file_name).await?;
+    let mut point_cloud = PointCloud::new();
+    if let Some(mounting_pose) = options.mounting_pose.or_else(|| file.metadata().and_then(|metadata| metadata.mounting_pose)) {
+        point_cloud = point_cloud.with_mounting_pose(mounting_pose);
+    }
+
+    if !options.per_scan {
+        while let Some(timestamped_packet) = file.next_timestamped().await? {
+            if let Packet::Distance(packet) = &timestamped_packet.packet {
+                point_cloud.add_packet(packet);
+            }
+        }
+
+        write(&point_cloud, output.as_ref())?;
+        return Ok(1);
+    }
+
+    let output = output.as_ref();
+    let stem = output.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    let extension = output
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| format!(".{}", extension));
+
+    let mut scan_assembler = ScanAssembler::new();
+    let mut files = 0;
+    while let Some(timestamped_packet) = file.next_timestamped().await? {
+        let scan = match &timestamped_packet.packet {
+            Packet::Distance(packet) => scan_assembler.push(packet),
+            _ => None,
+        };
+
+        if let Some(scan) = scan {
+            point_cloud.clear();
+            point_cloud.add_scan(&scan);
+
+            let path = output.with_file_name(format!("{}_{:05}{}", stem, files, extension.as_deref().unwrap_or_default()));
+            write(&point_cloud, &path)?;
+            files += 1;
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mounted_point_cloud(mounting_pose: MountingPose) -> PointCloud {
+        let mut point_cloud = PointCloud::new().with_mounting_pose(mounting_pose);
+        let packet = DistancePacket::new(6.5, 90.0, 0.0, vec![1000.0, 0.0]).with_signal_strengths(vec![80, 0]);
+        point_cloud.add_packet(&packet);
+        point_cloud
+    }
+
+    fn assert_point(x: f32, y: f32, point: &Point) {
+        assert!((point.x - x).abs() < 0.01 && (point.y - y).abs() < 0.01, "Unexpected point: {:?}", point);
+    }
+
+    #[test]
+    fn applies_mounting_pose() {
+        let point_cloud = mounted_point_cloud(MountingPose::default());
+        assert_eq!(1, point_cloud.points().len());
+        assert_point(0.0, 1000.0, &point_cloud.points()[0]);
+        assert_eq!(80.0, point_cloud.points()[0].intensity);
+
+        let mounting_pose = MountingPose {
+            x: 100.0,
+            y: 200.0,
+            yaw: 90.0,
+            flipped: false,
+        };
+        assert_point(-900.0, 200.0, &mounted_point_cloud(mounting_pose).points()[0]);
+
+        let mounting_pose = MountingPose {
+            flipped: true,
+            ..mounting_pose
+        };
+        assert_point(1100.0, 200.0, &mounted_point_cloud(mounting_pose).points()[0]);
+    }
+
+    #[test]
+    fn writes_ascii_pcd() {
+        let mut bytes = Vec::new();
+        mounted_point_cloud(MountingPose::default()).write_pcd(&mut bytes, Encoding::Ascii).unwrap();
+        let text = String::from_utf8(bytes).unwrap();
+
+        assert!(text.contains("\nFIELDS x y z intensity\n"));
+        assert!(text.contains("\nPOINTS 1\nDATA ascii\n"));
+        assert!(text.ends_with(" 1000 0 80\n"));
+    }
+
+    #[test]
+    fn writes_binary_ply() {
+        let mut bytes = Vec::new();
+        mounted_point_cloud(MountingPose::default()).write_ply(&mut bytes, Encoding::Binary).unwrap();
+
+        let header = b"ply\nformat binary_little_endian 1.0\nelement vertex 1\n\
+            property float x\nproperty float y\nproperty float z\nproperty float intensity\nend_header\n";
+        assert_eq!(&header[..], &bytes[..header.len()]);
+        assert_eq!(header.len() + 16, bytes.len());
+        assert_eq!(80.0f32.to_le_bytes(), bytes[bytes.len() - 4..]);
+    }
+
+    #[cfg(feature = "file")]
+    #[tokio::test]
+    async fn exports_scans_of_recording() {
+        use crate::measurements_file;
+        use crate::packet::Packet;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let recording = dir.path().join("measurements.ldr");
+        let mut file = measurements_file::write(&recording).await.unwrap();
+        for index in 0..40 {
+            let packet = DistancePacket::new(6.5, (index % 16) as f32 * 22.5, 0.0, vec![1000.0]);
+            file.write(&Packet::Distance(packet)).await.unwrap();
+        }
+        file.finish().await.unwrap();
+
+        // Only the second revolution is complete
+        let options = ExportOptions {
+            per_scan: true,
+            ..ExportOptions::default()
+        };
+        assert_eq!(1, export_file(&recording, dir.path().join("cloud.pcd"), options).await.unwrap());
+        assert!(dir.path().join("cloud_00000.pcd").exists());
+
+        let options = ExportOptions {
+            format: PointCloudFormat::Ply,
+            ..ExportOptions::default()
+        };
+        assert_eq!(1, export_file(&recording, dir.path().join("cloud.ply"), options).await.unwrap());
+        let text = std::fs::read_to_string(dir.path().join("cloud.ply")).unwrap();
+        assert!(text.contains("element vertex 40\n"));
+    }
+}
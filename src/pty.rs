@@ -0,0 +1,241 @@
+//! This module exposes a recorded or simulated lidar on a pseudo-terminal, so any program which talks to a serial device
+//! (including `Lidar::open`) sees a virtual sensor.
+//! It is hidden behind the `pty` feature flag and only available on unix.
+//!
+//! The bytes are written at the pace of the real sensor (230400 baud, 8N1). When nobody reads the terminal the kernel buffer
+//! fills up and further bytes are dropped, just like a real serial line.
+//! Opening the terminal (e.g. with `Lidar::open`) flushes it, so bytes emitted before it was opened are lost.
+//!
+//! With the `file` feature a raw capture can be emitted with the timing at which its chunks were received.
+use crate::lidar::LidarName;
+use crate::packet_stream::PacketStream;
+#[cfg(feature = "file")]
+use crate::raw_capture::RawCaptureReader;
+use log::{debug, warn};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::pty::openpty;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::ttyname;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::os::unix::io::{FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const LIDAR_BAUD_RATE: u32 = 230_400;
+// 1 start bit + 8 data bits + 1 stop bit
+const BITS_PER_BYTE: u32 = 10;
+const CHUNK_QUEUE_SIZE: usize = 16;
+const WRITE_SLICE_SIZE: usize = 64;
+
+/// A pseudo-terminal which emits Delta-2A frames
+///
+/// The terminal exists as long as this value is alive. All constructors must be called from within a tokio runtime.
+pub struct VirtualSerialPort {
+    path: PathBuf,
+    _slave: OwnedFd,
+    producer: JoinHandle<()>,
+}
+
+impl VirtualSerialPort {
+    /// Emit the frames of a packet stream, e.g. a measurements file or a simulator
+    pub fn from_packet_stream<S: PacketStream + Send + 'static>(mut stream: S) -> Result<VirtualSerialPort, PtyError> {
+        Self::spawn(|sender| async move {
+            while let Some(packet) = stream.next().await {
+                if sender.send(packet.to_bytes()).await.is_err() {
+                    return;
+                }
+            }
+        })
+    }
+
+    /// Emit the bytes of a reader as is, e.g. a raw capture of the serial port
+    pub fn from_reader<R: AsyncRead + Send + Unpin + 'static>(mut reader: R) -> Result<VirtualSerialPort, PtyError> {
+        Self::spawn(|sender| async move {
+            let mut buffer = [0u8; 1024];
+
+            loop {
+                match reader.read(&mut buffer).await {
+                    Ok(0) => return,
+                    Ok(read) => {
+                        if sender.send(buffer[..read].to_vec()).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Failed to read from source, stop emitting: {}", e);
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Emit the chunks of a raw capture byte for byte, every chunk at the time since the start at which it was received
+    #[cfg(feature = "file")]
+    pub fn from_raw_capture(mut capture: RawCaptureReader) -> Result<VirtualSerialPort, PtyError> {
+        Self::spawn(|sender| async move {
+            let started_at = tokio::time::Instant::now();
+
+            loop {
+                match capture.next_chunk().await {
+                    Ok(Some((timestamp, bytes))) => {
+                        tokio::time::sleep_until(started_at + timestamp).await;
+                        if sender.send(bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!("Failed to read raw capture, stop emitting: {}", e);
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    fn spawn<F, Fut>(produce: F) -> Result<VirtualSerialPort, PtyError>
+    where
+        F: FnOnce(mpsc::Sender<Vec<u8>>) -> Fut,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let pty = openpty(None, None).map_err(PtyError::Open)?;
+
+        // SAFETY: openpty returned two new file descriptors which are owned by nobody else
+        let (master, slave) = unsafe { (OwnedFd::from_raw_fd(pty.master), OwnedFd::from_raw_fd(pty.slave)) };
+
+        // Raw mode: no echo and no translation of line endings, the frames are binary
+        let mut termios = tcgetattr(pty.slave).map_err(PtyError::Configure)?;
+        cfmakeraw(&mut termios);
+        tcsetattr(pty.slave, SetArg::TCSANOW, &termios).map_err(PtyError::Configure)?;
+
+        // Never block on a full buffer, drop bytes instead (like a serial line nobody listens to)
+        fcntl(pty.master, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(PtyError::Configure)?;
+
+        let path = ttyname(pty.slave).map_err(PtyError::Open)?;
+
+        // The producer (async) hands the bytes over to the writer thread, which paces them at the baud rate
+        let (sender, receiver) = mpsc::channel(CHUNK_QUEUE_SIZE);
+        let producer = tokio::spawn(produce(sender));
+        thread::spawn(move || write_paced(File::from(master), receiver));
+
+        Ok(VirtualSerialPort { path, _slave: slave, producer })
+    }
+
+    /// Path of the terminal, e.g. /dev/pts/3
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Name which can be passed to `Lidar::open`
+    pub fn lidar_name(&self) -> LidarName {
+        LidarName::new(self.path.to_string_lossy())
+    }
+}
+
+impl Drop for VirtualSerialPort {
+    fn drop(&mut self) {
+        self.producer.abort();
+    }
+}
+
+fn write_paced(mut master: File, mut chunks: mpsc::Receiver<Vec<u8>>) {
+    let byte_duration = Duration::from_secs(BITS_PER_BYTE as u64) / LIDAR_BAUD_RATE;
+    let mut next_due = Instant::now();
+
+    while let Some(chunk) = chunks.blocking_recv() {
+        // Write small slices, sleeping for every single byte is too coarse for the scheduler
+        for slice in chunk.chunks(WRITE_SLICE_SIZE) {
+            // Wait until the previous bytes are on the "wire", an idle line doesn't allow sending a burst afterwards
+            let now = Instant::now();
+            if next_due > now {
+                thread::sleep(next_due - now);
+            } else {
+                next_due = now;
+            }
+            next_due += byte_duration * slice.len() as u32;
+
+            match master.write(slice) {
+                Ok(written) if written < slice.len() => debug!("Terminal buffer is full, dropped {} bytes", slice.len() - written),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => debug!("Terminal buffer is full, dropped {} bytes", slice.len()),
+                Err(e) => {
+                    warn!("Failed to write to terminal, stop emitting: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PtyError {
+    #[error("Failed to open pseudo-terminal: {0:}")]
+    Open(#[source] nix::Error),
+    #[error("Failed to configure pseudo-terminal: {0:}")]
+    Configure(#[source] nix::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_data::*;
+    use crate::packet::Packet;
+    use crate::Lidar;
+
+    #[tokio::test]
+    async fn lidar_reads_virtual_serial_port() {
+        // Opening a serial port flushes its buffers, so keep emitting until the lidar is surely listening
+        let mut bytes = Vec::new();
+        for _ in 0..100 {
+            bytes.extend_from_slice(&FIRST_EXAMPLE);
+            bytes.extend_from_slice(&SECOND_EXAMPLE);
+        }
+
+        let port = VirtualSerialPort::from_reader(std::io::Cursor::new(bytes)).unwrap();
+        let mut lidar = Lidar::open(port.lidar_name()).unwrap();
+
+        let mut received_distance = false;
+        let mut received_speed = false;
+        while !(received_distance && received_speed) {
+            match lidar.next().await {
+                Some(Packet::Distance(_)) => received_distance = true,
+                Some(Packet::LidarSpeed(_)) => received_speed = true,
+                None => panic!("Lidar stopped before both packets were received"),
+            }
+        }
+    }
+
+    #[cfg(feature = "file")]
+    #[tokio::test]
+    async fn emits_raw_capture_with_original_timing() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("capture.raw");
+
+        let mut capture = crate::raw_capture::create(&path).await.unwrap();
+        capture.write_chunk(Duration::ZERO, &FIRST_EXAMPLE).await.unwrap();
+        capture.write_chunk(Duration::from_millis(300), &SECOND_EXAMPLE).await.unwrap();
+        capture.flush().await.unwrap();
+
+        let started_at = Instant::now();
+        let port = VirtualSerialPort::from_raw_capture(crate::raw_capture::open(&path).await.unwrap()).unwrap();
+        let mut lidar = Lidar::open(port.lidar_name()).unwrap();
+
+        // The first chunk may be flushed when the lidar opens the terminal, the second one is sent after 300ms
+        let speed_packet = async {
+            loop {
+                if let Some(Packet::LidarSpeed(_)) = lidar.next().await {
+                    return;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), speed_packet).await.unwrap();
+        assert!(started_at.elapsed() >= Duration::from_millis(300));
+    }
+}
@@ -0,0 +1,271 @@
+//! Capture of the raw bytes received from the serial port, including frames which fail to parse.
+//! It is hidden behind the `file` feature flag.
+//!
+//! A capture starts with the magic bytes `D2ARAW` and a format version (u16, little endian).
+//! Every read of the serial port is stored as a record: the time since the start of the capture in microseconds (u64),
+//! the amount of bytes (u32) and the bytes themselves. All integers are little endian.
+//! A truncated last record (e.g. the capture was interrupted) is ignored.
+//!
+//! Reading a capture feeds the bytes through the `PacketDecoder` again, so captures can be reanalysed after a parser fix.
+use crate::lidar::RawChunk;
+use crate::packet::Packet;
+use crate::packet_decoder::PacketDecoder;
+use crate::packet_stream::PacketStream;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+const MAGIC: &[u8; 6] = b"D2ARAW";
+const FORMAT_VERSION: u16 = 1;
+// Timestamp (u64) + length (u32)
+const RECORD_HEADER_SIZE: usize = 12;
+// Far larger than a single read of the serial port, anything bigger means the capture is corrupt
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Create a new raw capture, an existing file is truncated
+pub async fn create(file_name: impl AsRef<Path>) -> Result<RawCaptureWriter> {
+    RawCaptureWriter::new(file_name).await
+}
+
+/// Open a raw capture for reading
+pub async fn open(file_name: impl AsRef<Path>) -> Result<RawCaptureReader> {
+    RawCaptureReader::new(file_name).await
+}
+
+/// A helper struct to write raw serial data to a capture file
+pub struct RawCaptureWriter {
+    buffer: BufWriter<File>,
+    started: Instant,
+}
+
+impl RawCaptureWriter {
+    async fn new(file_name: impl AsRef<Path>) -> Result<RawCaptureWriter> {
+        let file = File::create(file_name).await?;
+        let mut buffer = BufWriter::new(file);
+
+        buffer.write_all(MAGIC).await?;
+        buffer.write_all(&FORMAT_VERSION.to_le_bytes()).await?;
+
+        Ok(RawCaptureWriter {
+            buffer,
+            started: Instant::now(),
+        })
+    }
+
+    /// Write a chunk of bytes which was received at the given time since the start of the capture
+    pub async fn write_chunk(&mut self, timestamp: Duration, bytes: &[u8]) -> Result<()> {
+        self.buffer.write_all(&(timestamp.as_micros() as u64).to_le_bytes()).await?;
+        self.buffer.write_all(&(bytes.len() as u32).to_le_bytes()).await?;
+        self.buffer.write_all(bytes).await?;
+
+        Ok(())
+    }
+
+    /// Write a chunk which was received from `Lidar::subscribe_raw`
+    pub async fn write(&mut self, chunk: &RawChunk) -> Result<()> {
+        let timestamp = chunk.received.saturating_duration_since(self.started);
+
+        self.write_chunk(timestamp, &chunk.bytes).await
+    }
+
+    /// Write all chunks of a raw subscription until the lidar is closed
+    ///
+    /// ```no_run
+    ///# let blah = async {
+    /// use delta_2a_lidar::{raw_capture, Lidar};
+    ///
+    /// let lidar_name = Lidar::enumerate().unwrap().next().unwrap();
+    /// let lidar = Lidar::open(lidar_name).unwrap();
+    ///
+    /// let capture = raw_capture::create("./capture.raw").await.unwrap();
+    /// capture.record(lidar.subscribe_raw()).await.unwrap();
+    ///# };
+    /// ```
+    pub async fn record(mut self, mut chunks: broadcast::Receiver<RawChunk>) -> Result<()> {
+        loop {
+            match chunks.recv().await {
+                Ok(chunk) => self.write(&chunk).await?,
+                Err(RecvError::Lagged(skipped)) => warn!("Raw capture lagged behind, lost {} chunks", skipped),
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        self.flush().await
+    }
+
+    /// Write all buffered chunks to the file
+    pub async fn flush(&mut self) -> Result<()> {
+        self.buffer.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// A raw capture which is decoded while reading. This file implements `PacketStream` and can be used to mock a Lidar sensor
+pub struct RawCaptureReader {
+    buffer: BufReader<File>,
+    decoder: PacketDecoder,
+    packets: VecDeque<Packet>,
+    decode_errors: usize,
+}
+
+impl RawCaptureReader {
+    async fn new(file_name: impl AsRef<Path>) -> Result<RawCaptureReader> {
+        let file = File::open(file_name).await?;
+        let mut buffer = BufReader::new(file);
+
+        let mut magic = [0u8; 6];
+        buffer.read_exact(&mut magic).await?;
+        if &magic != MAGIC {
+            bail!("Not a raw capture file");
+        }
+
+        let version = buffer.read_u16_le().await?;
+        if version != FORMAT_VERSION {
+            bail!("Unsupported raw capture version: {}", version);
+        }
+
+        Ok(RawCaptureReader {
+            buffer,
+            decoder: PacketDecoder::new(),
+            packets: VecDeque::new(),
+            decode_errors: 0,
+        })
+    }
+
+    /// Reads the next chunk and the time since the start of the capture at which it was received.
+    /// Returns `Ok(None)` at the end of the capture.
+    ///
+    /// Chunks which are read with this function are not decoded.
+    pub async fn next_chunk(&mut self) -> Result<Option<(Duration, Vec<u8>)>> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+
+        // The end of the file is only valid between two records
+        match self.buffer.read_exact(&mut header[..1]).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        if let Err(e) = self.buffer.read_exact(&mut header[1..]).await {
+            return truncated(e);
+        }
+
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&header[..8]);
+        let mut length = [0u8; 4];
+        length.copy_from_slice(&header[8..]);
+
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_CHUNK_SIZE {
+            bail!("Invalid chunk length: {}", length);
+        }
+
+        let mut bytes = vec![0u8; length];
+        if let Err(e) = self.buffer.read_exact(&mut bytes).await {
+            return truncated(e);
+        }
+
+        Ok(Some((Duration::from_micros(u64::from_le_bytes(timestamp)), bytes)))
+    }
+
+    /// Amount of frames which failed to decode so far, bytes before the first frame header are not counted
+    pub fn decode_errors(&self) -> usize {
+        self.decode_errors
+    }
+
+    fn decode(&mut self, bytes: &[u8]) {
+        for result in self.decoder.decode(bytes) {
+            match result {
+                Ok(packet) => self.packets.push_back(packet),
+                Err(e) if e.is_out_of_sync() => debug!("{}", e),
+                Err(e) => {
+                    warn!("{}", e);
+                    self.decode_errors += 1;
+                }
+            }
+        }
+    }
+}
+
+fn truncated<T>(e: std::io::Error) -> Result<Option<T>> {
+    if e.kind() == ErrorKind::UnexpectedEof {
+        warn!("Ignoring truncated record at the end of the capture");
+        Ok(None)
+    } else {
+        Err(e.into())
+    }
+}
+
+#[async_trait]
+impl PacketStream for RawCaptureReader {
+    async fn next(&mut self) -> Option<Packet> {
+        loop {
+            if let Some(packet) = self.packets.pop_front() {
+                return Some(packet);
+            }
+
+            match self.next_chunk().await {
+                Ok(Some((_, bytes))) => self.decode(&bytes),
+                Ok(None) => return None,
+                Err(e) => {
+                    warn!("Stopped reading raw capture: {}", e);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_data::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn write_then_decode() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("capture.raw");
+
+        // A frame split over two reads, a corrupt frame and a complete frame
+        let mut corrupt = SECOND_EXAMPLE;
+        corrupt[9] ^= 0xFF;
+
+        let mut capture = create(&path).await.unwrap();
+        capture.write_chunk(Duration::from_millis(1), &FIRST_EXAMPLE[..100]).await.unwrap();
+        capture.write_chunk(Duration::from_millis(2), &FIRST_EXAMPLE[100..]).await.unwrap();
+        capture.write_chunk(Duration::from_millis(3), &corrupt).await.unwrap();
+        capture.write_chunk(Duration::from_millis(4), &SECOND_EXAMPLE).await.unwrap();
+        capture.flush().await.unwrap();
+
+        let mut reader = open(&path).await.unwrap();
+        assert_eq!(
+            Some((Duration::from_millis(1), FIRST_EXAMPLE[..100].to_vec())),
+            reader.next_chunk().await.unwrap()
+        );
+
+        let mut reader = open(&path).await.unwrap();
+        assert!(matches!(reader.next().await, Some(Packet::Distance(_))));
+        assert!(matches!(reader.next().await, Some(Packet::LidarSpeed(_))));
+        assert!(reader.next().await.is_none());
+        assert_eq!(1, reader.decode_errors());
+    }
+
+    #[tokio::test]
+    async fn rejects_other_files() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.ldr");
+        tokio::fs::write(&path, br#"{"LidarSpeed":{"radar_speed":1.0}}"#).await.unwrap();
+
+        assert!(open(&path).await.is_err());
+    }
+}
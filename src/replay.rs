@@ -0,0 +1,331 @@
+//! Replay of measurement files at the pace they were recorded.
+//! It is hidden behind the `file` feature flag.
+use crate::measurements_file::{MeasurementReadFile, TimestampedPacket};
+use crate::packet::Packet;
+use crate::packet_stream::PacketStream;
+use async_trait::async_trait;
+use log::warn;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+/// Replays a measurements file with the original timing between the packets
+///
+/// The replay can be controlled while it is running using a `ReplayHandle`.
+/// ```no_run
+///# let blah = async {
+/// use delta_2a_lidar::measurements_file;
+/// use delta_2a_lidar::packet_stream::PacketStream;
+/// use delta_2a_lidar::replay::Replay;
+///
+/// let file = measurements_file::read("./measurements.ldr").await.unwrap();
+/// let mut replay = Replay::new(file).with_speed(2.0).with_looping(true);
+///
+/// while let Some(package) = replay.next().await {
+///     println!("Replayed package: {:?}", package);
+/// }
+///# };
+/// ```
+pub struct Replay {
+    file: MeasurementReadFile,
+    looping: bool,
+    handle: ReplayHandle,
+    control: watch::Receiver<ReplayControl>,
+    seek_requests: mpsc::UnboundedReceiver<Duration>,
+    pending: Option<TimestampedPacket>,
+    anchor: Option<Anchor>,
+    position: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReplayControl {
+    speed: f64,
+    paused: bool,
+}
+
+/// A recording timestamp and the moment it is (was) replayed
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    instant: Instant,
+    timestamp: Duration,
+}
+
+impl Replay {
+    /// Replay a file at the original speed, without looping
+    pub fn new(file: MeasurementReadFile) -> Self {
+        let (control_sender, control) = watch::channel(ReplayControl { speed: 1.0, paused: false });
+        let (seek_sender, seek_requests) = mpsc::unbounded_channel();
+
+        Replay {
+            file,
+            looping: false,
+            handle: ReplayHandle {
+                control: Arc::new(control_sender),
+                seek_requests: seek_sender,
+            },
+            control,
+            seek_requests,
+            pending: None,
+            anchor: None,
+            position: Duration::default(),
+        }
+    }
+
+    /// Speed multiplier, 2.0 replays twice as fast as the recording
+    ///
+    /// An invalid speed (not a positive number) is logged and ignored.
+    pub fn with_speed(self, speed: f64) -> Self {
+        if let Err(e) = self.handle.set_speed(speed) {
+            warn!("{}, keeping the current speed", e);
+        }
+        self
+    }
+
+    /// Start again from the beginning when the end of the file is reached
+    pub fn with_looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    /// Handle to control this replay, also while it is being awaited in another task
+    pub fn handle(&self) -> ReplayHandle {
+        self.handle.clone()
+    }
+
+    /// Timestamp (in the recording) of the last replayed packet, or of the last seek
+    pub fn position(&self) -> Duration {
+        self.position
+    }
+
+    async fn read_next(&mut self) -> Option<TimestampedPacket> {
+        let mut rewound = false;
+
+        loop {
+            match self.file.next_timestamped().await {
+                Ok(Some(timestamped_packet)) => return Some(timestamped_packet),
+                // Rewinding an empty file would loop forever
+                Ok(None) if self.looping && !rewound => {
+                    if let Err(e) = self.file.rewind().await {
+                        warn!("Failed to rewind measurements: {}", e);
+                        return None;
+                    }
+
+                    rewound = true;
+                    self.anchor = None;
+                }
+                Ok(None) => return None,
+                Err(e) => {
+                    warn!("Stopped replaying measurements: {}", e);
+                    return None;
+                }
+            }
+        }
+    }
+
+    async fn seek(&mut self, target: Duration) {
+        if let Err(e) = self.file.seek_to_time(target).await {
+            warn!("Failed to seek in measurements: {}", e);
+        }
+
+        self.pending = None;
+        self.position = target;
+        self.anchor = None;
+    }
+}
+
+#[async_trait]
+impl PacketStream for Replay {
+    async fn next(&mut self) -> Option<Packet> {
+        loop {
+            while let Ok(target) = self.seek_requests.try_recv() {
+                self.seek(target).await;
+            }
+
+            if self.pending.is_none() {
+                self.pending = Some(self.read_next().await?);
+            }
+
+            let control = *self.control.borrow_and_update();
+            let timestamp = self.pending.as_ref().map(|packet| packet.timestamp).unwrap_or_default();
+
+            if control.paused {
+                // Resuming continues with the pending packet, without making up for the pause
+                self.anchor = None;
+
+                tokio::select! {
+                    _ = self.control.changed() => {}
+                    Some(target) = self.seek_requests.recv() => self.seek(target).await,
+                }
+                continue;
+            }
+
+            let anchor = *self.anchor.get_or_insert(Anchor {
+                instant: Instant::now(),
+                timestamp,
+            });
+            let due = anchor.instant + timestamp.saturating_sub(anchor.timestamp).div_f64(control.speed);
+
+            tokio::select! {
+                _ = tokio::time::sleep_until(due) => {
+                    let timestamped_packet = self.pending.take()?;
+                    self.position = timestamped_packet.timestamp;
+
+                    return Some(timestamped_packet.packet);
+                }
+                // A new speed applies from the pending packet onwards
+                _ = self.control.changed() => self.anchor = None,
+                Some(target) = self.seek_requests.recv() => self.seek(target).await,
+            }
+        }
+    }
+}
+
+/// Controls a running `Replay`, can be cloned and sent to other tasks
+#[derive(Clone)]
+pub struct ReplayHandle {
+    control: Arc<watch::Sender<ReplayControl>>,
+    seek_requests: mpsc::UnboundedSender<Duration>,
+}
+
+impl ReplayHandle {
+    /// Pause the replay, `next` doesn't return until the replay is resumed
+    pub fn pause(&self) {
+        self.control.send_modify(|control| control.paused = true);
+    }
+
+    /// Resume a paused replay
+    pub fn resume(&self) {
+        self.control.send_modify(|control| control.paused = false);
+    }
+
+    /// Returns true when the replay is paused
+    pub fn is_paused(&self) -> bool {
+        self.control.borrow().paused
+    }
+
+    /// Change the speed multiplier, 2.0 replays twice as fast as the recording
+    ///
+    /// The speed must be a positive number, otherwise the replay keeps its current speed.
+    pub fn set_speed(&self, speed: f64) -> Result<(), InvalidSpeed> {
+        if !speed.is_finite() || speed <= 0.0 {
+            return Err(InvalidSpeed(speed));
+        }
+
+        self.control.send_modify(|control| control.speed = speed);
+
+        Ok(())
+    }
+
+    /// The current speed multiplier
+    pub fn speed(&self) -> f64 {
+        self.control.borrow().speed
+    }
+
+    /// Continue the replay from the first packet at or after the given timestamp
+    pub fn seek(&self, timestamp: Duration) {
+        // The replay owns a handle as well, so the receiver is alive as long as anyone can seek
+        let _ = self.seek_requests.send(timestamp);
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+#[error("Replay speed must be a positive number, got {0:}")]
+pub struct InvalidSpeed(pub f64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurements_file;
+    use tempfile::TempDir;
+
+    async fn replay(dir: &TempDir, timestamps_ms: &[u64]) -> Replay {
+        let path = dir.path().join("measurements.ldr");
+
+        let lines: Vec<_> = timestamps_ms
+            .iter()
+            .map(|ms| {
+                format!(
+                    r#"{{"timestamp":{{"secs":0,"nanos":{}}},"packet":{{"LidarSpeed":{{"radar_speed":{}.0}}}}}}"#,
+                    ms * 1_000_000,
+                    ms
+                )
+            })
+            .collect();
+        tokio::fs::write(&path, lines.join("\n")).await.unwrap();
+
+        Replay::new(measurements_file::read(&path).await.unwrap())
+    }
+
+    fn radar_speed(packet: Option<Packet>) -> f32 {
+        match packet {
+            Some(Packet::LidarSpeed(packet)) => packet.radar_speed(),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reproduces_timing() {
+        let dir = TempDir::new().unwrap();
+        let mut replay = replay(&dir, &[100, 300, 400]).await;
+
+        let start = Instant::now();
+        replay.next().await.unwrap();
+        replay.next().await.unwrap();
+        assert_eq!(Duration::from_millis(200), start.elapsed());
+        replay.next().await.unwrap();
+        assert_eq!(Duration::from_millis(300), start.elapsed());
+        assert!(replay.next().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speed_and_looping() {
+        let dir = TempDir::new().unwrap();
+        let mut replay = replay(&dir, &[0, 100]).await.with_speed(4.0).with_looping(true);
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            replay.next().await.unwrap();
+            replay.next().await.unwrap();
+        }
+        assert_eq!(Duration::from_millis(75), start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn seek_and_pause() {
+        let dir = TempDir::new().unwrap();
+        let mut replay = replay(&dir, &[0, 100, 200, 300]).await;
+        let handle = replay.handle();
+
+        handle.seek(Duration::from_millis(150));
+        assert_eq!(200.0, radar_speed(replay.next().await));
+
+        handle.seek(Duration::from_millis(50));
+        assert_eq!(100.0, radar_speed(replay.next().await));
+
+        handle.pause();
+        assert!(tokio::time::timeout(Duration::from_secs(10), replay.next()).await.is_err());
+
+        handle.resume();
+        let start = Instant::now();
+        assert_eq!(200.0, radar_speed(replay.next().await));
+        assert_eq!(Duration::ZERO, start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_speeds() {
+        let dir = TempDir::new().unwrap();
+        let replay = replay(&dir, &[0]).await.with_speed(2.0).with_speed(-1.0);
+        let handle = replay.handle();
+        assert_eq!(2.0, handle.speed());
+
+        for speed in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(handle.set_speed(speed).is_err());
+        }
+        assert_eq!(2.0, handle.speed());
+
+        assert_eq!(Ok(()), handle.set_speed(0.5));
+        assert_eq!(0.5, handle.speed());
+    }
+}
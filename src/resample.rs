@@ -0,0 +1,197 @@
+//! Resampling of scans into a fixed amount of angular bins
+//!
+//! The amount of samples in a revolution depends on the rotation speed, so the samples of successive scans have different angles.
+//! A resampled scan always has the same bins: bin `i` is centered at `i * 360 / bins` degrees and covers half a bin on either side.
+//! A bin without any sample with a return is `None`.
+use crate::packet::Sample;
+use crate::scan::Scan;
+
+/// How the samples in a bin are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Reduction {
+    /// The sample closest to the center of the bin (default)
+    #[default]
+    Nearest,
+    /// The closest distance, the conservative choice for obstacle avoidance
+    Min,
+    /// The mean distance and signal strength
+    Mean,
+}
+
+/// A bin of a `ResampledScan`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bin {
+    /// Distance in millimeters
+    pub distance: f32,
+    pub signal_strength: u8,
+}
+
+/// A revolution in fixed angular bins
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResampledScan {
+    radar_speed: f32,
+    bins: Vec<Option<Bin>>,
+}
+
+impl ResampledScan {
+    /// Average rotation speed during this revolution in revolutions per second
+    pub fn radar_speed(&self) -> f32 {
+        self.radar_speed
+    }
+
+    /// All bins, ordered by angle. `None` means there was no return in the bin.
+    pub fn bins(&self) -> &[Option<Bin>] {
+        &self.bins
+    }
+
+    /// The distance of every bin, `None` means no return
+    pub fn distances(&self) -> impl Iterator<Item = Option<f32>> + '_ {
+        self.bins.iter().map(|bin| bin.map(|bin| bin.distance))
+    }
+
+    /// Angle between the centers of two bins, in degrees
+    pub fn bin_width(&self) -> f32 {
+        360.0 / self.bins.len() as f32
+    }
+
+    /// Angle of the center of the bin at `index`, in degrees
+    pub fn angle_of(&self, index: usize) -> f32 {
+        index as f32 * self.bin_width()
+    }
+}
+
+/// Converts scans into a fixed amount of bins
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resampler {
+    bins: usize,
+    reduction: Reduction,
+}
+
+impl Resampler {
+    /// Create a resampler with the given amount of bins per revolution, e.g. 360 or 720
+    pub fn new(bins: usize) -> Self {
+        Resampler {
+            bins: bins.max(1),
+            reduction: Reduction::default(),
+        }
+    }
+
+    /// Choose how the samples in a bin are combined, by default the nearest sample is used
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    /// Resample a scan, samples without a return are ignored
+    pub fn resample(&self, scan: &Scan) -> ResampledScan {
+        ResampledScan {
+            radar_speed: scan.radar_speed(),
+            bins: self.resample_samples(scan.samples()),
+        }
+    }
+
+    /// Resample the samples of a revolution
+    pub fn resample_samples(&self, samples: &[Sample]) -> Vec<Option<Bin>> {
+        let bin_width = 360.0 / self.bins as f32;
+        let mut grouped: Vec<Vec<(f32, Sample)>> = vec![Vec::new(); self.bins];
+
+        for sample in samples.iter().filter(|sample| sample.distance > 0.0 && sample.distance.is_finite()) {
+            let position = sample.angle.rem_euclid(360.0) / bin_width;
+            // The last half bin belongs to the first bin
+            let index = position.round() as usize % self.bins;
+            grouped[index].push(((position - position.round()).abs(), *sample));
+        }
+
+        grouped.iter().map(|samples| self.reduce(samples)).collect()
+    }
+
+    fn reduce(&self, samples: &[(f32, Sample)]) -> Option<Bin> {
+        let bin = |sample: &Sample| Bin {
+            distance: sample.distance,
+            signal_strength: sample.signal_strength,
+        };
+
+        match self.reduction {
+            Reduction::Nearest => samples.iter().min_by(|a, b| a.0.total_cmp(&b.0)).map(|(_, sample)| bin(sample)),
+            Reduction::Min => samples
+                .iter()
+                .map(|(_, sample)| sample)
+                .min_by(|a, b| a.distance.total_cmp(&b.distance))
+                .map(bin),
+            Reduction::Mean if samples.is_empty() => None,
+            Reduction::Mean => {
+                let count = samples.len() as f32;
+                let distance = samples.iter().map(|(_, sample)| sample.distance).sum::<f32>() / count;
+                let signal_strength = samples.iter().map(|(_, sample)| sample.signal_strength as f32).sum::<f32>() / count;
+
+                Some(Bin {
+                    distance,
+                    signal_strength: signal_strength.round() as u8,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(samples: &[(f32, f32, u8)]) -> Scan {
+        let samples = samples
+            .iter()
+            .map(|(angle, distance, signal_strength)| Sample {
+                angle: *angle,
+                distance: *distance,
+                signal_strength: *signal_strength,
+            })
+            .collect();
+
+        Scan::new(6.5, 16, samples)
+    }
+
+    #[test]
+    fn fixed_bins_regardless_of_sample_count() {
+        let resampler = Resampler::new(360);
+
+        for samples in [400, 500, 720] {
+            let samples: Vec<_> = (0..samples).map(|index| (index as f32 * 360.0 / samples as f32, 1000.0, 10)).collect();
+            let resampled = resampler.resample(&scan(&samples));
+
+            assert_eq!(360, resampled.bins().len());
+            assert!(resampled.distances().all(|distance| distance == Some(1000.0)));
+            assert_eq!(90.0, resampled.angle_of(90));
+        }
+    }
+
+    #[test]
+    fn reductions() {
+        let samples = scan(&[(0.4, 1000.0, 10), (0.9, 3000.0, 30), (1.2, 2000.0, 20), (359.8, 1500.0, 15)]);
+        let resample = |reduction| Resampler::new(360).with_reduction(reduction).resample(&samples);
+
+        // Bin 0 covers 359.5 up to 0.5 degrees, bin 1 covers 0.5 up to 1.5 degrees
+        let nearest = resample(Reduction::Nearest);
+        assert_eq!(Some(1500.0), nearest.bins()[0].map(|bin| bin.distance));
+        assert_eq!(Some(3000.0), nearest.bins()[1].map(|bin| bin.distance));
+
+        let min = resample(Reduction::Min);
+        assert_eq!(Some(1000.0), min.bins()[0].map(|bin| bin.distance));
+        assert_eq!(Some(2000.0), min.bins()[1].map(|bin| bin.distance));
+
+        let mean = resample(Reduction::Mean);
+        assert_eq!(
+            Some(Bin {
+                distance: 2500.0,
+                signal_strength: 25
+            }),
+            mean.bins()[1]
+        );
+    }
+
+    #[test]
+    fn marks_bins_without_return() {
+        let resampled = Resampler::new(4).resample(&scan(&[(0.0, 1000.0, 10), (90.0, 0.0, 0), (180.0, 2000.0, 20)]));
+
+        assert_eq!(vec![Some(1000.0), None, Some(2000.0), None], resampled.distances().collect::<Vec<_>>());
+    }
+}
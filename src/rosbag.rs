@@ -0,0 +1,437 @@
+//! ROS1 bag (version 2.0) writer, so recordings can be opened with rosbag and rqt_bag without installing ROS.
+//! It is hidden behind the `file` feature flag.
+//!
+//! Every revolution is written as a `sensor_msgs/LaserScan` message, see `LaserScan`.
+//! Messages are stored in uncompressed chunks, followed by the index which `rosbag` needs to open the bag.
+use crate::laser_scan::{LaserScan, LaserScanAssembler};
+use crate::measurements_file::MeasurementReadFile;
+use crate::packet::Packet;
+use crate::packet_stream::PacketStream;
+use anyhow::Result;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+
+const MAGIC: &[u8; 13] = b"#ROSBAG V2.0\n";
+// The bag header record is padded, so it can be rewritten once the index position is known
+const BAG_HEADER_SIZE: usize = 4096;
+// Chunks are written once they are larger than this amount of bytes
+const CHUNK_SIZE: usize = 768 * 1024;
+
+const OP_MESSAGE_DATA: u8 = 0x02;
+const OP_BAG_HEADER: u8 = 0x03;
+const OP_INDEX_DATA: u8 = 0x04;
+const OP_CHUNK: u8 = 0x05;
+const OP_CHUNK_INFO: u8 = 0x06;
+const OP_CONNECTION: u8 = 0x07;
+
+// All messages are written on a single connection
+const CONNECTION: u32 = 0;
+
+const LASER_SCAN_TYPE: &str = "sensor_msgs/LaserScan";
+const LASER_SCAN_MD5SUM: &str = "90c7ef2dc6895d81024acba2ac42f369";
+const LASER_SCAN_DEFINITION: &str = "Header header
+float32 angle_min
+float32 angle_max
+float32 angle_increment
+float32 time_increment
+float32 scan_time
+float32 range_min
+float32 range_max
+float32[] ranges
+float32[] intensities
+
+================================================================================
+MSG: std_msgs/Header
+uint32 seq
+time stamp
+string frame_id
+";
+
+/// Create a new bag, an existing file is truncated
+pub async fn create(file_name: impl AsRef<Path>) -> Result<RosbagWriter> {
+    RosbagWriter::new(file_name).await
+}
+
+#[derive(Debug)]
+struct ChunkInfo {
+    position: u64,
+    start_time: (u32, u32),
+    end_time: (u32, u32),
+    messages: u32,
+}
+
+/// Writes laser scans to a bag, call `finish` when done. Without `finish` the bag can only be opened after `rosbag reindex`.
+pub struct RosbagWriter {
+    buffer: BufWriter<File>,
+    position: u64,
+    topic: String,
+    laser_scan_assembler: LaserScanAssembler,
+    sequence: u32,
+    chunk: Vec<u8>,
+    // Time and offset in the chunk of every message in the current chunk
+    chunk_index: Vec<((u32, u32), u32)>,
+    chunks: Vec<ChunkInfo>,
+}
+
+impl RosbagWriter {
+    async fn new(file_name: impl AsRef<Path>) -> Result<RosbagWriter> {
+        let file = File::create(file_name).await?;
+
+        let mut rosbag_writer = RosbagWriter {
+            buffer: BufWriter::new(file),
+            position: 0,
+            topic: "/scan".to_string(),
+            laser_scan_assembler: LaserScanAssembler::new("laser"),
+            sequence: 0,
+            chunk: Vec::new(),
+            chunk_index: Vec::new(),
+            chunks: Vec::new(),
+        };
+
+        rosbag_writer.write_all(MAGIC).await?;
+        let bag_header = bag_header(0, 0, 0);
+        rosbag_writer.write_all(&bag_header).await?;
+
+        Ok(rosbag_writer)
+    }
+
+    /// The topic of the laser scans, `/scan` by default
+    pub fn with_topic(mut self, topic: impl Into<String>) -> Self {
+        self.topic = topic.into();
+        self
+    }
+
+    /// The frame id of the laser scans which are assembled by `write_packet`, `laser` by default
+    pub fn with_frame_id(mut self, frame_id: impl Into<String>) -> Self {
+        self.laser_scan_assembler = LaserScanAssembler::new(frame_id);
+        self
+    }
+
+    /// Write a packet which was received at `received`, a laser scan is written every time a revolution is completed
+    pub async fn write_packet(&mut self, received: SystemTime, packet: &Packet) -> Result<()> {
+        if let Packet::Distance(distance_packet) = packet {
+            if let Some(laser_scan) = self.laser_scan_assembler.push(distance_packet, received) {
+                self.write_laser_scan(&laser_scan).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write all packets of a stream until it ends, the packets are stamped with the time they pass
+    pub async fn record<S: PacketStream + ?Sized + Send>(&mut self, stream: &mut S) -> Result<()> {
+        while let Some(packet) = stream.next().await {
+            self.write_packet(SystemTime::now(), &packet).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write all revolutions of a measurements file, relative to the start time of the recording
+    pub async fn write_recording(&mut self, file: &mut MeasurementReadFile) -> Result<()> {
+        let start_time = file.metadata().map(|metadata| metadata.start_time).unwrap_or(SystemTime::UNIX_EPOCH);
+
+        while let Some(timestamped_packet) = file.next_timestamped().await? {
+            self.write_packet(start_time + timestamped_packet.timestamp, &timestamped_packet.packet).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a laser scan, e.g. one which was assembled outside of this writer
+    pub async fn write_laser_scan(&mut self, laser_scan: &LaserScan) -> Result<()> {
+        // The connection is described in the first chunk, before its first message
+        if self.chunks.is_empty() && self.chunk.is_empty() {
+            let connection = self.connection_record();
+            self.chunk.extend_from_slice(&connection);
+        }
+
+        let time = laser_scan.ros_stamp();
+        let header = [
+            field("op", &[OP_MESSAGE_DATA]),
+            field("conn", &CONNECTION.to_le_bytes()),
+            field("time", &time_bytes(time)),
+        ];
+        let data = serialize_laser_scan(laser_scan, self.sequence);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        self.chunk_index.push((time, self.chunk.len() as u32));
+        self.chunk.extend_from_slice(&record(&header, &data));
+
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.write_chunk().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the remaining messages and the index
+    pub async fn finish(mut self) -> Result<()> {
+        self.write_chunk().await?;
+
+        let index_position = self.position;
+        let chunk_count = self.chunks.len() as u32;
+        if chunk_count > 0 {
+            let connection = self.connection_record();
+            self.write_all(&connection).await?;
+        }
+
+        for chunk in std::mem::take(&mut self.chunks) {
+            let header = [
+                field("op", &[OP_CHUNK_INFO]),
+                field("ver", &1u32.to_le_bytes()),
+                field("chunk_pos", &chunk.position.to_le_bytes()),
+                field("start_time", &time_bytes(chunk.start_time)),
+                field("end_time", &time_bytes(chunk.end_time)),
+                field("count", &1u32.to_le_bytes()),
+            ];
+            let mut data = CONNECTION.to_le_bytes().to_vec();
+            data.extend_from_slice(&chunk.messages.to_le_bytes());
+            self.write_all(&record(&header, &data)).await?;
+        }
+
+        // Now the bag header can point to the index
+        let bag_header = bag_header(index_position, chunk_count.min(1), chunk_count);
+        self.buffer.seek(SeekFrom::Start(MAGIC.len() as u64)).await?;
+        self.buffer.write_all(&bag_header).await?;
+        self.buffer.flush().await?;
+
+        Ok(())
+    }
+
+    async fn write_chunk(&mut self) -> Result<()> {
+        if self.chunk_index.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = std::mem::take(&mut self.chunk);
+        let chunk_index = std::mem::take(&mut self.chunk_index);
+        let position = self.position;
+
+        let header = [
+            field("op", &[OP_CHUNK]),
+            field("compression", b"none"),
+            field("size", &(chunk.len() as u32).to_le_bytes()),
+        ];
+        self.write_all(&record(&header, &chunk)).await?;
+
+        let header = [
+            field("op", &[OP_INDEX_DATA]),
+            field("ver", &1u32.to_le_bytes()),
+            field("conn", &CONNECTION.to_le_bytes()),
+            field("count", &(chunk_index.len() as u32).to_le_bytes()),
+        ];
+        let mut data = Vec::with_capacity(chunk_index.len() * 12);
+        for (time, offset) in &chunk_index {
+            data.extend_from_slice(&time_bytes(*time));
+            data.extend_from_slice(&offset.to_le_bytes());
+        }
+        self.write_all(&record(&header, &data)).await?;
+
+        self.chunks.push(ChunkInfo {
+            position,
+            start_time: chunk_index.iter().map(|(time, _)| *time).min().unwrap_or_default(),
+            end_time: chunk_index.iter().map(|(time, _)| *time).max().unwrap_or_default(),
+            messages: chunk_index.len() as u32,
+        });
+
+        Ok(())
+    }
+
+    fn connection_record(&self) -> Vec<u8> {
+        let header = [
+            field("op", &[OP_CONNECTION]),
+            field("topic", self.topic.as_bytes()),
+            field("conn", &CONNECTION.to_le_bytes()),
+        ];
+        let data = [
+            field("topic", self.topic.as_bytes()),
+            field("type", LASER_SCAN_TYPE.as_bytes()),
+            field("md5sum", LASER_SCAN_MD5SUM.as_bytes()),
+            field("message_definition", LASER_SCAN_DEFINITION.as_bytes()),
+        ]
+        .concat();
+
+        record(&header, &data)
+    }
+
+    async fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+        self.buffer.write_all(bytes).await?;
+        self.position += bytes.len() as u64;
+
+        Ok(())
+    }
+}
+
+/// A header field: the length of the field (u32), the name, `=` and the value
+fn field(name: &str, value: &[u8]) -> Vec<u8> {
+    let mut field = Vec::with_capacity(4 + name.len() + 1 + value.len());
+    field.extend_from_slice(&((name.len() + 1 + value.len()) as u32).to_le_bytes());
+    field.extend_from_slice(name.as_bytes());
+    field.push(b'=');
+    field.extend_from_slice(value);
+    field
+}
+
+/// A record: the length of the header (u32), the header fields, the length of the data (u32) and the data
+fn record(header: &[Vec<u8>], data: &[u8]) -> Vec<u8> {
+    let header = header.concat();
+
+    let mut record = Vec::with_capacity(8 + header.len() + data.len());
+    record.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    record.extend_from_slice(&header);
+    record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+fn bag_header(index_position: u64, connections: u32, chunks: u32) -> Vec<u8> {
+    let header = [
+        field("op", &[OP_BAG_HEADER]),
+        field("index_pos", &index_position.to_le_bytes()),
+        field("conn_count", &connections.to_le_bytes()),
+        field("chunk_count", &chunks.to_le_bytes()),
+    ];
+    let header_length: usize = header.iter().map(Vec::len).sum();
+
+    // The data is padding
+    record(&header, &vec![b' '; BAG_HEADER_SIZE - 8 - header_length])
+}
+
+fn time_bytes((sec, nsec): (u32, u32)) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&sec.to_le_bytes());
+    bytes[4..].copy_from_slice(&nsec.to_le_bytes());
+    bytes
+}
+
+/// The ROS serialization of a `sensor_msgs/LaserScan` message
+fn serialize_laser_scan(laser_scan: &LaserScan, sequence: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(64 + laser_scan.frame_id.len() + 4 * (laser_scan.ranges.len() + laser_scan.intensities.len()));
+
+    data.extend_from_slice(&sequence.to_le_bytes());
+    data.extend_from_slice(&time_bytes(laser_scan.ros_stamp()));
+    data.extend_from_slice(&(laser_scan.frame_id.len() as u32).to_le_bytes());
+    data.extend_from_slice(laser_scan.frame_id.as_bytes());
+
+    for value in [
+        laser_scan.angle_min,
+        laser_scan.angle_max,
+        laser_scan.angle_increment,
+        laser_scan.time_increment,
+        laser_scan.scan_time,
+        laser_scan.range_min,
+        laser_scan.range_max,
+    ] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    for values in [&laser_scan.ranges, &laser_scan.intensities] {
+        data.extend_from_slice(&(values.len() as u32).to_le_bytes());
+        for value in values {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DistancePacket;
+    use std::collections::HashMap;
+    use std::convert::TryInto;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    // Parses the record at `position`, returns the header fields, the data and the position of the next record
+    fn parse_record(bytes: &[u8], position: usize) -> (HashMap<String, Vec<u8>>, &[u8], usize) {
+        let read_u32 = |position: usize| u32::from_le_bytes(bytes[position..position + 4].try_into().unwrap()) as usize;
+
+        let header_length = read_u32(position);
+        let mut fields = HashMap::new();
+        let mut field_position = position + 4;
+        while field_position < position + 4 + header_length {
+            let field = &bytes[field_position + 4..field_position + 4 + read_u32(field_position)];
+            let separator = field.iter().position(|byte| *byte == b'=').unwrap();
+            fields.insert(String::from_utf8(field[..separator].to_vec()).unwrap(), field[separator + 1..].to_vec());
+            field_position += 4 + field.len();
+        }
+
+        let data_length = read_u32(field_position);
+        let data = &bytes[field_position + 4..field_position + 4 + data_length];
+
+        (fields, data, field_position + 4 + data_length)
+    }
+
+    #[tokio::test]
+    async fn writes_indexed_bag() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("measurements.bag");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let mut writer = create(&path).await.unwrap().with_topic("/lidar/scan").with_frame_id("lidar");
+        for index in 0..56 {
+            let packet = DistancePacket::new(6.5, (index % 16) as f32 * 22.5, 0.0, vec![1000.0]).with_signal_strengths(vec![80]);
+            writer
+                .write_packet(start + Duration::from_millis(index * 10), &Packet::Distance(packet))
+                .await
+                .unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        let bytes = tokio::fs::read(&path).await.unwrap();
+        assert_eq!(MAGIC, &bytes[..13]);
+
+        let (bag_header, _, chunk_position) = parse_record(&bytes, 13);
+        assert_eq!(13 + BAG_HEADER_SIZE, chunk_position);
+        assert_eq!(vec![OP_BAG_HEADER], bag_header["op"]);
+        assert_eq!(1u32.to_le_bytes().to_vec(), bag_header["conn_count"]);
+        assert_eq!(1u32.to_le_bytes().to_vec(), bag_header["chunk_count"]);
+
+        // The chunk starts with the connection, followed by the two complete revolutions
+        let (chunk, chunk_data, index_data_position) = parse_record(&bytes, chunk_position);
+        assert_eq!(vec![OP_CHUNK], chunk["op"]);
+        assert_eq!(b"none".to_vec(), chunk["compression"]);
+        let (connection, connection_header, first_message) = parse_record(chunk_data, 0);
+        assert_eq!(vec![OP_CONNECTION], connection["op"]);
+        assert_eq!(b"/lidar/scan".to_vec(), connection["topic"]);
+        let connection_header = String::from_utf8_lossy(connection_header);
+        assert!(connection_header.contains("type=sensor_msgs/LaserScan"));
+        assert!(connection_header.contains(&format!("md5sum={}", LASER_SCAN_MD5SUM)));
+
+        let (message, message_data, second_message) = parse_record(chunk_data, first_message);
+        assert_eq!(vec![OP_MESSAGE_DATA], message["op"]);
+        // The first complete revolution started with the 17th packet
+        let stamp = time_bytes((1_000, 160_000_000));
+        assert_eq!(stamp.to_vec(), message["time"]);
+        // Sequence, stamp and frame id
+        assert_eq!(0u32.to_le_bytes(), message_data[..4]);
+        assert_eq!(stamp, message_data[4..12]);
+        assert_eq!(b"\x05\0\0\0lidar", &message_data[12..21]);
+        // Angles, times and range limits, followed by the ranges in meters
+        assert_eq!(16u32.to_le_bytes(), message_data[49..53]);
+        assert_eq!(1.0f32.to_le_bytes(), message_data[53..57]);
+        assert_eq!(21 + 28 + 2 * (4 + 16 * 4), message_data.len());
+        let (_, _, end) = parse_record(chunk_data, second_message);
+        assert_eq!(chunk_data.len(), end);
+
+        let (index_data, entries, index_position) = parse_record(&bytes, index_data_position);
+        assert_eq!(vec![OP_INDEX_DATA], index_data["op"]);
+        assert_eq!(2u32.to_le_bytes().to_vec(), index_data["count"]);
+        assert_eq!((first_message as u32).to_le_bytes(), entries[8..12]);
+        assert_eq!((second_message as u32).to_le_bytes(), entries[20..24]);
+
+        assert_eq!((index_position as u64).to_le_bytes().to_vec(), bag_header["index_pos"]);
+        let (connection, _, chunk_info_position) = parse_record(&bytes, index_position);
+        assert_eq!(vec![OP_CONNECTION], connection["op"]);
+        let (chunk_info, _, end) = parse_record(&bytes, chunk_info_position);
+        assert_eq!(vec![OP_CHUNK_INFO], chunk_info["op"]);
+        assert_eq!((chunk_position as u64).to_le_bytes().to_vec(), chunk_info["chunk_pos"]);
+        assert_eq!(bytes.len(), end);
+    }
+}
@@ -0,0 +1,146 @@
+//! Assembly of distance packets into full revolutions (scans)
+use crate::packet::{DistancePacket, Sample};
+use crate::scan_filter::ScanFilter;
+
+/// A full revolution of the lidar
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scan {
+    radar_speed: f32,
+    sectors: usize,
+    samples: Vec<Sample>,
+}
+
+impl Scan {
+    /// Create a scan from samples which are ordered by angle, e.g. synthetic data
+    pub fn new(radar_speed: f32, sectors: usize, samples: Vec<Sample>) -> Self {
+        Scan { radar_speed, sectors, samples }
+    }
+
+    /// Average rotation speed during this revolution in revolutions per second
+    pub fn radar_speed(&self) -> f32 {
+        self.radar_speed
+    }
+
+    /// Number of distance packets this revolution was assembled from
+    pub fn sectors(&self) -> usize {
+        self.sectors
+    }
+
+    /// All samples of this revolution, ordered by angle
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// Filter the samples of this revolution, see `scan_filter`
+    pub fn apply_filter<F: ScanFilter + ?Sized>(&mut self, filter: &F) {
+        filter.apply(&mut self.samples);
+    }
+}
+
+/// Collects distance packets and emits a `Scan` every time a revolution is completed
+///
+/// A new revolution starts when the start angle of a packet wraps around.
+/// The revolution which was already in progress when the first packet arrived is dropped, so all emitted scans are complete.
+#[derive(Debug, Default)]
+pub struct ScanAssembler {
+    last_start_angle: Option<f32>,
+    in_revolution: bool,
+    radar_speed_sum: f32,
+    sectors: usize,
+    samples: Vec<Sample>,
+}
+
+impl ScanAssembler {
+    /// Create a new scan assembler
+    pub fn new() -> Self {
+        ScanAssembler::default()
+    }
+
+    /// Add the next distance packet, returns the previous revolution when this packet starts a new one
+    pub fn push(&mut self, packet: &DistancePacket) -> Option<Scan> {
+        let wrapped = self.last_start_angle.map(|last| packet.start_angle() < last).unwrap_or(false);
+        self.last_start_angle = Some(packet.start_angle());
+
+        let scan = if wrapped { self.take_scan() } else { None };
+
+        if wrapped {
+            self.in_revolution = true;
+        }
+
+        if self.in_revolution {
+            self.radar_speed_sum += packet.radar_speed();
+            self.sectors += 1;
+            self.samples.extend(packet.samples());
+        }
+
+        scan
+    }
+
+    fn take_scan(&mut self) -> Option<Scan> {
+        let sectors = std::mem::take(&mut self.sectors);
+        let radar_speed_sum = std::mem::take(&mut self.radar_speed_sum);
+        let samples = std::mem::take(&mut self.samples);
+
+        if !self.in_revolution || sectors == 0 {
+            return None;
+        }
+
+        Some(Scan {
+            radar_speed: radar_speed_sum / sectors as f32,
+            sectors,
+            samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sector(index: usize) -> DistancePacket {
+        DistancePacket::new(6.5, index as f32 * 22.5, 0.0, vec![1000.0, 2000.0])
+    }
+
+    #[test]
+    fn drops_partial_first_revolution() {
+        let mut assembler = ScanAssembler::new();
+
+        for index in 8..16 {
+            assert_eq!(None, assembler.push(&sector(index)));
+        }
+
+        // The first wrap only starts the first complete revolution
+        assert_eq!(None, assembler.push(&sector(0)));
+    }
+
+    #[test]
+    fn emits_complete_revolutions() {
+        let mut assembler = ScanAssembler::new();
+
+        assembler.push(&sector(15));
+        for index in 0..16 {
+            assert_eq!(None, assembler.push(&sector(index)));
+        }
+
+        let scan = assembler.push(&sector(0)).unwrap();
+        assert_eq!(16, scan.sectors());
+        assert_eq!(32, scan.samples().len());
+        assert_eq!(6.5, scan.radar_speed());
+        assert_eq!(
+            Sample {
+                angle: 0.0,
+                distance: 1000.0,
+                signal_strength: 0
+            },
+            scan.samples()[0]
+        );
+        assert_eq!(
+            Sample {
+                angle: 348.75,
+                distance: 2000.0,
+                signal_strength: 0
+            },
+            scan.samples()[31]
+        );
+    }
+}
@@ -0,0 +1,314 @@
+//! Filters which clean up the samples of assembled scans, e.g. before they are used for mapping
+//!
+//! Filters mark bad samples as no return (a distance of 0), so the samples keep their angles.
+//! Use `Filter::RemoveNoReturn` as the last filter to drop them.
+//! The samples of a scan cover a full revolution, so the neighbours of the first sample include the last samples.
+//!
+//! With the `serialize` feature a `FilterChain` can be loaded from a config file, e.g. as JSON:
+//! `[{"Range": {"min": 150.0, "max": 6000.0}}, {"Median": {"window": 5}}, "RemoveNoReturn"]`
+use crate::packet::Sample;
+use crate::scan::Scan;
+#[cfg(feature = "serialize")]
+use serde::{Deserialize, Serialize};
+
+/// A filter over the samples of a full revolution, ordered by angle
+pub trait ScanFilter: Send + Sync {
+    fn apply(&self, samples: &mut Vec<Sample>);
+}
+
+/// The built-in filters, distances are in millimeters and angles in degrees
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum Filter {
+    /// Samples closer than `min` or further than `max` become no return
+    Range { min: f32, max: f32 },
+    /// Remove samples without a return (or with an invalid distance) from the scan
+    RemoveNoReturn,
+    /// Samples with a signal strength below `min` become no return
+    SignalStrength { min: u8 },
+    /// Replace every distance by the median of the `window` samples around it, which removes spikes
+    Median { window: usize },
+    /// Replace every distance by the mean of the `window` samples around it, which smooths noise
+    MovingAverage { window: usize },
+    /// Samples with less than `min_neighbours` of the `window` samples around them within `max_difference` become no return.
+    /// Removes isolated points (speckles), e.g. dust or reflections.
+    Speckle {
+        window: usize,
+        max_difference: f32,
+        min_neighbours: usize,
+    },
+    /// Samples which are seen at an angle outside of `min_angle`..`max_angle` from a closer sample of the `window` samples around them become no return.
+    /// At the edge of an object the beam partly hits the object and partly the background, the resulting mixed pixels
+    /// float between both and are seen almost parallel to the beam.
+    Shadow { window: usize, min_angle: f32, max_angle: f32 },
+}
+
+impl ScanFilter for Filter {
+    fn apply(&self, samples: &mut Vec<Sample>) {
+        match *self {
+            Filter::Range { min, max } => mark_no_return(samples, |sample| sample.distance < min || sample.distance > max),
+            Filter::RemoveNoReturn => samples.retain(has_return),
+            Filter::SignalStrength { min } => mark_no_return(samples, |sample| sample.signal_strength < min),
+            Filter::Median { window } => smooth(samples, window, |distances| {
+                distances.sort_by(f32::total_cmp);
+                let middle = distances.len() / 2;
+                if distances.len() % 2 == 1 {
+                    distances[middle]
+                } else {
+                    (distances[middle - 1] + distances[middle]) / 2.0
+                }
+            }),
+            Filter::MovingAverage { window } => smooth(samples, window, |distances| distances.iter().sum::<f32>() / distances.len() as f32),
+            Filter::Speckle {
+                window,
+                max_difference,
+                min_neighbours,
+            } => {
+                let original = samples.clone();
+                for (index, sample) in samples.iter_mut().enumerate().filter(|(_, sample)| has_return(sample)) {
+                    let neighbours = neighbours(&original, index, window)
+                        .filter(|neighbour| has_return(neighbour) && (neighbour.distance - sample.distance).abs() <= max_difference)
+                        .count();
+                    if neighbours < min_neighbours {
+                        sample.distance = 0.0;
+                    }
+                }
+            }
+            Filter::Shadow { window, min_angle, max_angle } => {
+                let original = samples.clone();
+                for (index, sample) in samples.iter_mut().enumerate().filter(|(_, sample)| has_return(sample)) {
+                    // Only the sample behind the edge is dropped, the edge of the object itself is valid
+                    let shadow = neighbours(&original, index, window).filter(has_return).any(|neighbour| {
+                        let angle = perpendicular_angle(sample, &neighbour);
+                        neighbour.distance < sample.distance && (angle < min_angle || angle > max_angle)
+                    });
+                    if shadow {
+                        sample.distance = 0.0;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Filters which are applied one after the other
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn ScanFilter>>,
+}
+
+impl FilterChain {
+    /// Create a chain without filters, which leaves scans untouched
+    pub fn new() -> Self {
+        FilterChain::default()
+    }
+
+    /// Create a chain of built-in filters, e.g. from a config file
+    pub fn from_filters(filters: impl IntoIterator<Item = Filter>) -> Self {
+        filters.into_iter().fold(FilterChain::new(), FilterChain::with_filter)
+    }
+
+    /// Append a filter to the chain
+    pub fn with_filter(mut self, filter: impl ScanFilter + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// Amount of filters in the chain
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Filter the samples of a scan
+    pub fn filter(&self, mut scan: Scan) -> Scan {
+        scan.apply_filter(self);
+        scan
+    }
+}
+
+impl ScanFilter for FilterChain {
+    fn apply(&self, samples: &mut Vec<Sample>) {
+        for filter in &self.filters {
+            filter.apply(samples);
+        }
+    }
+}
+
+fn has_return(sample: &Sample) -> bool {
+    sample.distance > 0.0 && sample.distance.is_finite()
+}
+
+fn mark_no_return(samples: &mut [Sample], predicate: impl Fn(&Sample) -> bool) {
+    for sample in samples.iter_mut().filter(|sample| has_return(sample) && predicate(sample)) {
+        sample.distance = 0.0;
+    }
+}
+
+/// The samples within `window / 2` positions of the sample at `index` (excluding itself), wrapping around the revolution
+fn neighbours(samples: &[Sample], index: usize, window: usize) -> impl Iterator<Item = Sample> + '_ {
+    let half = (window / 2).min(samples.len().saturating_sub(1) / 2);
+
+    (1..=half).flat_map(move |offset| {
+        let before = (index + samples.len() - offset) % samples.len();
+        let after = (index + offset) % samples.len();
+        [samples[before], samples[after]]
+    })
+}
+
+/// Replace the distance of every sample with a return by `reduce` of the distances (with a return) in the window around it
+fn smooth(samples: &mut [Sample], window: usize, reduce: impl Fn(&mut Vec<f32>) -> f32) {
+    let original = samples.to_vec();
+    let mut distances = Vec::with_capacity(window);
+
+    for (index, sample) in samples.iter_mut().enumerate().filter(|(_, sample)| has_return(sample)) {
+        distances.clear();
+        distances.push(sample.distance);
+        distances.extend(neighbours(&original, index, window).filter(has_return).map(|neighbour| neighbour.distance));
+
+        sample.distance = reduce(&mut distances);
+    }
+}
+
+/// The angle between the beam of `sample` and the line from `sample` to `neighbour`, in degrees.
+/// A surface which faces the lidar is seen at 90 degrees, a mixed pixel at almost 0 or 180.
+fn perpendicular_angle(sample: &Sample, neighbour: &Sample) -> f32 {
+    let difference = ((neighbour.angle - sample.angle + 540.0) % 360.0 - 180.0).to_radians();
+    let (sin, cos) = difference.sin_cos();
+
+    (neighbour.distance * sin).abs().atan2(sample.distance - neighbour.distance * cos).to_degrees()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A revolution of 360 samples, one per degree
+    fn samples(distances: &[f32]) -> Vec<Sample> {
+        distances
+            .iter()
+            .enumerate()
+            .map(|(index, distance)| Sample {
+                angle: index as f32 * 360.0 / distances.len() as f32,
+                distance: *distance,
+                signal_strength: 100,
+            })
+            .collect()
+    }
+
+    fn filtered(filter: Filter, distances: &[f32]) -> Vec<f32> {
+        let mut samples = samples(distances);
+        filter.apply(&mut samples);
+        samples.iter().map(|sample| sample.distance).collect()
+    }
+
+    #[test]
+    fn clips_range_and_removes_no_returns() {
+        let range = Filter::Range { min: 150.0, max: 6000.0 };
+        assert_eq!(vec![0.0, 150.0, 0.0, 6000.0, 0.0], filtered(range, &[100.0, 150.0, 0.0, 6000.0, 7000.0]));
+
+        let mut samples = samples(&[1000.0, 0.0, f32::NAN, 2000.0]);
+        Filter::RemoveNoReturn.apply(&mut samples);
+        assert_eq!(2, samples.len());
+        assert_eq!(270.0, samples[1].angle);
+    }
+
+    #[test]
+    fn signal_strength_threshold() {
+        let mut samples = samples(&[1000.0, 1000.0]);
+        samples[1].signal_strength = 10;
+        Filter::SignalStrength { min: 20 }.apply(&mut samples);
+
+        assert_eq!(1000.0, samples[0].distance);
+        assert_eq!(0.0, samples[1].distance);
+    }
+
+    #[test]
+    fn median_removes_spikes() {
+        let mut distances = vec![1000.0; 360];
+        distances[10] = 5000.0;
+        distances[20] = 0.0;
+
+        let result = filtered(Filter::Median { window: 5 }, &distances);
+        assert_eq!(1000.0, result[10]);
+        // No returns stay no return and are ignored by their neighbours
+        assert_eq!(0.0, result[20]);
+        assert_eq!(1000.0, result[21]);
+    }
+
+    #[test]
+    fn moving_average_smooths_and_wraps_around() {
+        let mut distances = vec![1000.0; 360];
+        distances[0] = 1300.0;
+
+        let result = filtered(Filter::MovingAverage { window: 3 }, &distances);
+        assert_eq!(1100.0, result[0]);
+        assert_eq!(1100.0, result[1]);
+        assert_eq!(1100.0, result[359]);
+        assert_eq!(1000.0, result[2]);
+    }
+
+    #[test]
+    fn removes_speckles() {
+        let mut distances = vec![0.0; 360];
+        // A wall and a single stray point
+        for distance in &mut distances[100..120] {
+            *distance = 2000.0;
+        }
+        distances[200] = 1500.0;
+
+        let speckle = Filter::Speckle {
+            window: 5,
+            max_difference: 50.0,
+            min_neighbours: 2,
+        };
+        let result = filtered(speckle, &distances);
+        assert_eq!(0.0, result[200]);
+        assert!(result[100..120].iter().all(|distance| *distance == 2000.0));
+    }
+
+    #[test]
+    fn removes_shadows_at_edges() {
+        // An object in front of a wall, with a mixed pixel between both
+        let mut distances = vec![3000.0; 360];
+        for distance in &mut distances[100..110] {
+            *distance = 1000.0;
+        }
+        distances[110] = 2000.0;
+
+        let shadow = Filter::Shadow {
+            window: 3,
+            min_angle: 10.0,
+            max_angle: 170.0,
+        };
+        let result = filtered(shadow, &distances);
+        assert_eq!(0.0, result[110]);
+        assert_eq!(0.0, result[99]);
+        assert_eq!(1000.0, result[100]);
+        assert_eq!(1000.0, result[109]);
+        assert_eq!(3000.0, result[200]);
+    }
+
+    #[test]
+    fn chains_filters() {
+        let chain = FilterChain::from_filters(vec![Filter::Range { min: 150.0, max: 6000.0 }, Filter::RemoveNoReturn]);
+        assert_eq!(2, chain.len());
+
+        let scan = chain.filter(Scan::new(6.5, 1, samples(&[100.0, 1000.0, 0.0, 7000.0])));
+        assert_eq!(1, scan.samples().len());
+        assert_eq!(1000.0, scan.samples()[0].distance);
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn loads_filters_from_config() {
+        let config = r#"[{"Range": {"min": 150.0, "max": 6000.0}}, {"Median": {"window": 5}}, "RemoveNoReturn"]"#;
+        let filters: Vec<Filter> = serde_json::from_str(config).unwrap();
+
+        assert_eq!(Filter::Median { window: 5 }, filters[1]);
+        assert_eq!(3, FilterChain::from_filters(filters).len());
+    }
+}
@@ -0,0 +1,578 @@
+//! This module simulates a Delta-2A lidar in a 2D world, so a whole application can be tested without hardware.
+//! It is hidden behind the `simulator` feature flag.
+//!
+//! The world consists of static line segments and circles and of moving (circular) obstacles.
+//! All coordinates are in millimeters, angles are in degrees and increase counter-clockwise starting from the x axis of the sensor.
+//!
+//! The simulator produces the same sector layout as the real sensor: a revolution is reported in 16 distance packets,
+//! starting at 0, 22.5, 45... degrees. Packets are available as `Packet`s (the simulator implements `PacketStream`)
+//! or as encoded frames (`Simulator::next_frame`), which can for example be written to a virtual serial port.
+//! A rotation speed below 0.05 revolutions per second (e.g. 0) simulates a stalled motor: the sensor only sends speed failure packets.
+use crate::packet::{DistancePacket, LidarSpeedPacket, Packet, SECTOR_ANGLE};
+use crate::packet_stream::PacketStream;
+use async_trait::async_trait;
+use std::time::Duration;
+
+const SECTORS_PER_REVOLUTION: usize = 16;
+// The resolution of the speed in the protocol, the sensor reports any slower speed as 0
+const MIN_ROTATION_SPEED: f32 = 0.05;
+// Interval of the speed failure packets while the motor is stalled
+const STALLED_REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A position or direction in the world, in millimeters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vector {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Vector {
+    pub fn new(x: f32, y: f32) -> Self {
+        Vector { x, y }
+    }
+
+    fn lerp(self, other: Vector, fraction: f32) -> Vector {
+        Vector::new(self.x + (other.x - self.x) * fraction, self.y + (other.y - self.y) * fraction)
+    }
+}
+
+/// A wall between two points
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub start: Vector,
+    pub end: Vector,
+}
+
+/// A round object (e.g. a pillar), the sensor sees it from the inside as well as from the outside
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Circle {
+    pub center: Vector,
+    pub radius: f32,
+}
+
+/// A round obstacle which moves back and forth between two points
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovingObstacle {
+    pub from: Vector,
+    pub to: Vector,
+    pub radius: f32,
+    /// Time it takes to move from `from` to `to` and back
+    pub period: Duration,
+}
+
+impl MovingObstacle {
+    /// The obstacle at the given simulation time
+    pub fn at(&self, time: Duration) -> Circle {
+        let period = self.period.as_secs_f32();
+        let fraction = if period > 0.0 {
+            // Triangle wave: 0 -> 1 -> 0 over one period
+            let phase = (time.as_secs_f32() / period).fract();
+            1.0 - (2.0 * phase - 1.0).abs()
+        } else {
+            0.0
+        };
+
+        Circle {
+            center: self.from.lerp(self.to, fraction),
+            radius: self.radius,
+        }
+    }
+}
+
+/// The world the simulated sensor is placed in
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct World {
+    pub segments: Vec<Segment>,
+    pub circles: Vec<Circle>,
+    pub moving_obstacles: Vec<MovingObstacle>,
+}
+
+impl World {
+    /// Create an empty world
+    pub fn new() -> Self {
+        World::default()
+    }
+
+    /// A closed, axis aligned rectangular room between two corners
+    pub fn rectangular_room(min: Vector, max: Vector) -> Self {
+        let corners = [min, Vector::new(max.x, min.y), max, Vector::new(min.x, max.y)];
+        let segments = (0..corners.len())
+            .map(|index| Segment {
+                start: corners[index],
+                end: corners[(index + 1) % corners.len()],
+            })
+            .collect();
+
+        World { segments, ..World::default() }
+    }
+
+    /// Distance from `origin` to the closest object in `direction` (a unit vector) at the given time
+    pub fn cast_ray(&self, origin: Vector, direction: Vector, time: Duration) -> Option<f32> {
+        let segments = self.segments.iter().filter_map(|segment| intersect_segment(origin, direction, segment));
+        let circles = self.circles.iter().filter_map(|circle| intersect_circle(origin, direction, circle));
+        let moving = self
+            .moving_obstacles
+            .iter()
+            .filter_map(|obstacle| intersect_circle(origin, direction, &obstacle.at(time)));
+
+        segments
+            .chain(circles)
+            .chain(moving)
+            .fold(None, |closest: Option<f32>, distance| match closest {
+                Some(closest) if closest <= distance => Some(closest),
+                _ => Some(distance),
+            })
+    }
+}
+
+fn intersect_segment(origin: Vector, direction: Vector, segment: &Segment) -> Option<f32> {
+    let edge = Vector::new(segment.end.x - segment.start.x, segment.end.y - segment.start.y);
+    let denominator = direction.x * edge.y - direction.y * edge.x;
+    if denominator.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let to_start = Vector::new(segment.start.x - origin.x, segment.start.y - origin.y);
+    let distance = (to_start.x * edge.y - to_start.y * edge.x) / denominator;
+    let position = (to_start.x * direction.y - to_start.y * direction.x) / denominator;
+
+    if distance > 0.0 && (0.0..=1.0).contains(&position) {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
+fn intersect_circle(origin: Vector, direction: Vector, circle: &Circle) -> Option<f32> {
+    let to_origin = Vector::new(origin.x - circle.center.x, origin.y - circle.center.y);
+    let b = to_origin.x * direction.x + to_origin.y * direction.y;
+    let c = to_origin.x * to_origin.x + to_origin.y * to_origin.y - circle.radius * circle.radius;
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let root = discriminant.sqrt();
+    [-b - root, -b + root].iter().copied().find(|distance| *distance > 0.0)
+}
+
+/// Imperfections of the simulated sensor
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoiseModel {
+    /// Standard deviation of the gaussian range noise in millimeters
+    pub range_sigma: f32,
+    /// Additional standard deviation, relative to the distance (e.g. 0.01 for 1%)
+    pub range_sigma_relative: f32,
+    /// Probability that a measurement has no return
+    pub dropout_rate: f32,
+    /// Seed of the random generator, a simulation with the same seed produces the same measurements
+    pub seed: u64,
+}
+
+impl NoiseModel {
+    /// A perfect sensor
+    pub fn none() -> Self {
+        NoiseModel {
+            range_sigma: 0.0,
+            range_sigma_relative: 0.0,
+            dropout_rate: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+impl Default for NoiseModel {
+    fn default() -> Self {
+        NoiseModel {
+            range_sigma: 5.0,
+            range_sigma_relative: 0.005,
+            dropout_rate: 0.02,
+            seed: 0x5EED,
+        }
+    }
+}
+
+/// Properties of the simulated sensor
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorConfig {
+    /// Position of the sensor in the world
+    pub position: Vector,
+    /// Rotation of the sensor in the world, in degrees
+    pub heading: f32,
+    /// Rotation speed in revolutions per second, below 0.05 the motor is stalled
+    pub rotation_speed: f32,
+    /// Amount of measurements in every distance packet
+    pub samples_per_sector: usize,
+    /// Measurements closer than this distance (in millimeters) have no return
+    pub min_range: f32,
+    /// Measurements further than this distance (in millimeters) have no return
+    pub max_range: f32,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        SensorConfig {
+            position: Vector::new(0.0, 0.0),
+            heading: 0.0,
+            rotation_speed: 6.5,
+            samples_per_sector: 47,
+            min_range: 150.0,
+            max_range: 8000.0,
+        }
+    }
+}
+
+/// A simulated Delta-2A lidar
+pub struct Simulator {
+    world: World,
+    sensor: SensorConfig,
+    noise: NoiseModel,
+    random: Random,
+    realtime: bool,
+    sector: usize,
+    elapsed: Duration,
+    started_at: Option<tokio::time::Instant>,
+}
+
+impl Simulator {
+    /// Create a simulator with a perfect sensor, packets are paced at the rotation speed of the sensor
+    pub fn new(world: World, sensor: SensorConfig) -> Self {
+        Simulator {
+            world,
+            sensor,
+            random: Random::new(0),
+            noise: NoiseModel::none(),
+            realtime: true,
+            sector: 0,
+            elapsed: Duration::ZERO,
+            started_at: None,
+        }
+    }
+
+    /// Set the noise model of the sensor
+    pub fn with_noise(mut self, noise: NoiseModel) -> Self {
+        self.random = Random::new(noise.seed);
+        self.noise = noise;
+        self
+    }
+
+    /// When `realtime` is true (the default) the packet stream waits until the sensor would have sent the packet,
+    /// otherwise packets are produced as fast as they are read
+    pub fn with_realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+
+    /// The world the sensor is placed in, it can be modified between packets
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.world
+    }
+
+    /// The simulated sensor, it can be moved between packets
+    pub fn sensor_mut(&mut self) -> &mut SensorConfig {
+        &mut self.sensor
+    }
+
+    /// Simulated time since the start of the simulation
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Time between two distance packets, or between two speed failure packets while the motor is stalled
+    pub fn sector_duration(&self) -> Duration {
+        if self.is_stalled() {
+            return STALLED_REPORT_INTERVAL;
+        }
+
+        Duration::from_secs_f64(1.0 / (self.sensor.rotation_speed as f64 * SECTORS_PER_REVOLUTION as f64))
+    }
+
+    fn is_stalled(&self) -> bool {
+        self.sensor.rotation_speed.is_nan() || self.sensor.rotation_speed < MIN_ROTATION_SPEED
+    }
+
+    /// Simulate the next sector, this ignores the real time pacing
+    ///
+    /// While the motor is stalled no sectors are measured, a speed failure packet is returned instead.
+    pub fn next_packet(&mut self) -> Packet {
+        if self.is_stalled() {
+            self.elapsed += STALLED_REPORT_INTERVAL;
+            return Packet::LidarSpeed(LidarSpeedPacket::new(0.0));
+        }
+
+        let start_angle = self.sector as f32 * SECTOR_ANGLE;
+        let samples = self.sensor.samples_per_sector.max(1);
+        let sector_start = self.elapsed();
+        let sample_duration = self.sector_duration() / samples as u32;
+
+        let (measurements, signal_strengths) = (0..samples)
+            .map(|index| {
+                let angle = start_angle + SECTOR_ANGLE * index as f32 / samples as f32;
+                let distance = self.measure(angle, sector_start + sample_duration * index as u32);
+                (distance, signal_strength(distance))
+            })
+            .unzip();
+
+        self.sector = (self.sector + 1) % SECTORS_PER_REVOLUTION;
+        self.elapsed += self.sector_duration();
+
+        let packet = DistancePacket::new(self.sensor.rotation_speed, start_angle, 0.0, measurements).with_signal_strengths(signal_strengths);
+        Packet::Distance(packet)
+    }
+
+    /// Simulate the next sector and encode it the way the sensor sends it over the serial port
+    pub fn next_frame(&mut self) -> Vec<u8> {
+        self.next_packet().to_bytes()
+    }
+
+    fn measure(&mut self, angle: f32, time: Duration) -> f32 {
+        if self.random.next_f32() < self.noise.dropout_rate {
+            return 0.0;
+        }
+
+        let world_angle = (self.sensor.heading + angle).to_radians();
+        let direction = Vector::new(world_angle.cos(), world_angle.sin());
+
+        let distance = match self.world.cast_ray(self.sensor.position, direction, time) {
+            Some(distance) => distance,
+            None => return 0.0,
+        };
+
+        let sigma = self.noise.range_sigma + self.noise.range_sigma_relative * distance;
+        let distance = distance + sigma * self.random.next_gaussian();
+
+        if distance < self.sensor.min_range || distance > self.sensor.max_range {
+            return 0.0;
+        }
+
+        // The sensor has a resolution of 0.25mm
+        (distance * 4.0).round() / 4.0
+    }
+}
+
+/// The stream never ends, in realtime mode every packet is delayed until the moment the sensor would have sent it
+#[async_trait]
+impl PacketStream for Simulator {
+    async fn next(&mut self) -> Option<Packet> {
+        if self.realtime {
+            let started_at = *self.started_at.get_or_insert_with(tokio::time::Instant::now);
+            let deadline = started_at + self.elapsed() + self.sector_duration();
+            tokio::time::sleep_until(deadline).await;
+        }
+
+        Some(self.next_packet())
+    }
+}
+
+/// Closer objects reflect more light
+fn signal_strength(distance: f32) -> u8 {
+    if distance <= 0.0 {
+        return 0;
+    }
+
+    (255.0 - distance / 40.0).clamp(16.0, 255.0) as u8
+}
+
+/// Small deterministic random generator (SplitMix64), good enough for sensor noise
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        Random(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Standard normal distributed value (Box-Muller)
+    fn next_gaussian(&mut self) -> f32 {
+        let u1 = 1.0 - self.next_f32();
+        let u2 = self.next_f32();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_decoder::PacketDecoder;
+
+    fn distances(packet: &Packet) -> Vec<f32> {
+        match packet {
+            Packet::Distance(distance_packet) => distance_packet.measurements().to_vec(),
+            Packet::LidarSpeed(_) => panic!("The motor of the simulated sensor turns"),
+        }
+    }
+
+    fn round_room() -> World {
+        World {
+            circles: vec![Circle {
+                center: Vector::new(0.0, 0.0),
+                radius: 2000.0,
+            }],
+            ..World::new()
+        }
+    }
+
+    #[test]
+    fn sees_round_room_from_the_center() {
+        let mut simulator = Simulator::new(round_room(), SensorConfig::default());
+
+        for _ in 0..SECTORS_PER_REVOLUTION {
+            assert!(distances(&simulator.next_packet()).iter().all(|distance| *distance == 2000.0));
+        }
+    }
+
+    #[test]
+    fn follows_the_sector_layout() {
+        let mut simulator = Simulator::new(round_room(), SensorConfig::default());
+
+        for sector in 0..2 * SECTORS_PER_REVOLUTION {
+            match simulator.next_packet() {
+                Packet::Distance(packet) => {
+                    assert_eq!((sector % SECTORS_PER_REVOLUTION) as f32 * 22.5, packet.start_angle());
+                    assert_eq!(47, packet.measurements().len());
+                    assert_eq!(6.5, packet.radar_speed());
+                }
+                Packet::LidarSpeed(_) => panic!("The motor of the simulated sensor turns"),
+            }
+        }
+
+        assert_eq!(simulator.sector_duration() * 2 * SECTORS_PER_REVOLUTION as u32, simulator.elapsed());
+    }
+
+    #[test]
+    fn stalled_motor_sends_speed_failures() {
+        let sensor = SensorConfig {
+            rotation_speed: 0.0,
+            ..SensorConfig::default()
+        };
+        let mut simulator = Simulator::new(round_room(), sensor);
+
+        for _ in 0..3 {
+            match simulator.next_packet() {
+                Packet::LidarSpeed(packet) => assert_eq!(0.0, packet.radar_speed()),
+                Packet::Distance(_) => panic!("A stalled motor doesn't measure"),
+            }
+        }
+        assert_eq!(STALLED_REPORT_INTERVAL * 3, simulator.elapsed());
+
+        for rotation_speed in [-1.0, f32::NAN, f32::MIN_POSITIVE, 0.01] {
+            simulator.sensor_mut().rotation_speed = rotation_speed;
+            assert_eq!(STALLED_REPORT_INTERVAL, simulator.sector_duration());
+            assert!(matches!(simulator.next_packet(), Packet::LidarSpeed(_)));
+        }
+
+        // The motor starts again at the sector it stalled in
+        simulator.sensor_mut().rotation_speed = 6.5;
+        match simulator.next_packet() {
+            Packet::Distance(packet) => assert_eq!(0.0, packet.start_angle()),
+            Packet::LidarSpeed(_) => panic!("The motor turns again"),
+        }
+    }
+
+    #[test]
+    fn sees_a_wall_in_front() {
+        let world = World {
+            segments: vec![Segment {
+                start: Vector::new(1000.0, -5000.0),
+                end: Vector::new(1000.0, 5000.0),
+            }],
+            ..World::new()
+        };
+        let sensor = SensorConfig {
+            samples_per_sector: 45,
+            ..SensorConfig::default()
+        };
+        let mut simulator = Simulator::new(world, sensor);
+
+        let first_sector = distances(&simulator.next_packet());
+        assert_eq!(1000.0, first_sector[0]);
+        // 11.5 degrees: 1000 / cos(11.5)
+        assert_eq!((1000.0 / 11.5f32.to_radians().cos() * 4.0).round() / 4.0, first_sector[23]);
+
+        // The wall is not visible behind the sensor
+        for _ in 1..8 {
+            simulator.next_packet();
+        }
+        assert_eq!(0.0, distances(&simulator.next_packet())[0]);
+    }
+
+    #[test]
+    fn applies_the_sensor_pose() {
+        let sensor = SensorConfig {
+            position: Vector::new(1000.0, 0.0),
+            heading: 180.0,
+            ..SensorConfig::default()
+        };
+        let mut simulator = Simulator::new(round_room(), sensor);
+
+        // Looking back through the center of the room
+        assert_eq!(3000.0, distances(&simulator.next_packet())[0]);
+    }
+
+    #[test]
+    fn moving_obstacle_moves_back_and_forth() {
+        let obstacle = MovingObstacle {
+            from: Vector::new(0.0, 0.0),
+            to: Vector::new(1000.0, 0.0),
+            radius: 100.0,
+            period: Duration::from_secs(2),
+        };
+
+        assert_eq!(Vector::new(0.0, 0.0), obstacle.at(Duration::from_secs(0)).center);
+        assert_eq!(Vector::new(500.0, 0.0), obstacle.at(Duration::from_millis(500)).center);
+        assert_eq!(Vector::new(1000.0, 0.0), obstacle.at(Duration::from_secs(1)).center);
+        assert_eq!(Vector::new(500.0, 0.0), obstacle.at(Duration::from_millis(1500)).center);
+    }
+
+    #[test]
+    fn dropout_removes_returns() {
+        let noise = NoiseModel {
+            dropout_rate: 1.0,
+            ..NoiseModel::none()
+        };
+        let mut simulator = Simulator::new(round_room(), SensorConfig::default()).with_noise(noise);
+
+        assert!(distances(&simulator.next_packet()).iter().all(|distance| *distance == 0.0));
+    }
+
+    #[test]
+    fn noise_is_deterministic() {
+        let mut first = Simulator::new(round_room(), SensorConfig::default()).with_noise(NoiseModel::default());
+        let mut second = Simulator::new(round_room(), SensorConfig::default()).with_noise(NoiseModel::default());
+
+        let first_distances = distances(&first.next_packet());
+        assert_eq!(first_distances, distances(&second.next_packet()));
+        assert!(first_distances.iter().any(|distance| *distance != 2000.0));
+    }
+
+    #[test]
+    fn frames_can_be_decoded() {
+        let room = World::rectangular_room(Vector::new(-2000.0, -1000.0), Vector::new(3000.0, 1500.0));
+        let expected = Simulator::new(room.clone(), SensorConfig::default()).next_packet();
+
+        let frame = Simulator::new(room, SensorConfig::default()).next_frame();
+        let results = PacketDecoder::new().decode(&frame);
+
+        assert_eq!(vec![Ok(expected)], results);
+    }
+
+    #[tokio::test]
+    async fn stream_produces_packets() {
+        let mut simulator = Simulator::new(round_room(), SensorConfig::default()).with_realtime(false);
+
+        assert!(simulator.next().await.is_some());
+        assert!(simulator.next().await.is_some());
+    }
+}
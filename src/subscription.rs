@@ -0,0 +1,116 @@
+//! Independent packet subscriptions, used to share a single lidar with multiple consumers
+use crate::packet::Packet;
+use crate::packet_stream::PacketStream;
+use async_trait::async_trait;
+use log::warn;
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// A subscription on the packets of a lidar, created using `Lidar::subscribe`
+///
+/// Every subscription receives all packets independently of the other subscriptions.
+/// A subscription which falls too far behind skips the oldest packets, this is reported by `recv`.
+pub struct PacketSubscription {
+    receiver: broadcast::Receiver<Packet>,
+    missed: u64,
+}
+
+impl PacketSubscription {
+    pub(crate) fn new(receiver: broadcast::Receiver<Packet>) -> Self {
+        PacketSubscription { receiver, missed: 0 }
+    }
+
+    /// Receive the next packet
+    ///
+    /// Returns `SubscriptionError::Lagged` when packets were skipped because this subscription fell behind,
+    /// the next call continues with the oldest packet that is still available.
+    pub async fn recv(&mut self) -> Result<Packet, SubscriptionError> {
+        match self.receiver.recv().await {
+            Ok(packet) => Ok(packet),
+            Err(RecvError::Lagged(skipped)) => {
+                self.missed += skipped;
+                Err(SubscriptionError::Lagged(skipped))
+            }
+            Err(RecvError::Closed) => Err(SubscriptionError::Closed),
+        }
+    }
+
+    /// Total amount of packets this subscription missed because it fell behind
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+/// The packet stream implementation skips over lag (it is logged), the stream ends when the lidar is closed
+#[async_trait]
+impl PacketStream for PacketSubscription {
+    async fn next(&mut self) -> Option<Packet> {
+        loop {
+            match self.recv().await {
+                Ok(packet) => return Some(packet),
+                Err(SubscriptionError::Lagged(skipped)) => warn!("Packet subscription lagged behind, skipped {} packets", skipped),
+                Err(SubscriptionError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum SubscriptionError {
+    #[error("The subscription lagged behind, skipped {0:} packets")]
+    Lagged(u64),
+    #[error("The lidar was closed")]
+    Closed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DistancePacket;
+
+    fn packet(start_angle: f32) -> Packet {
+        Packet::Distance(DistancePacket::new(6.5, start_angle, 0.0, vec![]))
+    }
+
+    #[tokio::test]
+    async fn subscriptions_are_independent() {
+        let (sender, _) = broadcast::channel(4);
+        let mut first = PacketSubscription::new(sender.subscribe());
+        let mut second = PacketSubscription::new(sender.subscribe());
+
+        sender.send(packet(0.0)).unwrap();
+
+        assert_eq!(Ok(packet(0.0)), first.recv().await);
+        assert_eq!(Ok(packet(0.0)), second.recv().await);
+    }
+
+    #[tokio::test]
+    async fn reports_lag() {
+        let (sender, _) = broadcast::channel(2);
+        let mut subscription = PacketSubscription::new(sender.subscribe());
+
+        for index in 0..5 {
+            sender.send(packet(index as f32)).unwrap();
+        }
+
+        assert_eq!(Err(SubscriptionError::Lagged(3)), subscription.recv().await);
+        assert_eq!(Ok(packet(3.0)), subscription.recv().await);
+        assert_eq!(3, subscription.missed());
+    }
+
+    #[tokio::test]
+    async fn stream_skips_lag_and_ends_when_closed() {
+        let (sender, _) = broadcast::channel(2);
+        let mut subscription = PacketSubscription::new(sender.subscribe());
+
+        for index in 0..3 {
+            sender.send(packet(index as f32)).unwrap();
+        }
+        drop(sender);
+
+        assert_eq!(Some(packet(1.0)), subscription.next().await);
+        assert_eq!(Some(packet(2.0)), subscription.next().await);
+        assert_eq!(None, subscription.next().await);
+    }
+}